@@ -21,6 +21,11 @@ int ckb_join(uint64_t id, int8_t *exit_code);
  * Get current VM instance ID.
  */
 uint64_t ckb_instance_id();
+/*
+ * Get the ID of the VM instance spawning current VM instance, root VM
+ * instance gets its own ID.
+ */
+uint64_t ckb_parent_id();
 
 /*
  * Create a pair of pipes owned by current VM instance. All pipe might
@@ -35,3 +40,35 @@ int ckb_pipe_read(uint8_t *buffer, size_t *length, uint64_t filde);
  * Blocking write to a pipe, might write less data from the buffer to the pipe
  */
 int ckb_pipe_write(const uint8_t *buffer, size_t *length, uint64_t filde);
+/*
+ * Close one end of a pipe owned by current VM instance, VM instances
+ * blocked on the other end of the pipe will be woken up.
+ */
+int ckb_close(uint64_t filde);
+/*
+ * Non-blocking variants of pipe read / write. When no VM instance is
+ * blocked on the other end of the pipe, a designated error code is
+ * returned immediately. A non-blocking write might only write part of
+ * the data, that can be accepted by the other end right away.
+ */
+int ckb_pipe_try_read(uint8_t *buffer, size_t *length, uint64_t filde);
+int ckb_pipe_try_write(const uint8_t *buffer, size_t *length, uint64_t filde);
+/*
+ * Block till any of the pipes is ready for reading or writing, the first
+ * ready pipe in the order of `fildes` is returned via `ready`.
+ */
+int ckb_select(const uint64_t *fildes, size_t count, uint64_t *ready);
+/*
+ * Block till any of the specified VM instances terminates, the ID and
+ * exit code of the terminated VM instance are returned. When `count` is 0,
+ * waits on all VM instances spawned by current VM instance that are not
+ * joined yet. When multiple VM instances have terminated, the one with
+ * the lowest ID is returned.
+ */
+int ckb_join_any(const uint64_t *ids, size_t count, uint64_t *id,
+                 int8_t *exit_code);
+/*
+ * Terminate a VM instance spawned by current VM instance, directly or
+ * indirectly. The terminated VM instance gets a designated exit code.
+ */
+int ckb_kill(uint64_t id);
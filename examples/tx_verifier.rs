@@ -1,9 +1,17 @@
 use ckb_mock_tx_types::{MockTransaction, ReprMockTransaction};
-use ckb_vm_deterministic_scheduler::dev_utils::verify_tx;
+use ckb_vm_deterministic_scheduler::{
+    dev_utils::{
+        chrome_trace::ChromeTraceObserver, print_vm_stats, verify_tx_with_options, VerifyOptions,
+    },
+    eviction::builtin_eviction_strategy,
+    scheduling_policy::builtin_scheduling_policy,
+    MIN_INSTANTIATED_VMS,
+};
 use clap::{command, Parser};
 use serde_json::from_str as from_json_str;
-use std::fs::read_to_string;
+use std::fs::{read_to_string, write};
 use std::io::{stdin, Read};
+use std::sync::{Arc, Mutex};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -19,12 +27,39 @@ struct Args {
 
     #[arg(short, long, default_value_t = 18446744073709551615)]
     max_cycles: u64,
+
+    /// 0: highest ID first, 1: round robin, 2: cycle fair
+    #[arg(long, default_value_t = 0)]
+    scheduling_policy: u8,
+
+    /// Max cycles a VM can run in a single turn, 0 disables time slicing
+    #[arg(long, default_value_t = 0)]
+    time_slice: u64,
+
+    /// Max number of VMs instantiated at the same time, 0 uses the default value
+    #[arg(long, default_value_t = 0)]
+    max_instantiated_vms: usize,
+
+    /// 0: lowest ID first, 1: least recently run, 2: smallest snapshot first
+    #[arg(long, default_value_t = 0)]
+    eviction_strategy: u8,
+
+    /// Write a Chrome trace event file, which can be loaded in chrome://tracing or Perfetto
+    #[arg(long)]
+    trace_out: Option<String>,
 }
 
 fn main() {
     env_logger::init();
 
     let args = Args::parse();
+    if args.max_instantiated_vms > 0 && args.max_instantiated_vms < MIN_INSTANTIATED_VMS {
+        println!(
+            "At least {} VMs must be instantiated at the same time",
+            MIN_INSTANTIATED_VMS
+        );
+        std::process::exit(1);
+    }
 
     let mock_tx: MockTransaction = {
         let data = if args.tx_file == "-" {
@@ -39,13 +74,47 @@ fn main() {
         repr_mock_tx.into()
     };
 
-    match verify_tx(
+    let chrome_trace = Arc::new(Mutex::new(ChromeTraceObserver::default()));
+    let options = VerifyOptions {
+        scheduling_policy: builtin_scheduling_policy(args.scheduling_policy)
+            .expect("unknown scheduling policy"),
+        time_slice: if args.time_slice > 0 {
+            Some(args.time_slice)
+        } else {
+            None
+        },
+        max_instantiated_vms: if args.max_instantiated_vms > 0 {
+            Some(args.max_instantiated_vms)
+        } else {
+            None
+        },
+        eviction_strategy: builtin_eviction_strategy(args.eviction_strategy)
+            .expect("unknown eviction strategy"),
+        observer: if args.trace_out.is_some() {
+            Some(chrome_trace.clone())
+        } else {
+            None
+        },
+        ..Default::default()
+    };
+
+    let result = verify_tx_with_options(
         &mock_tx,
         args.max_cycles,
         args.cycles_per_iterate,
         args.cycles_per_suspend,
-    ) {
-        Ok(cycles) => println!("Tx completes consuming {} cycles!", cycles),
+        options,
+    );
+    // The trace is written even when verification fails, so as to help
+    // debugging the failure.
+    if let Some(trace_out) = args.trace_out {
+        write(trace_out, chrome_trace.lock().expect("lock").to_json()).expect("write trace");
+    }
+    match result {
+        Ok(result) => {
+            print_vm_stats(&result);
+            println!("Tx completes consuming {} cycles!", result.consumed_cycles);
+        }
         Err(e) => {
             println!("Tx error occurs: {}", e);
             std::process::exit(1);
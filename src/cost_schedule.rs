@@ -0,0 +1,133 @@
+// Cycle charges for scheduler related syscalls & operations
+
+use crate::syscalls::transferred_byte_cycles;
+use ckb_types::core::Cycle;
+
+/// A CostSchedule decides how many cycles are charged for each new syscall
+/// introduced by the scheduler, as well as operations the scheduler performs
+/// on behalf of VMs, such as pipe transfers and VM swapping.
+///
+/// All functions here must be deterministic: given the same inputs, the same
+/// cycles must be returned, otherwise consensus might be broken.
+///
+/// Each function defaults to the charge used by DefaultCostSchedule, so a
+/// custom schedule only needs to implement the charges it changes.
+pub trait CostSchedule: Send + Sync {
+    /// Base cycles charged for each spawn syscall
+    fn spawn_base_cycles(&self) -> Cycle {
+        SPAWN_BASE_CYCLES
+    }
+
+    /// Cycles charged for argv passed to a spawn syscall, `bytes` contains
+    /// the total length of all argv values, including terminating zeros.
+    fn spawn_argv_cycles(&self, bytes: u64) -> Cycle {
+        transferred_byte_cycles(bytes)
+    }
+
+    /// Cycles charged for pipes passed from the spawner to the spawnee
+    fn spawn_pipe_cycles(&self, pipes: u64) -> Cycle {
+        // Each pipe is passed as a 64-bit value
+        transferred_byte_cycles(pipes.saturating_mul(8))
+    }
+
+    /// Base cycles charged for each join syscall
+    fn join_cycles(&self) -> Cycle {
+        YIELD_BASE_CYCLES
+    }
+
+    /// Base cycles charged for each instance_id syscall
+    fn instance_id_cycles(&self) -> Cycle {
+        // Like other syscalls that only read a register sized value,
+        // only the instruction cost is charged.
+        0
+    }
+
+    /// Base cycles charged for each parent_id syscall
+    fn parent_id_cycles(&self) -> Cycle {
+        // Same as instance_id syscall
+        0
+    }
+
+    /// Base cycles charged for each pipe syscall
+    fn pipe_cycles(&self) -> Cycle {
+        YIELD_BASE_CYCLES
+    }
+
+    /// Base cycles charged for each pipe_read syscall
+    fn pipe_read_cycles(&self) -> Cycle {
+        YIELD_BASE_CYCLES
+    }
+
+    /// Base cycles charged for each pipe_write syscall
+    fn pipe_write_cycles(&self) -> Cycle {
+        YIELD_BASE_CYCLES
+    }
+
+    /// Base cycles charged for each close syscall
+    fn close_cycles(&self) -> Cycle {
+        YIELD_BASE_CYCLES
+    }
+
+    /// Cycles charged for each select syscall waiting on `pipes` pipes
+    fn select_cycles(&self, pipes: u64) -> Cycle {
+        // Each pipe is read from memory as a 64-bit value
+        YIELD_BASE_CYCLES + transferred_byte_cycles(pipes.saturating_mul(8))
+    }
+
+    /// Cycles charged for each join_any syscall waiting on `vms` VMs, `vms`
+    /// is 0 when waiting on all VMs spawned by the caller.
+    fn join_any_cycles(&self, vms: u64) -> Cycle {
+        // Each VM ID is read from memory as a 64-bit value
+        YIELD_BASE_CYCLES + transferred_byte_cycles(vms.saturating_mul(8))
+    }
+
+    /// Base cycles charged for each kill syscall
+    fn kill_cycles(&self) -> Cycle {
+        YIELD_BASE_CYCLES
+    }
+
+    /// Cycles charged to the reading VM, each time the scheduler transfers
+    /// `bytes` of data from one end of a pipe to the other end.
+    fn pipe_transfer_read_cycles(&self, bytes: u64) -> Cycle {
+        // Copied bytes are charged to the reading VM, since they are written to
+        // its memory, similar to load_cell_data.
+        PIPE_TRANSFER_BASE_CYCLES + transferred_byte_cycles(bytes)
+    }
+
+    /// Cycles charged to the writing VM, each time the scheduler transfers
+    /// `bytes` of data from one end of a pipe to the other end.
+    fn pipe_transfer_write_cycles(&self, _bytes: u64) -> Cycle {
+        PIPE_TRANSFER_BASE_CYCLES
+    }
+
+    /// Cycles charged each time the scheduler suspends a VM to, or resumes
+    /// a VM from a snapshot. `bytes` contains the size of dirty pages and
+    /// registers in the snapshot, pages tracked from transaction data are
+    /// not included.
+    fn swap_cycles(&self, bytes: u64) -> Cycle {
+        SWAP_BASE_CYCLES + transferred_byte_cycles(bytes)
+    }
+}
+
+/// Base cycles for spawning a new VM
+pub const SPAWN_BASE_CYCLES: Cycle = 100_000;
+
+/// Base cycles for syscalls that yield control back to the scheduler
+pub const YIELD_BASE_CYCLES: Cycle = 800;
+
+/// Base cycles charged to both the reading VM and the writing VM, each time
+/// the scheduler transfers data from one end of a pipe to the other end.
+pub const PIPE_TRANSFER_BASE_CYCLES: Cycle = 800;
+
+/// Base cycles charged each time the scheduler suspends a VM to, or resumes
+/// a VM from a snapshot in order to make room for another VM.
+pub const SWAP_BASE_CYCLES: Cycle = 800;
+
+/// Default cost schedule, using the default charges of CostSchedule. Syscalls
+/// yielding control back to the scheduler are charged YIELD_BASE_CYCLES,
+/// data copied on behalf of VMs is charged at the same per byte rate as
+/// load_cell_data.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultCostSchedule;
+
+impl CostSchedule for DefaultCostSchedule {}
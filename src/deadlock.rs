@@ -0,0 +1,187 @@
+// Diagnostics for deadlocked scripts
+
+use crate::types::{PipeId, VmId, VmState};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+/// A single VM in a deadlock report
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockedVm {
+    pub vm_id: VmId,
+    pub state: VmState,
+    /// VMs this VM is waiting on: join targets for a VM in join state,
+    /// or owners of the other ends of the pipes for a VM in read / write /
+    /// select state. Empty when the VM is not waiting on any VM.
+    pub waiting_on: Vec<VmId>,
+}
+
+/// Deadlock report built from the wait-for graph of all VMs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadlockReport {
+    /// All VMs in the scheduler, sorted by VM ID
+    pub vms: Vec<BlockedVm>,
+    /// The first cycle found in the wait-for graph, starting from the VM
+    /// with the lowest ID in the cycle. Each VM waits on the next one, and
+    /// the last one waits on the first one.
+    pub cycle: Vec<VmId>,
+}
+
+impl DeadlockReport {
+    pub fn build(states: &BTreeMap<VmId, VmState>, pipes: &HashMap<PipeId, VmId>) -> Self {
+        let vms: Vec<BlockedVm> = states
+            .iter()
+            .map(|(vm_id, state)| {
+                let mut waiting_on: Vec<VmId> = match state {
+                    VmState::Join { target_vm_id, .. } => vec![*target_vm_id],
+                    VmState::JoinAny { target_vm_ids, .. } => target_vm_ids.clone(),
+                    VmState::WaitForRead { pipe, .. } | VmState::WaitForWrite { pipe, .. } => {
+                        pipes.get(&pipe.other_pipe()).copied().into_iter().collect()
+                    }
+                    VmState::WaitForAny { pipes: any, .. } => any
+                        .iter()
+                        .filter_map(|pipe| pipes.get(&pipe.other_pipe()).copied())
+                        .collect(),
+                    _ => vec![],
+                };
+                waiting_on.sort_unstable();
+                waiting_on.dedup();
+                BlockedVm {
+                    vm_id: *vm_id,
+                    state: state.clone(),
+                    waiting_on,
+                }
+            })
+            .collect();
+        let cycle = find_cycle(&vms);
+        Self { vms, cycle }
+    }
+
+    /// Render the wait-for graph in Graphviz DOT format, edges in the
+    /// cycle are highlighted.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph deadlock {\n");
+        for vm in &self.vms {
+            let color = if self.cycle.contains(&vm.vm_id) {
+                ", color=red"
+            } else {
+                ""
+            };
+            dot.push_str(&format!(
+                "  vm{} [label=\"VM {}\\n{}\"{}];\n",
+                vm.vm_id,
+                vm.vm_id,
+                state_label(&vm.state),
+                color
+            ));
+        }
+        for vm in &self.vms {
+            for target in &vm.waiting_on {
+                let color = if self.cycle_edge(vm.vm_id, *target) {
+                    " [color=red]"
+                } else {
+                    ""
+                };
+                dot.push_str(&format!("  vm{} -> vm{}{};\n", vm.vm_id, target, color));
+            }
+        }
+        dot.push('}');
+        dot
+    }
+
+    fn cycle_edge(&self, from: VmId, to: VmId) -> bool {
+        self.cycle
+            .iter()
+            .position(|id| *id == from)
+            .map(|i| self.cycle[(i + 1) % self.cycle.len()] == to)
+            .unwrap_or(false)
+    }
+}
+
+impl fmt::Display for DeadlockReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VMs:")?;
+        for vm in &self.vms {
+            write!(f, " [VM {}: {}", vm.vm_id, state_label(&vm.state))?;
+            if !vm.waiting_on.is_empty() {
+                write!(f, ", waiting on VMs {:?}", vm.waiting_on)?;
+            }
+            write!(f, "]")?;
+        }
+        if !self.cycle.is_empty() {
+            write!(f, ", cycle: {:?}", self.cycle)?;
+        }
+        Ok(())
+    }
+}
+
+fn state_label(state: &VmState) -> String {
+    match state {
+        VmState::Runnable => "Runnable".to_string(),
+        VmState::Terminated => "Terminated".to_string(),
+        VmState::Join { target_vm_id, .. } => format!("Join({})", target_vm_id),
+        VmState::WaitForRead { pipe, .. } => format!("WaitForRead({})", pipe.0),
+        VmState::WaitForWrite { pipe, .. } => format!("WaitForWrite({})", pipe.0),
+        VmState::WaitForAny { pipes, .. } => format!(
+            "WaitForAny({})",
+            pipes
+                .iter()
+                .map(|pipe| pipe.0.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        VmState::JoinAny { target_vm_ids, .. } => format!(
+            "JoinAny({})",
+            target_vm_ids
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+// Depth first search on the wait-for graph, starting from VMs in ID order,
+// and following edges in ID order. The first back edge found forms a cycle.
+fn find_cycle(vms: &[BlockedVm]) -> Vec<VmId> {
+    let edges: BTreeMap<VmId, &[VmId]> = vms
+        .iter()
+        .map(|vm| (vm.vm_id, &vm.waiting_on[..]))
+        .collect();
+    let mut visited: BTreeSet<VmId> = BTreeSet::new();
+    for vm in vms {
+        let mut path: Vec<VmId> = Vec::new();
+        if let Some(mut cycle) = visit(vm.vm_id, &edges, &mut visited, &mut path) {
+            let lowest = cycle
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, id)| **id)
+                .map(|(i, _)| i)
+                .unwrap();
+            cycle.rotate_left(lowest);
+            return cycle;
+        }
+    }
+    Vec::new()
+}
+
+fn visit(
+    id: VmId,
+    edges: &BTreeMap<VmId, &[VmId]>,
+    visited: &mut BTreeSet<VmId>,
+    path: &mut Vec<VmId>,
+) -> Option<Vec<VmId>> {
+    if let Some(i) = path.iter().position(|p| *p == id) {
+        return Some(path[i..].to_vec());
+    }
+    if !visited.insert(id) {
+        return None;
+    }
+    path.push(id);
+    for target in edges.get(&id).copied().unwrap_or(&[]) {
+        if let Some(cycle) = visit(*target, edges, visited, path) {
+            return Some(cycle);
+        }
+    }
+    path.pop();
+    None
+}
@@ -0,0 +1,159 @@
+//! Exports scheduler activities in Chrome trace event format, which can be
+//! loaded in chrome://tracing or https://ui.perfetto.dev.
+//!
+//! Each script group is a process, each VM is a thread(track) in it. Time
+//! is measured in cycles, 1 cycle is displayed as 1 microsecond.
+
+use crate::{
+    observer::SchedulerObserver,
+    types::{DataPieceId, PipeId, VmId, VmState},
+    ROOT_VM_ID,
+};
+use ckb_types::core::Cycle;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Default)]
+pub struct ChromeTraceObserver {
+    // Index of current script group
+    pid: u64,
+    events: Vec<Value>,
+    // VMs in blocked states, with the state label and the time the VM
+    // enters the state
+    blocked: BTreeMap<VmId, (&'static str, Cycle)>,
+    next_flow_id: u64,
+}
+
+impl ChromeTraceObserver {
+    /// Render all recorded events as a JSON document
+    pub fn to_json(&self) -> String {
+        json!({ "traceEvents": self.events }).to_string()
+    }
+
+    fn complete(&mut self, vm_id: VmId, name: &str, ts: Cycle, dur: Cycle) {
+        self.events.push(json!({
+            "name": name,
+            "ph": "X",
+            "pid": self.pid,
+            "tid": vm_id,
+            "ts": ts,
+            "dur": dur,
+        }));
+    }
+
+    fn instant(&mut self, vm_id: VmId, name: &str, ts: Cycle, args: Value) {
+        self.events.push(json!({
+            "name": name,
+            "ph": "i",
+            "s": "t",
+            "pid": self.pid,
+            "tid": vm_id,
+            "ts": ts,
+            "args": args,
+        }));
+    }
+
+    fn flow(&mut self, phase: &str, vm_id: VmId, id: u64, ts: Cycle) {
+        self.events.push(json!({
+            "name": "Transfer",
+            "cat": "pipe",
+            "ph": phase,
+            "bp": "e",
+            "id": id,
+            "pid": self.pid,
+            "tid": vm_id,
+            "ts": ts,
+        }));
+    }
+
+    fn unblock(&mut self, vm_id: VmId, cycles: Cycle) {
+        if let Some((label, start)) = self.blocked.remove(&vm_id) {
+            self.complete(vm_id, label, start, cycles.saturating_sub(start));
+        }
+    }
+}
+
+// Blocked states are labeled by VmState variant, None for other states
+fn blocked_label(state: &VmState) -> Option<&'static str> {
+    match state {
+        VmState::Runnable | VmState::Terminated => None,
+        VmState::Join { .. } => Some("Join"),
+        VmState::JoinAny { .. } => Some("JoinAny"),
+        VmState::WaitForRead { .. } => Some("WaitForRead"),
+        VmState::WaitForWrite { .. } => Some("WaitForWrite"),
+        VmState::WaitForAny { .. } => Some("WaitForAny"),
+    }
+}
+
+impl SchedulerObserver for ChromeTraceObserver {
+    fn on_vm_boot(&mut self, cycles: Cycle, vm_id: VmId, _data_piece_id: &DataPieceId) {
+        self.events.push(json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": self.pid,
+            "tid": vm_id,
+            "args": { "name": format!("VM {}", vm_id) },
+        }));
+        self.instant(vm_id, "Boot", cycles, json!({}));
+    }
+
+    fn on_vm_run(&mut self, cycles: Cycle, vm_id: VmId, consumed_cycles: Cycle) {
+        self.complete(vm_id, "Run", cycles, consumed_cycles);
+    }
+
+    fn on_vm_state_change(&mut self, cycles: Cycle, vm_id: VmId, state: &VmState) {
+        let label = blocked_label(state);
+        // Partial writes update the state without unblocking the VM
+        if self.blocked.get(&vm_id).map(|(l, _)| *l) == label {
+            return;
+        }
+        self.unblock(vm_id, cycles);
+        if let Some(label) = label {
+            self.blocked.insert(vm_id, (label, cycles));
+        }
+    }
+
+    fn on_pipe_transfer(
+        &mut self,
+        cycles: Cycle,
+        write_vm: VmId,
+        write_pipe: PipeId,
+        read_vm: VmId,
+        bytes: u64,
+    ) {
+        let id = self.next_flow_id;
+        self.next_flow_id += 1;
+        let args = json!({ "pipe": write_pipe.0, "bytes": bytes });
+        self.instant(write_vm, "Write", cycles, args.clone());
+        self.instant(read_vm, "Read", cycles, args);
+        self.flow("s", write_vm, id, cycles);
+        self.flow("f", read_vm, id, cycles);
+    }
+
+    fn on_vm_suspend(&mut self, cycles: Cycle, vm_id: VmId, bytes: u64) {
+        self.instant(vm_id, "Suspend", cycles, json!({ "bytes": bytes }));
+    }
+
+    fn on_vm_resume(&mut self, cycles: Cycle, vm_id: VmId, bytes: u64) {
+        self.instant(vm_id, "Resume", cycles, json!({ "bytes": bytes }));
+    }
+
+    fn on_vm_terminate(&mut self, cycles: Cycle, vm_id: VmId, exit_code: i8, vm_cycles: Cycle) {
+        self.unblock(vm_id, cycles);
+        self.instant(
+            vm_id,
+            "Terminate",
+            cycles,
+            json!({ "exit_code": exit_code, "vm_cycles": vm_cycles }),
+        );
+        // When root VM terminates, all other VMs are purged, and the next
+        // script group, if any, starts from 0 cycles.
+        if vm_id == ROOT_VM_ID {
+            let blocked: Vec<VmId> = self.blocked.keys().copied().collect();
+            for id in blocked {
+                self.unblock(id, cycles);
+            }
+            self.pid += 1;
+        }
+    }
+}
@@ -2,21 +2,28 @@
 //! The scheduler itself does not require code in here.
 
 use crate::{
-    types::{RunMode, TxData},
+    cost_schedule::{CostSchedule, DefaultCostSchedule},
+    eviction::{EvictionStrategy, LowestIdFirst},
+    observer::SchedulerObserver,
+    scheduling_policy::{HighestIdFirst, SchedulingPolicy},
+    types::{
+        DebugMessage, DebugSink, FullSuspendedState, Limits, RunMode, RunResult, TxData, VmStats,
+    },
     Scheduler,
 };
 use ckb_chain_spec::consensus::ConsensusBuilder;
 use ckb_mock_tx_types::{
     DummyResourceLoader, MockCellDep, MockInfo, MockInput, MockTransaction, Resource,
 };
-use ckb_script::{TransactionScriptsVerifier, TxVerifyEnv};
+use ckb_script::{ScriptGroupType, ScriptVersion, TransactionScriptsVerifier, TxVerifyEnv};
 use ckb_types::{
     bytes::Bytes,
     core::{
-        cell::resolve_transaction, hardfork::HardForks, Cycle, DepType, HeaderView, ScriptHashType,
-        TransactionBuilder,
+        cell::{resolve_transaction, ResolvedTransaction},
+        hardfork::HardForks,
+        Cycle, DepType, HeaderView, ScriptHashType, TransactionBuilder,
     },
-    packed::{CellDep, CellInput, CellOutput, OutPoint, Script},
+    packed::{Byte32, CellDep, CellInput, CellOutput, OutPoint, Script},
     prelude::*,
 };
 use ckb_vm::Error;
@@ -24,19 +31,124 @@ use daggy::{Dag, Walker};
 use molecule::prelude::*;
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+pub mod chrome_trace;
 pub mod dag;
 
 /// Given a full CKB transaction, this method runs all scripts in it
 /// and either return full consumed cycles, or an Error generated from
-/// one script. It serves as an example on how to use the scheduler.
+/// one script. Statistics of each VM are printed per script group. It
+/// serves as an example on how to use the scheduler.
 pub fn verify_tx(
     mock_tx: &MockTransaction,
     max_cycles: Cycle,
     cycles_per_iterate: Cycle,
     cycles_per_suspend: Cycle,
 ) -> Result<Cycle, Error> {
+    let result = verify_tx_with_options(
+        mock_tx,
+        max_cycles,
+        cycles_per_iterate,
+        cycles_per_suspend,
+        VerifyOptions::default(),
+    )?;
+    print_vm_stats(&result);
+    Ok(result.consumed_cycles)
+}
+
+/// Same as verify_tx, but allows using a different cost schedule, so
+/// different fee proposals can be tested against the same transaction.
+pub fn verify_tx_with_cost_schedule(
+    mock_tx: &MockTransaction,
+    max_cycles: Cycle,
+    cycles_per_iterate: Cycle,
+    cycles_per_suspend: Cycle,
+    cost_schedule: Arc<dyn CostSchedule>,
+) -> Result<Cycle, Error> {
+    verify_tx_with_options(
+        mock_tx,
+        max_cycles,
+        cycles_per_iterate,
+        cycles_per_suspend,
+        VerifyOptions {
+            cost_schedule,
+            ..Default::default()
+        },
+    )
+    .map(|result| result.consumed_cycles)
+}
+
+/// Scheduler configurations used by verify_tx_with_options
+#[derive(Clone)]
+pub struct VerifyOptions {
+    pub cost_schedule: Arc<dyn CostSchedule>,
+    pub limits: Limits,
+    pub scheduling_policy: Arc<dyn SchedulingPolicy>,
+    pub time_slice: Option<Cycle>,
+    pub max_instantiated_vms: Option<usize>,
+    pub eviction_strategy: Arc<dyn EvictionStrategy>,
+    // The same observer is set on all schedulers created for the
+    // transaction, including resumed ones.
+    pub observer: Option<Arc<Mutex<dyn SchedulerObserver>>>,
+    // When set, debug output of all script groups is captured and appended
+    // here, instead of being printed via log::debug!. Output printed before
+    // a script fails is kept as well.
+    pub debug_output: Option<Arc<Mutex<Vec<DebugMessage>>>>,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self {
+            cost_schedule: Arc::new(DefaultCostSchedule),
+            limits: Limits::default(),
+            scheduling_policy: Arc::new(HighestIdFirst),
+            time_slice: None,
+            max_instantiated_vms: None,
+            eviction_strategy: Arc::new(LowestIdFirst),
+            observer: None,
+            debug_output: None,
+        }
+    }
+}
+
+/// Outcome of a successful verify_tx_with_options run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyResult {
+    /// Cycles consumed by all script groups
+    pub consumed_cycles: Cycle,
+    /// Script groups in the order they are run
+    pub groups: Vec<GroupResult>,
+}
+
+/// Outcome of running a single script group
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GroupResult {
+    pub group_type: ScriptGroupType,
+    pub script_hash: Byte32,
+    pub total_cycles: Cycle,
+    pub swap_count: u64,
+    pub vm_stats: Vec<VmStats>,
+}
+
+/// A script group in a transaction, together with everything required to
+/// create a scheduler running it.
+#[derive(Clone)]
+pub struct ScriptGroupData {
+    pub group_type: ScriptGroupType,
+    pub script_hash: Byte32,
+    pub tx_data: TxData<Resource>,
+    pub version: ScriptVersion,
+}
+
+// Resolve the transaction, and build a ckb-script verifier for it
+fn build_verifier(
+    mock_tx: &MockTransaction,
+) -> (
+    Arc<ResolvedTransaction>,
+    Resource,
+    TransactionScriptsVerifier<Resource>,
+) {
     let resource = Resource::from_both(mock_tx, DummyResourceLoader {}).expect("create resource");
     let resolved_tx = Arc::new(
         resolve_transaction(
@@ -57,45 +169,103 @@ pub fn verify_tx(
         &HeaderView::new_advanced_builder().build(),
     ));
 
-    let groups: Vec<_> = {
-        let verifier = TransactionScriptsVerifier::new(
-            resolved_tx.clone(),
-            resource.clone(),
-            consensus.clone(),
-            tx_env.clone(),
-        );
-        verifier
-            .groups_with_type()
-            .map(|(a, b, c)| (a, b.clone(), c.clone()))
-            .collect()
-    };
+    let verifier =
+        TransactionScriptsVerifier::new(resolved_tx.clone(), resource.clone(), consensus, tx_env);
+    (resolved_tx, resource, verifier)
+}
 
-    let mut consumed_cycles = 0;
-    for (t, hash, group) in groups {
-        log::debug!("Running {} of hash {:#x}", t, hash);
+/// Resolve the transaction, and collect all script groups in it, in the
+/// order they are verified.
+pub fn script_groups(mock_tx: &MockTransaction) -> Vec<ScriptGroupData> {
+    // The verifier is only used to enumerate script groups, and to locate
+    // the program & script version of each group, scripts are executed by
+    // the scheduler alone.
+    let (resolved_tx, resource, verifier) = build_verifier(mock_tx);
+    verifier
+        .groups_with_type()
+        .map(|(group_type, script_hash, group)| {
+            let program = verifier
+                .extract_script(&group.script)
+                .expect("extracting program");
+            let version = verifier
+                .select_version(&group.script)
+                .expect("selecting version");
+            ScriptGroupData {
+                group_type,
+                script_hash: script_hash.clone(),
+                tx_data: TxData {
+                    rtx: resolved_tx.clone(),
+                    data_loader: resource.clone(),
+                    program,
+                    script_group: Arc::new(group.clone()),
+                },
+                version,
+            }
+        })
+        .collect()
+}
 
-        let verifier = TransactionScriptsVerifier::new(
-            resolved_tx.clone(),
-            resource.clone(),
-            consensus.clone(),
-            tx_env.clone(),
-        );
-        let program = verifier
-            .extract_script(&group.script)
-            .expect("extracting program");
-
-        let tx_data = TxData {
-            rtx: resolved_tx.clone(),
-            data_loader: resource.clone(),
-            program,
-            script_group: Arc::new(group),
-        };
+/// Verify the transaction with ckb-script alone, returning consumed cycles
+/// together with all debug output, so the scheduler can be checked against
+/// the reference implementation.
+pub fn verify_tx_with_ckb_script(
+    mock_tx: &MockTransaction,
+    max_cycles: Cycle,
+) -> (Result<Cycle, String>, Vec<String>) {
+    let (_, _, mut verifier) = build_verifier(mock_tx);
+    let debug_output = Arc::new(Mutex::new(Vec::new()));
+    let captured = debug_output.clone();
+    verifier.set_debug_printer(move |_hash: &Byte32, message: &str| {
+        captured.lock().expect("lock").push(message.to_string());
+    });
+    let result = verifier.verify(max_cycles).map_err(|e| format!("{:?}", e));
+    let output = debug_output.lock().expect("lock").clone();
+    (result, output)
+}
 
-        let mut scheduler = Scheduler::new(tx_data.clone(), verifier);
+/// Same as verify_tx, but allows tweaking scheduler configurations, so
+/// different configurations can be compared against the same transaction.
+/// Statistics of each script group are returned as well.
+pub fn verify_tx_with_options(
+    mock_tx: &MockTransaction,
+    max_cycles: Cycle,
+    cycles_per_iterate: Cycle,
+    cycles_per_suspend: Cycle,
+    options: VerifyOptions,
+) -> Result<VerifyResult, Error> {
+    let mut result = VerifyResult::default();
+    for ScriptGroupData {
+        group_type: t,
+        script_hash: hash,
+        tx_data,
+        version,
+    } in script_groups(mock_tx)
+    {
+        log::debug!("Running {} of hash {:#x}", t, hash);
+
+        let mut scheduler = Scheduler::new(tx_data.clone(), version, options.cost_schedule.clone())
+            .with_limits(options.limits.clone())
+            .with_scheduling_policy(options.scheduling_policy.clone())
+            .with_eviction_strategy(options.eviction_strategy.clone());
+        if let Some(max_instantiated_vms) = options.max_instantiated_vms {
+            scheduler = scheduler
+                .with_max_instantiated_vms(max_instantiated_vms)
+                .map_err(|e| Error::Unexpected(format!("Invalid options: {}", e)))?;
+        }
+        if let Some(time_slice) = options.time_slice {
+            scheduler = scheduler.with_time_slice(time_slice);
+        }
+        if let Some(observer) = &options.observer {
+            scheduler = scheduler.with_observer(Box::new(observer.clone()));
+        }
+        if options.debug_output.is_some() {
+            scheduler = scheduler.with_debug_sink(DebugSink::Capture);
+        }
         let mut last_suspended_cycles = 0;
 
         loop {
             if scheduler.consumed_cycles() > max_cycles {
+                collect_debug_output(&options, &scheduler);
                 return Err(Error::Unexpected(format!(
                     "{} of hash {:#x} runs out of max cycles! Consumed: {}, max: {}",
                     t,
@@ -108,21 +278,43 @@ pub fn verify_tx(
             if scheduler.consumed_cycles() - last_suspended_cycles >= cycles_per_suspend {
                 // Perform a full suspend here.
                 let state = scheduler.suspend().expect("suspend");
+                // Persisting the state in binary format, then loading it back,
+                // the same as what a node would do across restarts.
+                let encoded = state.encode();
                 log::debug!(
                     "{} of hash {:#x} suspended state size: {} bytes",
                     t,
                     hash,
-                    state.size()
+                    encoded.len()
                 );
-                scheduler = {
-                    let verifier = TransactionScriptsVerifier::new(
-                        resolved_tx.clone(),
-                        resource.clone(),
-                        consensus.clone(),
-                        tx_env.clone(),
+                for vm_size in state.vm_sizes() {
+                    log::debug!(
+                        "VM {} registers: {} bytes, dirty pages: {} bytes, source pages: {} bytes",
+                        vm_size.vm_id,
+                        vm_size.registers,
+                        vm_size.dirty_pages,
+                        vm_size.source_pages
                     );
-                    Scheduler::resume(tx_data.clone(), verifier, state)
-                };
+                }
+                let state = FullSuspendedState::decode(&encoded).expect("decode");
+                let captured_output = state.debug_output.clone();
+                scheduler = Scheduler::resume(
+                    tx_data.clone(),
+                    version,
+                    options.cost_schedule.clone(),
+                    state,
+                )
+                .map_err(|e| {
+                    if let Some(debug_output) = &options.debug_output {
+                        debug_output.lock().expect("lock").extend(captured_output);
+                    }
+                    Error::Unexpected(format!("{} of hash {:#x} resume error: {}", t, hash, e))
+                })?
+                .with_scheduling_policy(options.scheduling_policy.clone())
+                .with_eviction_strategy(options.eviction_strategy.clone());
+                if let Some(observer) = &options.observer {
+                    scheduler = scheduler.with_observer(Box::new(observer.clone()));
+                }
                 last_suspended_cycles = scheduler.consumed_cycles();
             }
 
@@ -133,31 +325,47 @@ pub fn verify_tx(
                 cycles_per_iterate
             );
             match scheduler.run(RunMode::LimitCycles(cycles_per_iterate)) {
-                Ok((exit_code, total_cycles)) => {
+                Ok(RunResult::Terminated {
+                    exit_code,
+                    total_cycles,
+                    vm_stats,
+                    ..
+                }) => {
+                    collect_debug_output(&options, &scheduler);
                     if exit_code != 0 {
                         return Err(Error::Unexpected(format!(
                             "Non-zero return code: {}",
                             exit_code
                         )));
                     }
-                    consumed_cycles += total_cycles;
-                    if total_cycles > max_cycles || consumed_cycles > max_cycles {
+                    result.consumed_cycles += total_cycles;
+                    if total_cycles > max_cycles || result.consumed_cycles > max_cycles {
                         return Err(Error::Unexpected(format!(
                             "{} of hash {:#x} runs out of max cycles! Consumed: {}, total: {} max: {}",
-                            t, hash, consumed_cycles, total_cycles, max_cycles,
+                            t, hash, result.consumed_cycles, total_cycles, max_cycles,
                         )));
                     }
                     log::info!(
-                        "{} of hash {:#x} terminates, exit code: {}, consumed cycles: {}",
+                        "{} of hash {:#x} terminates, exit code: {}, consumed cycles: {}, swap count: {}",
                         t,
                         hash,
                         exit_code,
-                        total_cycles
+                        total_cycles,
+                        scheduler.swap_count()
                     );
+                    result.groups.push(GroupResult {
+                        group_type: t,
+                        script_hash: hash.clone(),
+                        total_cycles,
+                        swap_count: scheduler.swap_count(),
+                        vm_stats,
+                    });
                     break;
                 }
-                Err(Error::CyclesExceeded) => (),
+                Ok(RunResult::Exhausted { .. }) => (),
                 Err(e) => {
+                    // Output printed before the error helps locating it
+                    collect_debug_output(&options, &scheduler);
                     return Err(Error::Unexpected(format!(
                         "{} of hash {:#x} encounters error: {:?}",
                         t, hash, e
@@ -167,7 +375,55 @@ pub fn verify_tx(
         }
     }
 
-    Ok(consumed_cycles)
+    Ok(result)
+}
+
+// Append output captured by the scheduler to the debug_output option
+fn collect_debug_output(options: &VerifyOptions, scheduler: &Scheduler<Resource>) {
+    if let Some(debug_output) = &options.debug_output {
+        debug_output
+            .lock()
+            .expect("lock")
+            .extend(scheduler.debug_output());
+    }
+}
+
+/// Print statistics of each script group, followed by a table of per VM
+/// statistics in the group.
+pub fn print_vm_stats(result: &VerifyResult) {
+    for group in &result.groups {
+        println!(
+            "{} of hash {:#x} consumes {} cycles, swap count: {}",
+            group.group_type, group.script_hash, group.total_cycles, group.swap_count
+        );
+        println!("{}", format_vm_stats(&group.vm_stats));
+    }
+}
+
+/// Render per VM statistics as a table, one VM per row
+pub fn format_vm_stats(vm_stats: &[VmStats]) -> String {
+    let mut table = format!(
+        "{:>6} {:<16} {:>14} {:>14} {:>14} {:>14} {:>12} {:>12} {:>5}",
+        "VM", "Program", "Execution", "Syscall", "Swap", "Unpaid", "Read", "Written", "Exit"
+    );
+    for stats in vm_stats {
+        table.push_str(&format!(
+            "\n{:>6} {:<16} {:>14} {:>14} {:>14} {:>14} {:>12} {:>12} {:>5}",
+            stats.vm_id,
+            format!("{:?}", stats.program),
+            stats.execution_cycles,
+            stats.syscall_cycles,
+            stats.swap_cycles,
+            stats.unpaid_cycles,
+            stats.bytes_read,
+            stats.bytes_written,
+            stats
+                .exit_code
+                .map(|code| code.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+        ));
+    }
+    table
 }
 
 pub fn build_mock_tx(seed: u64, program: Bytes, data: dag::Data) -> MockTransaction {
@@ -0,0 +1,121 @@
+// Error types used by the scheduler
+
+use crate::{
+    deadlock::DeadlockReport,
+    types::{PipeId, VmId},
+};
+use ckb_vm::Error;
+use std::fmt;
+
+/// Errors terminating the execution of a script. Different from
+/// ckb_vm::Error, each kind of failure gets its own variant here, so
+/// callers can classify failures without parsing strings.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SchedulerError {
+    /// The running VM reaches the cycle limit
+    CyclesExceeded,
+    /// Execution is interrupted by the pause signal
+    Pause,
+    /// No VM can make progress, while root VM has not terminated yet.
+    /// The report contains the wait-for graph of all VMs.
+    Deadlock(DeadlockReport),
+    /// A VM attempts to use a pipe it does not own
+    PipeOwnership { vm_id: VmId, pipe: PipeId },
+    /// More VMs are requested to be instantiated at the same time than allowed
+    VmLimitReached { limit: usize, requested: usize },
+    /// Creating a snapshot from a VM, or resuming a VM from a snapshot fails
+    Snapshot { vm_id: VmId, error: Error },
+    /// Errors generated by a particular VM
+    Vm { vm_id: VmId, error: Error },
+    /// The scheduling policy differs from the one recorded when booting
+    SchedulingPolicyMismatch { expected: u8, actual: u8 },
+    /// The eviction strategy differs from the one recorded when booting
+    EvictionStrategyMismatch { expected: u8, actual: u8 },
+    /// The script version differs from the one recorded when suspending
+    ScriptVersionMismatch { expected: u8, actual: u8 },
+    /// Decoding an encoded FullSuspendedState fails
+    Decode(String),
+    /// A decoded FullSuspendedState carries settings the scheduler cannot
+    /// resume with
+    InvalidState(String),
+    /// Broken internal invariants, this denotes a bug in the scheduler
+    Unexpected(String),
+}
+
+impl fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedulerError::CyclesExceeded => write!(f, "Cycles exceeded"),
+            SchedulerError::Pause => write!(f, "Execution paused"),
+            SchedulerError::Deadlock(report) => {
+                write!(f, "A deadlock situation has been reached! {}", report)
+            }
+            SchedulerError::PipeOwnership { vm_id, pipe } => {
+                write!(f, "VM {} does not own pipe {}", vm_id, pipe.0)
+            }
+            SchedulerError::VmLimitReached { limit, requested } => write!(
+                f,
+                "At most {} VMs can be instantiated but {} are requested",
+                limit, requested
+            ),
+            SchedulerError::Snapshot { vm_id, error } => {
+                write!(f, "Snapshot error from VM {}: {}", vm_id, error)
+            }
+            SchedulerError::Vm { vm_id, error } => write!(f, "Error from VM {}: {}", vm_id, error),
+            SchedulerError::SchedulingPolicyMismatch { expected, actual } => write!(
+                f,
+                "Scheduling policy {} is used but {} is expected",
+                actual, expected
+            ),
+            SchedulerError::EvictionStrategyMismatch { expected, actual } => write!(
+                f,
+                "Eviction strategy {} is used but {} is expected",
+                actual, expected
+            ),
+            SchedulerError::ScriptVersionMismatch { expected, actual } => write!(
+                f,
+                "Script version {} is used but {} is expected",
+                actual, expected
+            ),
+            SchedulerError::Decode(message) => write!(f, "Decode error: {}", message),
+            SchedulerError::InvalidState(message) => write!(f, "Invalid state: {}", message),
+            SchedulerError::Unexpected(message) => write!(f, "Unexpected error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SchedulerError {}
+
+/// Builds a closure tagging ckb_vm::Error with the VM generating it
+pub(crate) fn vm_error(vm_id: VmId) -> impl FnOnce(Error) -> SchedulerError {
+    move |error| SchedulerError::Vm { vm_id, error }
+}
+
+/// Builds a closure tagging snapshot related ckb_vm::Error with the VM
+pub(crate) fn snapshot_error(vm_id: VmId) -> impl FnOnce(Error) -> SchedulerError {
+    move |error| SchedulerError::Snapshot { vm_id, error }
+}
+
+// Syscalls and data sources can only return ckb_vm::Error, the special
+// errors below are used for flow control inside the scheduler, they are
+// never returned to the caller of the scheduler.
+const YIELD: &str = "YIELD";
+const INDEX_OUT_OF_BOUND: &str = "INDEX_OUT_OF_BOUND";
+
+/// Error returned by syscalls that yield control back to the scheduler
+pub(crate) fn yield_error() -> Error {
+    Error::External(YIELD.to_string())
+}
+
+pub(crate) fn is_yield(error: &Error) -> bool {
+    matches!(error, Error::External(m) if m == YIELD)
+}
+
+/// Error returned by data sources when the requested data piece does not exist
+pub(crate) fn index_out_of_bound_error() -> Error {
+    Error::External(INDEX_OUT_OF_BOUND.to_string())
+}
+
+pub(crate) fn is_index_out_of_bound(error: &Error) -> bool {
+    matches!(error, Error::External(m) if m == INDEX_OUT_OF_BOUND)
+}
@@ -0,0 +1,105 @@
+// Strategies deciding which instantiated VMs to suspend
+
+use crate::types::VmId;
+use std::sync::Arc;
+
+/// An instantiated VM that can be suspended to make room for other VMs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EvictionCandidate {
+    pub vm_id: VmId,
+    /// A logical timestamp of the last time the VM runs, larger value means
+    /// more recent. A newly booted VM uses the time it is booted.
+    pub last_run: u64,
+    /// Bytes of dirty pages in the VM's memory, which would be copied into
+    /// the snapshot when the VM is suspended. Always 0 when the strategy
+    /// does not need it, see `EvictionStrategy::needs_dirty_bytes`.
+    pub dirty_bytes: u64,
+}
+
+/// An EvictionStrategy picks the VMs to suspend when more VMs need to be
+/// instantiated than allowed.
+///
+/// Strategies must be deterministic: given the same candidates, the same
+/// VMs must be picked, otherwise consensus might be broken.
+pub trait EvictionStrategy: Send + Sync {
+    /// Unique ID of the strategy, it is recorded in FullSuspendedState so
+    /// the same strategy can be used when resuming.
+    fn id(&self) -> u8;
+
+    /// Whether the strategy uses `dirty_bytes` of candidates. Counting dirty
+    /// bytes requires scanning the memory pages of all candidates, it is
+    /// skipped for strategies that do not need it.
+    fn needs_dirty_bytes(&self) -> bool {
+        false
+    }
+
+    /// Pick `count` distinct VMs to suspend. `candidates` is sorted by VM ID
+    /// in ascending order, and contains at least `count` VMs.
+    fn select(&self, candidates: &[EvictionCandidate], count: usize) -> Vec<VmId>;
+}
+
+pub const LOWEST_ID_FIRST_STRATEGY_ID: u8 = 0;
+pub const LEAST_RECENTLY_RUN_STRATEGY_ID: u8 = 1;
+pub const SMALLEST_SNAPSHOT_FIRST_STRATEGY_ID: u8 = 2;
+
+/// Suspends VMs with the smallest IDs, this is the default strategy
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LowestIdFirst;
+
+impl EvictionStrategy for LowestIdFirst {
+    fn id(&self) -> u8 {
+        LOWEST_ID_FIRST_STRATEGY_ID
+    }
+
+    fn select(&self, candidates: &[EvictionCandidate], count: usize) -> Vec<VmId> {
+        candidates.iter().take(count).map(|c| c.vm_id).collect()
+    }
+}
+
+/// Suspends VMs that have not run for the longest time, ties are broken
+/// by picking the smallest ID.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LeastRecentlyRun;
+
+impl EvictionStrategy for LeastRecentlyRun {
+    fn id(&self) -> u8 {
+        LEAST_RECENTLY_RUN_STRATEGY_ID
+    }
+
+    fn select(&self, candidates: &[EvictionCandidate], count: usize) -> Vec<VmId> {
+        let mut candidates = candidates.to_vec();
+        candidates.sort_by_key(|c| (c.last_run, c.vm_id));
+        candidates.iter().take(count).map(|c| c.vm_id).collect()
+    }
+}
+
+/// Suspends VMs that are cheapest to swap, i.e., having the least dirty
+/// pages, ties are broken by picking the smallest ID.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SmallestSnapshotFirst;
+
+impl EvictionStrategy for SmallestSnapshotFirst {
+    fn id(&self) -> u8 {
+        SMALLEST_SNAPSHOT_FIRST_STRATEGY_ID
+    }
+
+    fn needs_dirty_bytes(&self) -> bool {
+        true
+    }
+
+    fn select(&self, candidates: &[EvictionCandidate], count: usize) -> Vec<VmId> {
+        let mut candidates = candidates.to_vec();
+        candidates.sort_by_key(|c| (c.dirty_bytes, c.vm_id));
+        candidates.iter().take(count).map(|c| c.vm_id).collect()
+    }
+}
+
+/// Looks up a builtin strategy by its ID
+pub fn builtin_eviction_strategy(id: u8) -> Option<Arc<dyn EvictionStrategy>> {
+    match id {
+        LOWEST_ID_FIRST_STRATEGY_ID => Some(Arc::new(LowestIdFirst)),
+        LEAST_RECENTLY_RUN_STRATEGY_ID => Some(Arc::new(LeastRecentlyRun)),
+        SMALLEST_SNAPSHOT_FIRST_STRATEGY_ID => Some(Arc::new(SmallestSnapshotFirst)),
+        _ => None,
+    }
+}
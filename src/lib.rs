@@ -1,14 +1,23 @@
 use crate::{
+    cost_schedule::CostSchedule,
+    deadlock::DeadlockReport,
+    error::{is_yield, snapshot_error, vm_error, SchedulerError},
+    eviction::{builtin_eviction_strategy, EvictionCandidate, EvictionStrategy, LowestIdFirst},
+    observer::SchedulerObserver,
+    scheduling_policy::{
+        builtin_scheduling_policy, HighestIdFirst, SchedulingContext, SchedulingPolicy,
+    },
     syscalls::{
-        transferred_byte_cycles, MachineContext, INVALID_PIPE, JOIN_FAILURE, OTHER_END_CLOSED,
-        SUCCESS,
+        swap_bytes, transferred_byte_cycles, MachineContext, INVALID_PIPE, JOIN_FAILURE,
+        KILLED_EXIT_CODE, KILL_FAILURE, MAX_PIPES_CREATED, MAX_SPAWN_DEPTH, MAX_VMS_SPAWNED,
+        NOT_PERMITTED, OTHER_END_CLOSED, SUCCESS, WOULD_BLOCK,
     },
     types::{
-        DataPieceId, FullSuspendedState, Message, PipeId, RunMode, TxData, VmId, VmState,
-        FIRST_PIPE_SLOT, FIRST_VM_ID,
+        DataPieceId, DebugMessage, DebugSink, FullSuspendedState, Limits, Message, PipeId, RunMode,
+        RunResult, SpawnTree, TxData, VmId, VmState, VmStats, FIRST_PIPE_SLOT, FIRST_VM_ID,
     },
 };
-use ckb_script::{ScriptVersion, TransactionScriptsVerifier};
+use ckb_script::ScriptVersion;
 use ckb_traits::{CellDataProvider, ExtensionProvider, HeaderProvider};
 use ckb_types::core::Cycle;
 use ckb_vm::{
@@ -19,23 +28,35 @@ use ckb_vm::{
         asm::{AsmCoreMachine, AsmMachine},
         CoreMachine, DefaultMachineBuilder, Pause, SupportMachine,
     },
-    memory::Memory,
+    memory::{Memory, FLAG_DIRTY},
     registers::A0,
     snapshot2::{DataSource, Snapshot2},
-    Error, Register,
+    Error, Register, RISCV_PAGESIZE,
 };
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::{Arc, Mutex};
 
+pub mod cost_schedule;
+pub mod deadlock;
 pub mod dev_utils;
+pub mod error;
+pub mod eviction;
+pub mod observer;
+pub mod scheduling_policy;
+pub mod serialization;
 pub mod syscalls;
+pub mod trace;
 pub mod types;
 
 #[cfg(test)]
 mod tests;
 
-const ROOT_VM_ID: VmId = FIRST_VM_ID;
-const MAX_INSTANTIATED_VMS: usize = 4;
+pub(crate) const ROOT_VM_ID: VmId = FIRST_VM_ID;
+const DEFAULT_MAX_INSTANTIATED_VMS: usize = 4;
+/// Min number of VMs that can be instantiated at the same time. A pipe
+/// transfer requires both the reading VM and the writing VM to be
+/// instantiated at the same time.
+pub const MIN_INSTANTIATED_VMS: usize = 2;
 
 /// A single Scheduler instance is used to verify a single script
 /// within a CKB transaction.
@@ -47,11 +68,13 @@ pub struct Scheduler<
     DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + Clone + 'static,
 > {
     tx_data: TxData<DL>,
-    // In fact, Scheduler here has the potential to totally replace
-    // TransactionScriptsVerifier, nonetheless much of current syscall
-    // implementation is strictly tied to TransactionScriptsVerifier, we
-    // are using it here to save some extra code.
-    verifier: TransactionScriptsVerifier<DL>,
+    // All syscalls are implemented in MachineContext, the script version
+    // decides the VM version, as well as available syscalls.
+    version: ScriptVersion,
+    cost_schedule: Arc<dyn CostSchedule>,
+    limits: Limits,
+    scheduling_policy: Arc<dyn SchedulingPolicy>,
+    eviction_strategy: Arc<dyn EvictionStrategy>,
 
     total_cycles: Cycle,
     next_vm_id: VmId,
@@ -61,6 +84,39 @@ pub struct Scheduler<
     instantiated: BTreeMap<VmId, (MachineContext<DL>, AsmMachine)>,
     suspended: HashMap<VmId, Snapshot2<DataPieceId>>,
     terminated_vms: HashMap<VmId, i8>,
+    spawn_depths: BTreeMap<VmId, u64>,
+    // ID of the scheduling policy used when booting the root VM
+    scheduling_policy_id: u8,
+    last_run_vm: Option<VmId>,
+    // A VM interrupted by the cycle budget or the pause signal is always
+    // picked first in the next iteration, so the way the budget is split
+    // does not affect scheduling decisions.
+    interrupted_vm: Option<VmId>,
+    vm_cycles: BTreeMap<VmId, Cycle>,
+    swap_count: u64,
+    // Max cycles a VM can run in a single turn before it gets preempted
+    time_slice: Option<Cycle>,
+    // Cycles consumed by the interrupted VM in its current turn, cycles
+    // charged before the turn are not included.
+    slice_consumed_cycles: Cycle,
+    max_instantiated_vms: usize,
+    // ID of the eviction strategy used when booting the root VM
+    eviction_strategy_id: u8,
+    // Logical timestamps of the last time each VM runs, run_count is
+    // increased each time a VM runs.
+    run_count: u64,
+    last_run_times: BTreeMap<VmId, u64>,
+    spawn_tree: SpawnTree,
+    // VMs whose exit codes have been returned to their parents via join /
+    // join_any syscalls.
+    reaped_vms: BTreeSet<VmId>,
+    // Statistics of all booted VMs, including terminated ones
+    vm_stats: BTreeMap<VmId, VmStats>,
+    debug_sink: DebugSink,
+    // Output of debug syscalls, shared with all VMs. With DebugSink::Log,
+    // the output is drained and printed after each VM run.
+    debug_output: Arc<Mutex<Vec<DebugMessage>>>,
+    observer: Option<Box<dyn SchedulerObserver>>,
 
     // message_box is expected to be empty before returning from `run`
     // function, there is no need to persist messages.
@@ -71,10 +127,18 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
     Scheduler<DL>
 {
     /// Create a new scheduler from empty state
-    pub fn new(tx_data: TxData<DL>, verifier: TransactionScriptsVerifier<DL>) -> Self {
+    pub fn new(
+        tx_data: TxData<DL>,
+        version: ScriptVersion,
+        cost_schedule: Arc<dyn CostSchedule>,
+    ) -> Self {
         Self {
             tx_data,
-            verifier,
+            version,
+            cost_schedule,
+            limits: Limits::default(),
+            scheduling_policy: Arc::new(HighestIdFirst),
+            eviction_strategy: Arc::new(LowestIdFirst),
             total_cycles: 0,
             next_vm_id: FIRST_VM_ID,
             next_pipe_slot: FIRST_PIPE_SLOT,
@@ -84,22 +148,158 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
             suspended: HashMap::default(),
             message_box: Arc::new(Mutex::new(Vec::new())),
             terminated_vms: HashMap::default(),
+            spawn_depths: BTreeMap::default(),
+            scheduling_policy_id: HighestIdFirst.id(),
+            last_run_vm: None,
+            interrupted_vm: None,
+            vm_cycles: BTreeMap::default(),
+            swap_count: 0,
+            time_slice: None,
+            slice_consumed_cycles: 0,
+            max_instantiated_vms: DEFAULT_MAX_INSTANTIATED_VMS,
+            eviction_strategy_id: LowestIdFirst.id(),
+            run_count: 0,
+            last_run_times: BTreeMap::default(),
+            spawn_tree: SpawnTree::default(),
+            reaped_vms: BTreeSet::default(),
+            vm_stats: BTreeMap::default(),
+            debug_sink: DebugSink::default(),
+            debug_output: Arc::new(Mutex::new(Vec::new())),
+            observer: None,
         }
     }
 
+    /// Set resource limits enforced on the script. Limits are recorded in the
+    /// suspended state, and restored automatically when resuming.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Set the policy picking the next VM to run. The policy's ID is recorded
+    /// in the suspended state, builtin policies are restored automatically
+    /// when resuming, while a custom policy must be set again after resuming.
+    pub fn with_scheduling_policy(mut self, scheduling_policy: Arc<dyn SchedulingPolicy>) -> Self {
+        self.scheduling_policy = scheduling_policy;
+        self
+    }
+
+    /// Enable time slicing: a VM running for `time_slice` cycles in a single
+    /// turn is preempted, and the scheduling policy picks the next VM to run.
+    /// Cycles charged to a VM before its turn, such as swapping and pipe
+    /// transfer cycles, do not count against its time slice.
+    /// The time slice is recorded in the suspended state, and restored
+    /// automatically when resuming.
+    ///
+    /// Note with HighestIdFirst policy, a preempted VM would be picked again
+    /// if it is still the runnable VM with the largest ID.
+    pub fn with_time_slice(mut self, time_slice: Cycle) -> Self {
+        self.time_slice = Some(time_slice);
+        self
+    }
+
+    /// Set the max number of VMs that can be instantiated at the same time,
+    /// the value is recorded in the suspended state, and restored
+    /// automatically when resuming. Values less than MIN_INSTANTIATED_VMS
+    /// are rejected.
+    pub fn with_max_instantiated_vms(
+        mut self,
+        max_instantiated_vms: usize,
+    ) -> Result<Self, SchedulerError> {
+        if max_instantiated_vms < MIN_INSTANTIATED_VMS {
+            return Err(SchedulerError::InvalidState(format!(
+                "At least {} VMs must be instantiated at the same time, got {}",
+                MIN_INSTANTIATED_VMS, max_instantiated_vms
+            )));
+        }
+        self.max_instantiated_vms = max_instantiated_vms;
+        Ok(self)
+    }
+
+    /// Set the strategy picking VMs to suspend when more VMs need to be
+    /// instantiated than allowed. Similar to scheduling policy, builtin
+    /// strategies are restored automatically when resuming, while a custom
+    /// strategy must be set again after resuming.
+    pub fn with_eviction_strategy(mut self, eviction_strategy: Arc<dyn EvictionStrategy>) -> Self {
+        self.eviction_strategy = eviction_strategy;
+        self
+    }
+
+    /// Set an observer getting notified of scheduler activities. Observers
+    /// are not part of the suspended state, the observer must be set again
+    /// after resuming.
+    pub fn with_observer(mut self, observer: Box<dyn SchedulerObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Set where output of the debug syscall goes, by default the output is
+    /// printed via log::debug!. The sink is recorded in the suspended state,
+    /// and restored automatically when resuming, so is the captured output.
+    pub fn with_debug_sink(mut self, debug_sink: DebugSink) -> Self {
+        self.debug_sink = debug_sink;
+        self
+    }
+
     pub fn consumed_cycles(&self) -> Cycle {
         self.total_cycles
     }
 
-    /// Resume a previously suspended scheduler state
+    /// Spawn lineage of all VMs in the script, including terminated ones
+    pub fn spawn_tree(&self) -> &SpawnTree {
+        &self.spawn_tree
+    }
+
+    /// Number of times VMs are suspended or resumed to make room for other VMs
+    pub fn swap_count(&self) -> u64 {
+        self.swap_count
+    }
+
+    /// Output of debug syscalls captured with DebugSink::Capture, in the
+    /// order it is printed.
+    pub fn debug_output(&self) -> Vec<DebugMessage> {
+        self.debug_output.lock().expect("lock").clone()
+    }
+
+    /// Statistics of all VMs booted so far, ordered by VM ID
+    pub fn vm_stats(&self) -> impl Iterator<Item = &VmStats> {
+        self.vm_stats.values()
+    }
+
+    /// Resume a previously suspended scheduler state, the script version
+    /// must be the same as the one used to create the scheduler.
     pub fn resume(
         tx_data: TxData<DL>,
-        verifier: TransactionScriptsVerifier<DL>,
+        version: ScriptVersion,
+        cost_schedule: Arc<dyn CostSchedule>,
         full: FullSuspendedState,
-    ) -> Self {
-        Self {
+    ) -> Result<Self, SchedulerError> {
+        if full.script_version != version as u8 {
+            return Err(SchedulerError::ScriptVersionMismatch {
+                expected: full.script_version,
+                actual: version as u8,
+            });
+        }
+        if full.max_instantiated_vms < MIN_INSTANTIATED_VMS as u64
+            || full.instantiated_vms.len() as u64 > full.max_instantiated_vms
+        {
+            return Err(SchedulerError::InvalidState(format!(
+                "{} VMs are instantiated with a limit of {}, while the limit must be at least {}",
+                full.instantiated_vms.len(),
+                full.max_instantiated_vms,
+                MIN_INSTANTIATED_VMS
+            )));
+        }
+        let instantiated_ids = full.instantiated_vms.clone();
+        let mut scheduler = Self {
             tx_data,
-            verifier,
+            version,
+            cost_schedule,
+            limits: full.limits,
+            scheduling_policy: builtin_scheduling_policy(full.scheduling_policy)
+                .unwrap_or_else(|| Arc::new(HighestIdFirst)),
+            eviction_strategy: builtin_eviction_strategy(full.eviction_strategy)
+                .unwrap_or_else(|| Arc::new(LowestIdFirst)),
             total_cycles: full.total_cycles,
             next_vm_id: full.next_vm_id,
             next_pipe_slot: full.next_pipe_slot,
@@ -117,15 +317,45 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                 .collect(),
             message_box: Arc::new(Mutex::new(Vec::new())),
             terminated_vms: full.terminated_vms.into_iter().collect(),
+            spawn_depths: full.spawn_depths.into_iter().collect(),
+            scheduling_policy_id: full.scheduling_policy,
+            last_run_vm: full.last_run_vm,
+            interrupted_vm: full.interrupted_vm,
+            vm_cycles: full.vm_cycles.into_iter().collect(),
+            swap_count: full.swap_count,
+            time_slice: full.time_slice,
+            slice_consumed_cycles: full.slice_consumed_cycles,
+            max_instantiated_vms: full.max_instantiated_vms as usize,
+            eviction_strategy_id: full.eviction_strategy,
+            run_count: full.run_count,
+            last_run_times: full.last_run_times.into_iter().collect(),
+            spawn_tree: full.parents.into_iter().collect(),
+            reaped_vms: full.reaped_vms.into_iter().collect(),
+            vm_stats: full
+                .vm_stats
+                .into_iter()
+                .map(|stats| (stats.vm_id, stats))
+                .collect(),
+            debug_sink: full.debug_sink,
+            debug_output: Arc::new(Mutex::new(full.debug_output)),
+            observer: None,
+        };
+        // VMs that were instantiated when suspending are instantiated again
+        // without charging any cycles, so the swapping behavior(hence consumed
+        // cycles) stays the same no matter how many times a scheduler is
+        // suspended and resumed.
+        for id in instantiated_ids {
+            scheduler.resume_vm(&id)?;
         }
+        Ok(scheduler)
     }
 
     /// Suspend current scheduler into a serializable full state
-    pub fn suspend(mut self) -> Result<FullSuspendedState, Error> {
+    pub fn suspend(mut self) -> Result<FullSuspendedState, SchedulerError> {
         let mut vms = Vec::with_capacity(self.states.len());
         let instantiated_ids: Vec<_> = self.instantiated.keys().cloned().collect();
-        for id in instantiated_ids {
-            self.suspend_vm(&id)?;
+        for id in &instantiated_ids {
+            self.suspend_vm(id)?;
         }
         for (id, state) in self.states {
             let snapshot = self.suspended.remove(&id).unwrap();
@@ -138,6 +368,26 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
             vms,
             pipes: self.pipes.into_iter().collect(),
             terminated_vms: self.terminated_vms.into_iter().collect(),
+            instantiated_vms: instantiated_ids,
+            spawn_depths: self.spawn_depths.into_iter().collect(),
+            limits: self.limits,
+            script_version: self.version as u8,
+            scheduling_policy: self.scheduling_policy_id,
+            last_run_vm: self.last_run_vm,
+            interrupted_vm: self.interrupted_vm,
+            vm_cycles: self.vm_cycles.into_iter().collect(),
+            swap_count: self.swap_count,
+            time_slice: self.time_slice,
+            slice_consumed_cycles: self.slice_consumed_cycles,
+            max_instantiated_vms: self.max_instantiated_vms as u64,
+            eviction_strategy: self.eviction_strategy_id,
+            run_count: self.run_count,
+            last_run_times: self.last_run_times.into_iter().collect(),
+            parents: self.spawn_tree.iter().collect(),
+            reaped_vms: self.reaped_vms.into_iter().collect(),
+            vm_stats: self.vm_stats.into_values().collect(),
+            debug_sink: self.debug_sink,
+            debug_output: std::mem::take(&mut *self.debug_output.lock().expect("lock")),
         })
     }
 
@@ -146,68 +396,144 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
     /// It accepts 2 run mode, one can either limit the cycles to execute,
     /// or use a pause signal to trigger termination.
     ///
-    /// Only when the execution terminates without VM errors, will this
-    /// function return an exit code(could still be non-zero) and total
-    /// consumed cycles.
+    /// When limiting cycles, the limit is a budget shared by all VMs that
+    /// get to run in this invocation. Cycles consumed by each VM are deducted
+    /// from the budget, when the budget is used up before the root VM
+    /// terminates, RunResult::Exhausted will be returned, and one can invoke
+    /// this function again to continue execution. Note that since a VM can
+    /// only be interrupted at certain points, consumed cycles might slightly
+    /// exceed the budget.
+    ///
+    /// When the root VM terminates, RunResult::Terminated will be returned,
+    /// containing the exit code(could still be non-zero), consumed cycles,
+    /// and statistics of each VM.
     ///
     /// Err would be returned in the following cases:
-    /// * Cycle limit reached, the returned error would be ckb_vm::Error::CyclesExceeded,
-    /// * Pause trigger, the returned error would be ckb_vm::Error::Pause,
-    /// * Other terminating errors
-    pub fn run(&mut self, mode: RunMode) -> Result<(i8, Cycle), Error> {
+    /// * Pause trigger, the returned error would be SchedulerError::Pause,
+    /// * Other terminating errors, see SchedulerError for more details
+    pub fn run(&mut self, mode: RunMode) -> Result<RunResult, SchedulerError> {
         if self.states.is_empty() {
             // Booting phase, we will need to initialize the first VM.
             assert_eq!(
-                self.boot_vm(&DataPieceId::Program, 0, u64::max_value(), &[])?,
+                self.boot_vm(&DataPieceId::Program, 0, u64::max_value(), &[], None)?,
                 ROOT_VM_ID
             );
+            self.scheduling_policy_id = self.scheduling_policy.id();
+            self.eviction_strategy_id = self.eviction_strategy.id();
         }
         assert!(self.states.contains_key(&ROOT_VM_ID));
+        if self.scheduling_policy.id() != self.scheduling_policy_id {
+            return Err(SchedulerError::SchedulingPolicyMismatch {
+                expected: self.scheduling_policy_id,
+                actual: self.scheduling_policy.id(),
+            });
+        }
+        if self.eviction_strategy.id() != self.eviction_strategy_id {
+            return Err(SchedulerError::EvictionStrategyMismatch {
+                expected: self.eviction_strategy_id,
+                actual: self.eviction_strategy.id(),
+            });
+        }
 
-        let (pause, mut limit_cycles) = match mode {
+        let (pause, mut remaining_cycles) = match mode {
             RunMode::LimitCycles(limit_cycles) => (Pause::new(), limit_cycles),
             RunMode::Pause(pause) => (pause, u64::max_value()),
         };
+        let initial_cycles = self.total_cycles;
 
         while self.states[&ROOT_VM_ID] != VmState::Terminated {
-            let consumed_cycles = self.iterate(pause.clone(), limit_cycles)?;
-            limit_cycles = consumed_cycles
-                .checked_sub(consumed_cycles)
-                .ok_or(Error::CyclesExceeded)?;
+            if remaining_cycles == 0 {
+                return Ok(RunResult::Exhausted {
+                    consumed_cycles: self.total_cycles - initial_cycles,
+                    total_cycles: self.total_cycles,
+                });
+            }
+            remaining_cycles = match self.iterate(pause.clone(), remaining_cycles) {
+                Ok(consumed_cycles) => remaining_cycles.saturating_sub(consumed_cycles),
+                // The running VM reaches the limit set from remaining budget
+                Err(SchedulerError::CyclesExceeded) => 0,
+                Err(e) => return Err(e),
+            };
         }
 
         // At this point, root VM cannot be suspended
         let root_vm = &self.instantiated[&ROOT_VM_ID];
-        Ok((root_vm.1.machine.exit_code(), self.total_cycles))
+        Ok(RunResult::Terminated {
+            exit_code: root_vm.1.machine.exit_code(),
+            consumed_cycles: self.total_cycles - initial_cycles,
+            total_cycles: self.total_cycles,
+            vm_stats: self.vm_stats.values().cloned().collect(),
+        })
     }
 
     // This is internal function that does the actual VM execution loop.
     // Here both pause signal and limit_cycles are provided so as to simplify
     // branches.
-    fn iterate(&mut self, pause: Pause, limit_cycles: Cycle) -> Result<Cycle, Error> {
+    fn iterate(&mut self, pause: Pause, limit_cycles: Cycle) -> Result<Cycle, SchedulerError> {
         // 1. Process all pending VM reads & writes
         self.process_io()?;
         // 2. Run an actual VM
-        // Find a runnable VM that has the largest ID
-        let vm_id_to_run = self
+        // Find a runnable VM, an interrupted VM is always picked first, the
+        // rest are decided by the scheduling policy.
+        let runnable: Vec<VmId> = self
             .states
             .iter()
-            .rev()
             .filter(|(_, state)| matches!(state, VmState::Runnable))
             .map(|(id, _)| *id)
-            .next();
-        if vm_id_to_run.is_none() {
-            return Err(Error::Unexpected(
-                "A deadlock situation has been reached!".to_string(),
-            ));
+            .collect();
+        if runnable.is_empty() {
+            let report = DeadlockReport::build(&self.states, &self.pipes);
+            log::debug!("Deadlock wait-for graph:\n{}", report.to_dot());
+            return Err(SchedulerError::Deadlock(report));
+        }
+        // An interrupted VM might have used up its time slice exactly when it
+        // was interrupted, in which case it is treated as preempted.
+        let slice_used_up = self
+            .time_slice
+            .map(|time_slice| self.slice_consumed_cycles >= time_slice)
+            .unwrap_or(false);
+        let vm_id_to_run = match self.interrupted_vm.take() {
+            Some(id) if runnable.contains(&id) && !slice_used_up => id,
+            _ => {
+                // A new turn starts
+                self.slice_consumed_cycles = 0;
+                self.scheduling_policy.select(
+                    &runnable,
+                    &SchedulingContext {
+                        last_run_vm: self.last_run_vm,
+                        vm_cycles: &self.vm_cycles,
+                    },
+                )
+            }
+        };
+        if !runnable.contains(&vm_id_to_run) {
+            return Err(SchedulerError::Unexpected(format!(
+                "Scheduling policy picks VM {} which is not runnable!",
+                vm_id_to_run
+            )));
         }
-        let vm_id_to_run = vm_id_to_run.unwrap();
         log::debug!("Running VM {}", vm_id_to_run);
-        let (result, consumed_cycles) = {
+        // Cycles left in current turn when time slicing is enabled
+        let slice_cycles = self
+            .time_slice
+            .map(|time_slice| time_slice.saturating_sub(self.slice_consumed_cycles));
+        let start_cycles = self.total_cycles;
+        let (result, consumed_cycles, charged_cycles, slice_max_cycles) = {
             self.ensure_vms_instantiated(&[vm_id_to_run])?;
             let (context, machine) = self.instantiated.get_mut(&vm_id_to_run).unwrap();
             context.set_base_cycles(self.total_cycles);
-            machine.set_max_cycles(limit_cycles);
+            // Cycles charged by the scheduler before the VM runs, they have
+            // been counted in VM stats already. They are not counted against
+            // the time slice, so a VM always makes progress in its turn, even
+            // when swapping it in costs more than a time slice.
+            let charged_cycles = machine.machine.cycles();
+            let slice_max_cycles =
+                slice_cycles.map(|slice_cycles| charged_cycles.saturating_add(slice_cycles));
+            let max_cycles = match slice_max_cycles {
+                Some(slice_max_cycles) => std::cmp::min(slice_max_cycles, limit_cycles),
+                None => limit_cycles,
+            };
+            machine.set_max_cycles(max_cycles);
             machine.machine.set_pause(pause);
             let result = machine.run();
             let consumed_cycles = {
@@ -215,13 +541,34 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                 machine.machine.set_cycles(0);
                 c
             };
-            // This shall be the only place where total_cycles gets updated
+            let syscall_cycles = context.take_syscall_cycles();
+            let stats = self.vm_stats.get_mut(&vm_id_to_run).unwrap();
+            stats.syscall_cycles = stats.syscall_cycles.saturating_add(syscall_cycles);
+            stats.execution_cycles = stats.execution_cycles.saturating_add(
+                consumed_cycles
+                    .saturating_sub(charged_cycles)
+                    .saturating_sub(syscall_cycles),
+            );
+            // Apart from cycles still pending when the root VM terminates,
+            // this shall be the only place where total_cycles gets updated
             self.total_cycles = self
                 .total_cycles
                 .checked_add(consumed_cycles)
-                .ok_or(Error::CyclesOverflow)?;
-            (result, consumed_cycles)
+                .ok_or(Error::CyclesOverflow)
+                .map_err(vm_error(vm_id_to_run))?;
+            let vm_cycles = self.vm_cycles.entry(vm_id_to_run).or_insert(0);
+            *vm_cycles = vm_cycles.saturating_add(consumed_cycles);
+            self.last_run_vm = Some(vm_id_to_run);
+            self.last_run_times.insert(vm_id_to_run, self.run_count);
+            self.run_count += 1;
+            (result, consumed_cycles, charged_cycles, slice_max_cycles)
         };
+        self.notify(|o, _| o.on_vm_run(start_cycles, vm_id_to_run, consumed_cycles));
+        if self.debug_sink == DebugSink::Log {
+            for debug in self.debug_output.lock().expect("lock").drain(..) {
+                log::debug!("Debug print from VM {}: {}", debug.vm_id, debug.message);
+            }
+        }
         // 3. Process message box, update VM states accordingly
         self.process_message_box()?;
         assert!(self.message_box.lock().expect("lock").is_empty());
@@ -231,72 +578,147 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         match result {
             Ok(code) => {
                 log::debug!("VM {} terminates with code {}", vm_id_to_run, code);
-                self.terminated_vms.insert(vm_id_to_run, code);
                 // When root VM terminates, the execution stops immediately, we will purge
                 // all non-root VMs, and only keep root VM in states.
                 // When non-root VM terminates, we only purge the VM's own states.
                 if vm_id_to_run == ROOT_VM_ID {
+                    self.terminated_vms.insert(vm_id_to_run, code);
+                    self.vm_stats.get_mut(&vm_id_to_run).unwrap().exit_code = Some(code);
+                    let vm_cycles = self.vm_cycles.get(&vm_id_to_run).copied().unwrap_or(0);
                     self.ensure_vms_instantiated(&[vm_id_to_run])?;
+                    // Cycles charged to VMs that never get to run again are
+                    // still part of total_cycles, they are paid by the root VM,
+                    // the same as cycles taken over from a killed VM.
+                    let pending_cycles: Vec<(VmId, Cycle)> = self
+                        .instantiated
+                        .iter()
+                        .map(|(id, (_, machine))| (*id, machine.machine.cycles()))
+                        .chain(
+                            self.suspended
+                                .iter()
+                                .map(|(id, snapshot)| (*id, snapshot.cycles)),
+                        )
+                        .collect();
+                    for (id, cycles) in pending_cycles {
+                        self.total_cycles = self
+                            .total_cycles
+                            .checked_add(cycles)
+                            .ok_or(Error::CyclesOverflow)
+                            .map_err(vm_error(vm_id_to_run))?;
+                        if id == ROOT_VM_ID {
+                            continue;
+                        }
+                        {
+                            let stats = self.vm_stats.get_mut(&id).unwrap();
+                            stats.unpaid_cycles = stats.unpaid_cycles.saturating_add(cycles);
+                        }
+                        {
+                            let stats = self.vm_stats.get_mut(&ROOT_VM_ID).unwrap();
+                            stats.syscall_cycles = stats.syscall_cycles.saturating_add(cycles);
+                        }
+                    }
+                    let (_, root_machine) = self.instantiated.get_mut(&ROOT_VM_ID).unwrap();
+                    root_machine.machine.set_cycles(0);
                     self.instantiated.retain(|id, _| *id == vm_id_to_run);
                     self.suspended.clear();
                     self.states.clear();
-                    self.states.insert(vm_id_to_run, VmState::Terminated);
-                } else {
-                    let mut joining_vms: Vec<(VmId, u64)> = Vec::new();
-                    self.states.iter().for_each(|(vm_id, state)| {
-                        if let VmState::Join {
-                            target_vm_id,
-                            exit_code_addr,
-                        } = state
-                        {
-                            if *target_vm_id == vm_id_to_run {
-                                joining_vms.push((*vm_id, *exit_code_addr));
-                            }
+                    // Remaining pipes are closed along with the root VM, while
+                    // non-root VMs still alive get no on_vm_terminate, they
+                    // never exit.
+                    if self.observer.is_some() {
+                        let mut closed_pipes: Vec<(PipeId, VmId)> =
+                            self.pipes.iter().map(|(pipe, id)| (*pipe, *id)).collect();
+                        closed_pipes.sort_by_key(|(pipe, _)| pipe.0);
+                        for (pipe, id) in closed_pipes {
+                            self.notify(|o, cycles| o.on_pipe_close(cycles, id, pipe));
                         }
-                    });
-                    // For all joining VMs, update exit code, then mark them as
-                    // runnable state.
-                    for (vm_id, exit_code_addr) in joining_vms {
-                        self.ensure_vms_instantiated(&[vm_id])?;
-                        let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
-                        machine
-                            .machine
-                            .memory_mut()
-                            .store8(&exit_code_addr, &u64::from_i8(code))?;
-                        machine.machine.set_register(A0, SUCCESS as u64);
-                        self.states.insert(vm_id, VmState::Runnable);
                     }
-                    // Close pipes
-                    self.pipes.retain(|_, vm_id| *vm_id != vm_id_to_run);
-                    // Clear terminated VM states
-                    self.states.remove(&vm_id_to_run);
-                    self.instantiated.remove(&vm_id_to_run);
-                    self.suspended.remove(&vm_id_to_run);
+                    self.pipes.clear();
+                    self.set_state(vm_id_to_run, VmState::Terminated);
+                    self.spawn_depths.clear();
+                    self.vm_cycles.clear();
+                    self.last_run_times.clear();
+                    self.notify(|o, cycles| {
+                        o.on_vm_terminate(cycles, vm_id_to_run, code, vm_cycles)
+                    });
+                } else {
+                    self.terminate_vm(vm_id_to_run, code)?;
                 }
                 Ok(consumed_cycles)
             }
-            Err(Error::External(msg)) if msg == "YIELD" => Ok(consumed_cycles),
-            Err(e) => Err(e),
+            Err(e) if is_yield(&e) => Ok(consumed_cycles),
+            // The VM uses up its time slice, it is preempted but stays
+            // runnable. The next VM to run will be picked in next iteration.
+            Err(Error::CyclesExceeded)
+                if slice_max_cycles
+                    .map(|slice_max_cycles| slice_max_cycles <= limit_cycles)
+                    .unwrap_or(false) =>
+            {
+                log::debug!("VM {} is preempted", vm_id_to_run);
+                Ok(consumed_cycles)
+            }
+            Err(Error::CyclesExceeded) => {
+                self.interrupted_vm = Some(vm_id_to_run);
+                self.slice_consumed_cycles = self
+                    .slice_consumed_cycles
+                    .saturating_add(consumed_cycles.saturating_sub(charged_cycles));
+                Err(SchedulerError::CyclesExceeded)
+            }
+            Err(Error::Pause) => {
+                self.interrupted_vm = Some(vm_id_to_run);
+                self.slice_consumed_cycles = self
+                    .slice_consumed_cycles
+                    .saturating_add(consumed_cycles.saturating_sub(charged_cycles));
+                Err(SchedulerError::Pause)
+            }
+            Err(error) => Err(SchedulerError::Vm {
+                vm_id: vm_id_to_run,
+                error,
+            }),
         }
     }
 
-    fn process_message_box(&mut self) -> Result<(), Error> {
+    fn process_message_box(&mut self) -> Result<(), SchedulerError> {
         let messages: Vec<Message> = self.message_box.lock().expect("lock").drain(..).collect();
         for message in messages {
+            self.notify(|o, cycles| o.on_message(cycles, &message));
             match message {
                 Message::Spawn(vm_id, args) => {
                     // All pipes must belong to the correct owner
                     for pipe in &args.pipes {
                         if !(self.pipes.contains_key(pipe) && (self.pipes[pipe] == vm_id)) {
-                            return Err(Error::Unexpected(format!(
-                                "VM {} does not own pipe {}!",
-                                vm_id, pipe.0,
-                            )));
+                            return Err(SchedulerError::PipeOwnership { vm_id, pipe: *pipe });
                         }
                     }
-                    // TODO: spawn limits
-                    let spawned_vm_id =
-                        self.boot_vm(&args.data_piece_id, args.offset, args.length, &args.argv)?;
+                    let depth = self.spawn_depths.get(&vm_id).copied().unwrap_or(0) + 1;
+                    // Root VM is booted directly, it is not counted as a spawn
+                    let spawns = self.next_vm_id - FIRST_VM_ID - 1;
+                    let code = if self.states.len() as u64 >= self.limits.max_live_vms
+                        || spawns >= self.limits.max_spawns
+                    {
+                        Some(MAX_VMS_SPAWNED)
+                    } else if depth > self.limits.max_spawn_depth {
+                        Some(MAX_SPAWN_DEPTH)
+                    } else {
+                        None
+                    };
+                    if let Some(code) = code {
+                        self.ensure_vms_instantiated(&[vm_id])?;
+                        {
+                            let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+                            machine.machine.set_register(A0, code as u64);
+                        }
+                        continue;
+                    }
+                    let spawned_vm_id = self.boot_vm(
+                        &args.data_piece_id,
+                        args.offset,
+                        args.length,
+                        &args.argv,
+                        Some(vm_id),
+                    )?;
+                    self.spawn_depths.insert(spawned_vm_id, depth);
+                    self.notify(|o, cycles| o.on_spawn(cycles, vm_id, spawned_vm_id, &args.pipes));
                     // Move passed pipes from spawner to spawnee
                     for pipe in &args.pipes {
                         self.pipes.insert(*pipe, spawned_vm_id);
@@ -307,22 +729,20 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                         machine
                             .machine
                             .memory_mut()
-                            .store64(&args.instance_id_addr, &spawned_vm_id)?;
+                            .store64(&args.instance_id_addr, &spawned_vm_id)
+                            .map_err(vm_error(vm_id))?;
                         machine.machine.set_register(A0, SUCCESS as u64);
                     }
                 }
                 Message::Join(vm_id, args) => {
                     if let Some(exit_code) = self.terminated_vms.get(&args.target_id).copied() {
-                        self.ensure_vms_instantiated(&[vm_id])?;
-                        {
-                            let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
-                            machine
-                                .machine
-                                .memory_mut()
-                                .store8(&args.exit_code_addr, &u64::from_i8(exit_code))?;
-                            machine.machine.set_register(A0, SUCCESS as u64);
-                            self.states.insert(vm_id, VmState::Runnable);
-                        }
+                        self.finish_join(
+                            vm_id,
+                            args.target_id,
+                            exit_code,
+                            None,
+                            args.exit_code_addr,
+                        )?;
                         continue;
                     }
                     if !self.states.contains_key(&args.target_id) {
@@ -334,7 +754,8 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                         continue;
                     }
                     // Return code will be updated when the joining VM exits
-                    self.states.insert(
+                    self.notify(|o, cycles| o.on_join_wait(cycles, vm_id, &[args.target_id]));
+                    self.set_state(
                         vm_id,
                         VmState::Join {
                             target_vm_id: args.target_id,
@@ -343,13 +764,25 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                     );
                 }
                 Message::Pipe(vm_id, args) => {
-                    // TODO: pipe limits
+                    let owned_pipes =
+                        self.pipes.values().filter(|owner| **owner == vm_id).count() as u64;
+                    if owned_pipes + 2 > self.limits.max_pipes_per_vm
+                        || self.pipes.len() as u64 + 2 > self.limits.max_pipes
+                    {
+                        self.ensure_vms_instantiated(&[vm_id])?;
+                        {
+                            let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+                            machine.machine.set_register(A0, MAX_PIPES_CREATED as u64);
+                        }
+                        continue;
+                    }
                     let (p1, p2, slot) = PipeId::create(self.next_pipe_slot);
                     self.next_pipe_slot = slot;
                     log::debug!("VM {} creates pipes ({}, {})", vm_id, p1.0, p2.0);
 
                     self.pipes.insert(p1, vm_id);
                     self.pipes.insert(p2, vm_id);
+                    self.notify(|o, cycles| o.on_pipe_create(cycles, vm_id, p1, p2));
 
                     self.ensure_vms_instantiated(&[vm_id])?;
                     {
@@ -357,11 +790,13 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                         machine
                             .machine
                             .memory_mut()
-                            .store64(&args.pipe1_addr, &p1.0)?;
+                            .store64(&args.pipe1_addr, &p1.0)
+                            .map_err(vm_error(vm_id))?;
                         machine
                             .machine
                             .memory_mut()
-                            .store64(&args.pipe2_addr, &p2.0)?;
+                            .store64(&args.pipe2_addr, &p2.0)
+                            .map_err(vm_error(vm_id))?;
                         machine.machine.set_register(A0, SUCCESS as u64);
                     }
                 }
@@ -382,8 +817,19 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                         }
                         continue;
                     }
+                    // A non-blocking read can only proceed when a VM is blocked
+                    // writing to the other end, the actual transfer happens in
+                    // process_io before any VM gets to run.
+                    if args.nonblocking && !self.is_pipe_ready(&args.pipe) {
+                        self.ensure_vms_instantiated(&[vm_id])?;
+                        {
+                            let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+                            machine.machine.set_register(A0, WOULD_BLOCK as u64);
+                        }
+                        continue;
+                    }
                     // Return code will be updated when the read operation finishes
-                    self.states.insert(
+                    self.set_state(
                         vm_id,
                         VmState::WaitForRead {
                             pipe: args.pipe,
@@ -410,24 +856,380 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                         }
                         continue;
                     }
+                    // Similar to read, a non-blocking write can only proceed when
+                    // a VM is blocked reading from the other end. The write is
+                    // truncated to what the reader can accept, so it finishes
+                    // with a single transfer.
+                    let length = if args.nonblocking {
+                        match self
+                            .pipes
+                            .get(&args.pipe.other_pipe())
+                            .and_then(|id| self.states.get(id))
+                        {
+                            Some(VmState::WaitForRead { pipe, length, .. })
+                                if *pipe == args.pipe.other_pipe() =>
+                            {
+                                std::cmp::min(args.length, *length)
+                            }
+                            _ => {
+                                self.ensure_vms_instantiated(&[vm_id])?;
+                                {
+                                    let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+                                    machine.machine.set_register(A0, WOULD_BLOCK as u64);
+                                }
+                                continue;
+                            }
+                        }
+                    } else {
+                        args.length
+                    };
                     // Return code will be updated when the write operation finishes
-                    self.states.insert(
+                    self.set_state(
                         vm_id,
                         VmState::WaitForWrite {
                             pipe: args.pipe,
                             consumed: 0,
-                            length: args.length,
+                            length,
                             buffer_addr: args.buffer_addr,
                             length_addr: args.length_addr,
                         },
                     );
                 }
+                Message::Close(vm_id, pipe) => {
+                    self.ensure_vms_instantiated(&[vm_id])?;
+                    if !(self.pipes.contains_key(&pipe) && (self.pipes[&pipe] == vm_id)) {
+                        let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+                        machine.machine.set_register(A0, INVALID_PIPE as u64);
+                        continue;
+                    }
+                    log::debug!("VM {} closes pipe {}", vm_id, pipe.0);
+                    // VMs blocked on the other end of the pipe will be woken up
+                    // in process_io, just like the case where a VM terminates.
+                    self.pipes.remove(&pipe);
+                    self.notify(|o, cycles| o.on_pipe_close(cycles, vm_id, pipe));
+                    let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+                    machine.machine.set_register(A0, SUCCESS as u64);
+                }
+                Message::Select(vm_id, args) => {
+                    if args
+                        .pipes
+                        .iter()
+                        .any(|pipe| !(self.pipes.contains_key(pipe) && (self.pipes[pipe] == vm_id)))
+                    {
+                        self.ensure_vms_instantiated(&[vm_id])?;
+                        {
+                            let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+                            machine.machine.set_register(A0, INVALID_PIPE as u64);
+                        }
+                        continue;
+                    }
+                    // Return code will be updated when any of the pipes is ready
+                    self.set_state(
+                        vm_id,
+                        VmState::WaitForAny {
+                            pipes: args.pipes,
+                            ready_pipe_addr: args.ready_pipe_addr,
+                        },
+                    );
+                }
+                Message::Kill(vm_id, target_id) => {
+                    self.ensure_vms_instantiated(&[vm_id])?;
+                    let code = if !self.states.contains_key(&target_id) {
+                        Some(KILL_FAILURE)
+                    } else if !self.spawn_tree.is_ancestor(vm_id, target_id) {
+                        Some(NOT_PERMITTED)
+                    } else {
+                        None
+                    };
+                    if let Some(code) = code {
+                        let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+                        machine.machine.set_register(A0, code as u64);
+                        continue;
+                    }
+                    log::debug!("VM {} kills VM {}", vm_id, target_id);
+                    // Cycles charged to the killed VM, that are not yet
+                    // accumulated into total_cycles, are paid by the killing VM.
+                    let pending_cycles = match self.instantiated.get(&target_id) {
+                        Some((_, machine)) => machine.machine.cycles(),
+                        None => self.suspended[&target_id].cycles,
+                    };
+                    self.charge_cycles(&vm_id, pending_cycles)?;
+                    // The cycles have been counted in the killed VM's stats
+                    // when they were charged, they are moved to the killing VM.
+                    {
+                        let stats = self.vm_stats.get_mut(&target_id).unwrap();
+                        stats.unpaid_cycles = stats.unpaid_cycles.saturating_add(pending_cycles);
+                    }
+                    {
+                        let stats = self.vm_stats.get_mut(&vm_id).unwrap();
+                        stats.syscall_cycles = stats.syscall_cycles.saturating_add(pending_cycles);
+                    }
+                    self.terminate_vm(target_id, KILLED_EXIT_CODE)?;
+                    // The killing VM might have been suspended to wake up VMs
+                    // joining the killed VM.
+                    self.ensure_vms_instantiated(&[vm_id])?;
+                    let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+                    machine.machine.set_register(A0, SUCCESS as u64);
+                }
+                Message::Exec(vm_id, args) => {
+                    log::debug!("VM {} execs {:?}", vm_id, args.data_piece_id);
+                    // The VM is rebuilt with the new program, cycles charged
+                    // to the old VM are carried over.
+                    self.ensure_vms_instantiated(&[vm_id])?;
+                    let (_, old_machine) = self.instantiated.remove(&vm_id).unwrap();
+                    let (context, mut machine, load_cycles) = self.load_vm(
+                        vm_id,
+                        &args.data_piece_id,
+                        args.offset,
+                        args.length,
+                        &args.argv,
+                    )?;
+                    machine
+                        .machine
+                        .add_cycles_no_checking(old_machine.machine.cycles())
+                        .map_err(vm_error(vm_id))?;
+                    self.instantiated.insert(vm_id, (context, machine));
+                    let stats = self.vm_stats.get_mut(&vm_id).unwrap();
+                    stats.program = args.data_piece_id;
+                    stats.syscall_cycles = stats.syscall_cycles.saturating_add(load_cycles);
+                }
+                Message::JoinAny(vm_id, args) => {
+                    // Candidates are sorted by ID, so when multiple VMs have
+                    // terminated, the one with the lowest ID is returned.
+                    // Children already joined by current VM are skipped in
+                    // explicit lists as well, other VMs are never reaped.
+                    let candidates: Vec<VmId> = if args.target_ids.is_empty() {
+                        self.spawn_tree.children(vm_id)
+                    } else {
+                        let mut ids = args.target_ids.clone();
+                        ids.sort_unstable();
+                        ids.dedup();
+                        ids
+                    }
+                    .into_iter()
+                    .filter(|id| {
+                        !(self.reaped_vms.contains(id)
+                            && self.spawn_tree.parent(*id) == Some(vm_id))
+                    })
+                    .collect();
+                    if let Some((target_id, exit_code)) = candidates
+                        .iter()
+                        .find_map(|id| self.terminated_vms.get(id).map(|code| (*id, *code)))
+                    {
+                        self.finish_join(
+                            vm_id,
+                            target_id,
+                            exit_code,
+                            Some(args.instance_id_addr),
+                            args.exit_code_addr,
+                        )?;
+                        continue;
+                    }
+                    let target_vm_ids: Vec<VmId> = candidates
+                        .into_iter()
+                        .filter(|id| self.states.contains_key(id))
+                        .collect();
+                    if target_vm_ids.is_empty() {
+                        self.ensure_vms_instantiated(&[vm_id])?;
+                        {
+                            let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+                            machine.machine.set_register(A0, JOIN_FAILURE as u64);
+                        }
+                        continue;
+                    }
+                    // Return code will be updated when any of the VMs exits
+                    self.notify(|o, cycles| o.on_join_wait(cycles, vm_id, &target_vm_ids));
+                    self.set_state(
+                        vm_id,
+                        VmState::JoinAny {
+                            target_vm_ids,
+                            instance_id_addr: args.instance_id_addr,
+                            exit_code_addr: args.exit_code_addr,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Terminate a non-root VM: record its exit code, close its pipes, purge
+    // all its states, and wake up VMs joining it.
+    fn terminate_vm(&mut self, vm_id_to_terminate: VmId, code: i8) -> Result<(), SchedulerError> {
+        self.terminated_vms.insert(vm_id_to_terminate, code);
+        self.vm_stats
+            .get_mut(&vm_id_to_terminate)
+            .unwrap()
+            .exit_code = Some(code);
+        // Close pipes
+        if self.observer.is_some() {
+            let mut closed_pipes: Vec<PipeId> = self
+                .pipes
+                .iter()
+                .filter(|(_, vm_id)| **vm_id == vm_id_to_terminate)
+                .map(|(pipe, _)| *pipe)
+                .collect();
+            closed_pipes.sort_by_key(|pipe| pipe.0);
+            for pipe in closed_pipes {
+                self.notify(|o, cycles| o.on_pipe_close(cycles, vm_id_to_terminate, pipe));
+            }
+        }
+        self.pipes.retain(|_, vm_id| *vm_id != vm_id_to_terminate);
+        let vm_cycles = self
+            .vm_cycles
+            .get(&vm_id_to_terminate)
+            .copied()
+            .unwrap_or(0);
+        self.notify(|o, cycles| o.on_vm_terminate(cycles, vm_id_to_terminate, code, vm_cycles));
+        // Clear terminated VM states
+        self.states.remove(&vm_id_to_terminate);
+        self.spawn_depths.remove(&vm_id_to_terminate);
+        self.vm_cycles.remove(&vm_id_to_terminate);
+        self.last_run_times.remove(&vm_id_to_terminate);
+        self.instantiated.remove(&vm_id_to_terminate);
+        self.suspended.remove(&vm_id_to_terminate);
+        if self.interrupted_vm == Some(vm_id_to_terminate) {
+            self.interrupted_vm = None;
+        }
+        let mut joining_vms: Vec<(VmId, Option<u64>, u64)> = Vec::new();
+        self.states.iter().for_each(|(vm_id, state)| match state {
+            VmState::Join {
+                target_vm_id,
+                exit_code_addr,
+            } if *target_vm_id == vm_id_to_terminate => {
+                joining_vms.push((*vm_id, None, *exit_code_addr));
             }
+            VmState::JoinAny {
+                target_vm_ids,
+                instance_id_addr,
+                exit_code_addr,
+            } if target_vm_ids.contains(&vm_id_to_terminate) => {
+                joining_vms.push((*vm_id, Some(*instance_id_addr), *exit_code_addr));
+            }
+            _ => (),
+        });
+        // For all joining VMs, update exit code, then mark them as
+        // runnable state. This happens after the terminated VM is purged,
+        // so observers see the VM terminate before its joiners wake up,
+        // and the VM is never picked for eviction.
+        for (vm_id, instance_id_addr, exit_code_addr) in joining_vms {
+            self.finish_join(
+                vm_id,
+                vm_id_to_terminate,
+                code,
+                instance_id_addr,
+                exit_code_addr,
+            )?;
+        }
+        Ok(())
+    }
+
+    // Update the state of a VM, the observer gets notified of the change
+    fn set_state(&mut self, vm_id: VmId, state: VmState) {
+        self.notify(|o, cycles| o.on_vm_state_change(cycles, vm_id, &state));
+        self.states.insert(vm_id, state);
+    }
+
+    // Notify the observer if there is one, the closure also gets current
+    // total cycles.
+    fn notify<F: FnOnce(&mut dyn SchedulerObserver, Cycle)>(&mut self, f: F) {
+        if let Some(observer) = self.observer.as_mut() {
+            f(observer.as_mut(), self.total_cycles);
+        }
+    }
+
+    // Finish a join / join_any syscall with the terminated VM's exit code,
+    // the terminated VM is reaped if the joining VM is its parent.
+    fn finish_join(
+        &mut self,
+        vm_id: VmId,
+        target_id: VmId,
+        exit_code: i8,
+        instance_id_addr: Option<u64>,
+        exit_code_addr: u64,
+    ) -> Result<(), SchedulerError> {
+        self.ensure_vms_instantiated(&[vm_id])?;
+        let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+        if let Some(instance_id_addr) = instance_id_addr {
+            machine
+                .machine
+                .memory_mut()
+                .store64(&instance_id_addr, &target_id)
+                .map_err(vm_error(vm_id))?;
+        }
+        machine
+            .machine
+            .memory_mut()
+            .store8(&exit_code_addr, &u64::from_i8(exit_code))
+            .map_err(vm_error(vm_id))?;
+        machine.machine.set_register(A0, SUCCESS as u64);
+        self.notify(|o, cycles| o.on_join_wakeup(cycles, vm_id, target_id, exit_code));
+        self.set_state(vm_id, VmState::Runnable);
+        if self.spawn_tree.parent(target_id) == Some(vm_id) {
+            self.reaped_vms.insert(target_id);
         }
         Ok(())
     }
 
-    fn process_io(&mut self) -> Result<(), Error> {
+    // A pipe is ready when a read / write operation on it can finish without
+    // waiting for any VM to run: either the other end is closed, or a VM is
+    // blocked writing to / reading from the other end.
+    fn is_pipe_ready(&self, pipe: &PipeId) -> bool {
+        let other_pipe = pipe.other_pipe();
+        match self.pipes.get(&other_pipe) {
+            None => true,
+            Some(owner) => match self.states.get(owner) {
+                Some(VmState::WaitForWrite { pipe, .. }) => *pipe == other_pipe,
+                Some(VmState::WaitForRead { pipe, .. }) => *pipe == other_pipe,
+                _ => false,
+            },
+        }
+    }
+
+    // For select syscall, a pipe is also ready when the VM owning the other
+    // end is selecting on that end. Both VMs are woken up in the same round,
+    // so two VMs selecting on the two ends of a pipe do not wait for each
+    // other forever.
+    fn is_pipe_selectable(&self, pipe: &PipeId) -> bool {
+        if self.is_pipe_ready(pipe) {
+            return true;
+        }
+        let other_pipe = pipe.other_pipe();
+        matches!(
+            self.pipes.get(&other_pipe).and_then(|owner| self.states.get(owner)),
+            Some(VmState::WaitForAny { pipes, .. }) if pipes.contains(&other_pipe)
+        )
+    }
+
+    fn process_io(&mut self) -> Result<(), SchedulerError> {
+        // Wake up VMs waiting on any of a set of pipes. For determinism, pipes
+        // are checked in the order they are passed to select syscall, the
+        // first ready pipe is returned. All ready selects are collected before
+        // any VM is woken up, so the result does not depend on VM order.
+        let mut ready_selects: Vec<(VmId, u64, PipeId)> = Vec::new();
+        for (vm_id, state) in &self.states {
+            if let VmState::WaitForAny {
+                pipes,
+                ready_pipe_addr,
+            } = state
+            {
+                if let Some(pipe) = pipes.iter().find(|pipe| self.is_pipe_selectable(pipe)) {
+                    ready_selects.push((*vm_id, *ready_pipe_addr, *pipe));
+                }
+            }
+        }
+        for (vm_id, ready_pipe_addr, pipe) in ready_selects {
+            self.ensure_vms_instantiated(&[vm_id])?;
+            let (_, machine) = self.instantiated.get_mut(&vm_id).unwrap();
+            machine
+                .machine
+                .memory_mut()
+                .store64(&ready_pipe_addr, &pipe.0)
+                .map_err(vm_error(vm_id))?;
+            machine.machine.set_register(A0, SUCCESS as u64);
+            self.set_state(vm_id, VmState::Runnable);
+        }
+
         let mut reads: HashMap<PipeId, (VmId, VmState)> = HashMap::default();
         let mut closed_pipes: Vec<VmId> = Vec::new();
         self.states.iter().for_each(|(vm_id, state)| {
@@ -453,15 +1255,17 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         });
         // Finish read / write syscalls for pipes that are closed on the other end
         for vm_id in closed_pipes {
+            self.ensure_vms_instantiated(&[vm_id])?;
             match self.states[&vm_id].clone() {
                 VmState::WaitForRead { length_addr, .. } => {
                     let (_, read_machine) = self.instantiated.get_mut(&vm_id).unwrap();
                     read_machine
                         .machine
                         .memory_mut()
-                        .store64(&length_addr, &0)?;
+                        .store64(&length_addr, &0)
+                        .map_err(vm_error(vm_id))?;
                     read_machine.machine.set_register(A0, SUCCESS as u64);
-                    self.states.insert(vm_id, VmState::Runnable);
+                    self.set_state(vm_id, VmState::Runnable);
                 }
                 VmState::WaitForWrite {
                     consumed,
@@ -472,9 +1276,10 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                     write_machine
                         .machine
                         .memory_mut()
-                        .store64(&length_addr, &consumed)?;
+                        .store64(&length_addr, &consumed)
+                        .map_err(vm_error(vm_id))?;
                     write_machine.machine.set_register(A0, SUCCESS as u64);
-                    self.states.insert(vm_id, VmState::Runnable);
+                    self.set_state(vm_id, VmState::Runnable);
                 }
                 _ => (),
             }
@@ -508,7 +1313,6 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                 let copiable = std::cmp::min(fillable, consumable);
 
                 // Actual data copying
-                // TODO: charge cycles
                 let data = self
                     .instantiated
                     .get_mut(&write_vm_id)
@@ -516,23 +1320,45 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                     .1
                     .machine
                     .memory_mut()
-                    .load_bytes(write_buffer_addr.wrapping_add(consumed), copiable)?;
+                    .load_bytes(write_buffer_addr.wrapping_add(consumed), copiable)
+                    .map_err(vm_error(write_vm_id))?;
                 self.instantiated
                     .get_mut(&read_vm_id)
                     .unwrap()
                     .1
                     .machine
                     .memory_mut()
-                    .store_bytes(read_buffer_addr, &data)?;
+                    .store_bytes(read_buffer_addr, &data)
+                    .map_err(vm_error(read_vm_id))?;
 
                 // Read syscall terminates as soon as some data are filled
                 let (_, read_machine) = self.instantiated.get_mut(&read_vm_id).unwrap();
                 read_machine
                     .machine
                     .memory_mut()
-                    .store64(&read_length_addr, &copiable)?;
+                    .store64(&read_length_addr, &copiable)
+                    .map_err(vm_error(read_vm_id))?;
                 read_machine.machine.set_register(A0, SUCCESS as u64);
-                self.states.insert(read_vm_id, VmState::Runnable);
+                // Transfer cycles are charged to the VMs directly, they will be
+                // accumulated into total_cycles when the VMs get to run again.
+                let read_cycles = self.cost_schedule.pipe_transfer_read_cycles(copiable);
+                self.charge_cycles(&read_vm_id, read_cycles)?;
+                self.set_state(read_vm_id, VmState::Runnable);
+                self.notify(|o, cycles| {
+                    o.on_pipe_transfer(cycles, write_vm_id, write_pipe, read_vm_id, copiable)
+                });
+                let write_cycles = self.cost_schedule.pipe_transfer_write_cycles(copiable);
+                self.charge_cycles(&write_vm_id, write_cycles)?;
+                {
+                    let stats = self.vm_stats.get_mut(&read_vm_id).unwrap();
+                    stats.syscall_cycles = stats.syscall_cycles.saturating_add(read_cycles);
+                    stats.bytes_read = stats.bytes_read.saturating_add(copiable);
+                }
+                {
+                    let stats = self.vm_stats.get_mut(&write_vm_id).unwrap();
+                    stats.syscall_cycles = stats.syscall_cycles.saturating_add(write_cycles);
+                    stats.bytes_written = stats.bytes_written.saturating_add(copiable);
+                }
 
                 // Write syscall, however, terminates only when all the data
                 // have been written, or when the pairing read pipe is closed.
@@ -543,12 +1369,13 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                     write_machine
                         .machine
                         .memory_mut()
-                        .store64(&write_length_addr, &write_length)?;
+                        .store64(&write_length_addr, &write_length)
+                        .map_err(vm_error(write_vm_id))?;
                     write_machine.machine.set_register(A0, SUCCESS as u64);
-                    self.states.insert(write_vm_id, VmState::Runnable);
+                    self.set_state(write_vm_id, VmState::Runnable);
                 } else {
                     // Only update write VM state
-                    self.states.insert(
+                    self.set_state(
                         write_vm_id,
                         VmState::WaitForWrite {
                             pipe: write_pipe,
@@ -565,13 +1392,12 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
     }
 
     // Ensure VMs are instantiated
-    fn ensure_vms_instantiated(&mut self, ids: &[VmId]) -> Result<(), Error> {
-        if ids.len() > MAX_INSTANTIATED_VMS {
-            return Err(Error::Unexpected(format!(
-                "At most {} VMs can be instantiated but {} are requested!",
-                MAX_INSTANTIATED_VMS,
-                ids.len()
-            )));
+    fn ensure_vms_instantiated(&mut self, ids: &[VmId]) -> Result<(), SchedulerError> {
+        if ids.len() > self.max_instantiated_vms {
+            return Err(SchedulerError::VmLimitReached {
+                limit: self.max_instantiated_vms,
+                requested: ids.len(),
+            });
         }
 
         let mut uninstantiated_ids: Vec<VmId> = ids
@@ -579,66 +1405,146 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
             .filter(|id| !self.instantiated.contains_key(id))
             .copied()
             .collect();
-        while (!uninstantiated_ids.is_empty()) && (self.instantiated.len() < MAX_INSTANTIATED_VMS) {
+        while (!uninstantiated_ids.is_empty())
+            && (self.instantiated.len() < self.max_instantiated_vms)
+        {
             let id = uninstantiated_ids.pop().unwrap();
-            self.resume_vm(&id)?;
+            let resume_cycles = self.resume_vm(&id)?;
+            self.charge_cycles(&id, resume_cycles)?;
+            self.count_swap_cycles(&id, resume_cycles);
+            self.swap_count += 1;
         }
 
         if !uninstantiated_ids.is_empty() {
-            // instantiated is a BTreeMap, an iterator on it maintains key order to ensure deterministic behavior
-            let suspendable_ids: Vec<VmId> = self
-                .instantiated
-                .keys()
-                .filter(|id| !ids.contains(id))
-                .copied()
-                .collect();
-
-            assert!(suspendable_ids.len() >= uninstantiated_ids.len());
+            let suspendable_ids = self.pick_vms_to_suspend(ids, uninstantiated_ids.len())?;
             for i in 0..uninstantiated_ids.len() {
-                self.suspend_vm(&suspendable_ids[i])?;
-                self.resume_vm(&uninstantiated_ids[i])?;
+                // The VM to instantiate pays for both its own resuming, and
+                // the suspending of the VM it replaces.
+                let suspend_cycles = self.suspend_vm(&suspendable_ids[i])?;
+                let resume_cycles = self.resume_vm(&uninstantiated_ids[i])?;
+                self.swap_count += 2;
+                let swap_cycles = suspend_cycles
+                    .checked_add(resume_cycles)
+                    .ok_or(Error::CyclesOverflow)
+                    .map_err(vm_error(uninstantiated_ids[i]))?;
+                self.charge_cycles(&uninstantiated_ids[i], swap_cycles)?;
+                self.count_swap_cycles(&uninstantiated_ids[i], swap_cycles);
             }
         }
 
         Ok(())
     }
 
-    // Resume a suspended VM
-    fn resume_vm(&mut self, id: &VmId) -> Result<(), Error> {
+    // Pick instantiated VMs to suspend via the eviction strategy, VMs in
+    // `excluded` are never picked.
+    fn pick_vms_to_suspend(
+        &mut self,
+        excluded: &[VmId],
+        count: usize,
+    ) -> Result<Vec<VmId>, SchedulerError> {
+        let mut candidates = Vec::new();
+        let needs_dirty_bytes = self.eviction_strategy.needs_dirty_bytes();
+        // instantiated is a BTreeMap, an iterator on it maintains key order to ensure deterministic behavior
+        for (id, (_, machine)) in self.instantiated.iter_mut() {
+            if excluded.contains(id) {
+                continue;
+            }
+            let mut dirty_bytes = 0;
+            if needs_dirty_bytes {
+                let memory = machine.machine.memory_mut();
+                for page in 0..memory.memory_pages() as u64 {
+                    if memory.fetch_flag(page).map_err(vm_error(*id))? & FLAG_DIRTY != 0 {
+                        dirty_bytes += RISCV_PAGESIZE as u64;
+                    }
+                }
+            }
+            candidates.push(EvictionCandidate {
+                vm_id: *id,
+                last_run: self.last_run_times.get(id).copied().unwrap_or(0),
+                dirty_bytes,
+            });
+        }
+        assert!(candidates.len() >= count);
+        let picked = self.eviction_strategy.select(&candidates, count);
+        let mut unique = picked.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        if picked.len() != count
+            || unique.len() != count
+            || !unique
+                .iter()
+                .all(|id| candidates.iter().any(|c| c.vm_id == *id))
+        {
+            return Err(SchedulerError::Unexpected(format!(
+                "Eviction strategy picks invalid VMs {:?}!",
+                picked
+            )));
+        }
+        Ok(picked)
+    }
+
+    // Charge cycles to an instantiated VM, the cycles will be accumulated
+    // into total_cycles when the VM runs next time.
+    fn charge_cycles(&mut self, id: &VmId, cycles: Cycle) -> Result<(), SchedulerError> {
+        let (_, machine) = self.instantiated.get_mut(id).unwrap();
+        machine
+            .machine
+            .add_cycles_no_checking(cycles)
+            .map_err(vm_error(*id))
+    }
+
+    fn count_swap_cycles(&mut self, id: &VmId, cycles: Cycle) {
+        let stats = self.vm_stats.get_mut(id).unwrap();
+        stats.swap_cycles = stats.swap_cycles.saturating_add(cycles);
+    }
+
+    // Resume a suspended VM, returns the cycles required to resume the VM.
+    // It is up to the caller to decide which VM shall pay for the cycles.
+    fn resume_vm(&mut self, id: &VmId) -> Result<Cycle, SchedulerError> {
         log::debug!("Resuming VM: {}", id);
         if !self.suspended.contains_key(id) {
-            return Err(Error::Unexpected(format!("VM {:?} is not suspended!", id)));
+            return Err(SchedulerError::Unexpected(format!(
+                "VM {:?} is not suspended!",
+                id
+            )));
         }
         let snapshot = &self.suspended[id];
+        let bytes = swap_bytes(snapshot);
+        let cycles = self.cost_schedule.swap_cycles(bytes);
         let (context, mut machine) = self.create_dummy_vm(id)?;
         {
             let mut sc = context.snapshot2_context().lock().expect("lock");
-            sc.resume(&mut machine.machine, snapshot)?;
+            sc.resume(&mut machine.machine, snapshot)
+                .map_err(snapshot_error(*id))?;
         }
-        // TODO: charge cycles
         self.instantiated.insert(*id, (context, machine));
         self.suspended.remove(id);
-        Ok(())
+        self.notify(|o, cycles| o.on_vm_resume(cycles, *id, bytes));
+        Ok(cycles)
     }
 
-    // Suspend an instantiated VM
-    fn suspend_vm(&mut self, id: &VmId) -> Result<(), Error> {
+    // Suspend an instantiated VM, returns the cycles required to suspend the VM.
+    // It is up to the caller to decide which VM shall pay for the cycles.
+    fn suspend_vm(&mut self, id: &VmId) -> Result<Cycle, SchedulerError> {
         log::debug!("Suspending VM: {}", id);
         if !self.instantiated.contains_key(id) {
-            return Err(Error::Unexpected(format!(
+            return Err(SchedulerError::Unexpected(format!(
                 "VM {:?} is not instantiated!",
                 id
             )));
         }
-        // TODO: charge cycles
         let (context, machine) = self.instantiated.get_mut(id).unwrap();
         let snapshot = {
             let sc = context.snapshot2_context().lock().expect("lock");
-            sc.make_snapshot(&mut machine.machine)?
+            sc.make_snapshot(&mut machine.machine)
+                .map_err(snapshot_error(*id))?
         };
+        let bytes = swap_bytes(&snapshot);
+        let cycles = self.cost_schedule.swap_cycles(bytes);
         self.suspended.insert(*id, snapshot);
         self.instantiated.remove(id);
-        Ok(())
+        self.notify(|o, cycles| o.on_vm_suspend(cycles, *id, bytes));
+        Ok(cycles)
     }
 
     fn boot_vm(
@@ -647,42 +1553,86 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         offset: u64,
         length: u64,
         args: &[Bytes],
-    ) -> Result<VmId, Error> {
+        parent: Option<VmId>,
+    ) -> Result<VmId, SchedulerError> {
         // Newly booted VM will be instantiated by default
-        while self.instantiated.len() >= MAX_INSTANTIATED_VMS {
-            // instantiated is a BTreeMap, first_entry will maintain key order
-            let id = *self.instantiated.first_entry().unwrap().key();
-            self.suspend_vm(&id)?;
+        let mut suspend_cycles: Cycle = 0;
+        if self.instantiated.len() >= self.max_instantiated_vms {
+            let count = self.instantiated.len() + 1 - self.max_instantiated_vms;
+            for id in self.pick_vms_to_suspend(&[], count)? {
+                suspend_cycles = suspend_cycles
+                    .checked_add(self.suspend_vm(&id)?)
+                    .ok_or(Error::CyclesOverflow)
+                    .map_err(vm_error(self.next_vm_id))?;
+                self.swap_count += 1;
+            }
         }
 
         let id = self.next_vm_id;
         self.next_vm_id += 1;
-        let (context, mut machine) = self.create_dummy_vm(&id)?;
-        {
-            let mut sc = context.snapshot2_context().lock().expect("lock");
-            let (program, _) = sc.data_source().load_data(data_piece_id, offset, length)?;
-            let metadata = parse_elf::<u64>(&program, machine.machine.version())?;
-            let bytes = machine.load_program_with_metadata(&program, &metadata, args)?;
-            sc.mark_program(&mut machine.machine, &metadata, data_piece_id, offset)?;
-            machine
-                .machine
-                .add_cycles_no_checking(transferred_byte_cycles(bytes))?;
+        if let Some(parent) = parent {
+            self.spawn_tree.insert(id, parent);
         }
+        let (context, mut machine, load_cycles) =
+            self.load_vm(id, data_piece_id, offset, length, args)?;
+        // Newly booted VM also pays for suspending VMs to make room for itself
+        machine
+            .machine
+            .add_cycles_no_checking(suspend_cycles)
+            .map_err(vm_error(id))?;
+        let mut stats = VmStats::new(id, data_piece_id.clone());
+        stats.syscall_cycles = load_cycles;
+        stats.swap_cycles = suspend_cycles;
         self.instantiated.insert(id, (context, machine));
-        self.states.insert(id, VmState::Runnable);
+        self.vm_stats.insert(id, stats);
+        self.notify(|o, cycles| o.on_vm_boot(cycles, id, data_piece_id));
+        self.set_state(id, VmState::Runnable);
+        // Newly booted VM is considered to have just run for eviction purposes
+        self.last_run_times.insert(id, self.run_count);
 
         Ok(id)
     }
 
+    // Create a new VM instance running the program loaded from the data
+    // piece, the VM is charged for loading the program. Returns the VM
+    // together with the charged cycles.
+    fn load_vm(
+        &self,
+        id: VmId,
+        data_piece_id: &DataPieceId,
+        offset: u64,
+        length: u64,
+        args: &[Bytes],
+    ) -> Result<(MachineContext<DL>, AsmMachine, Cycle), SchedulerError> {
+        let (context, mut machine) = self.create_dummy_vm(&id)?;
+        let load_cycles = {
+            let mut sc = context.snapshot2_context().lock().expect("lock");
+            let (program, _) = sc
+                .data_source()
+                .load_data(data_piece_id, offset, length)
+                .map_err(vm_error(id))?;
+            let metadata =
+                parse_elf::<u64>(&program, machine.machine.version()).map_err(vm_error(id))?;
+            let bytes = machine
+                .load_program_with_metadata(&program, &metadata, args)
+                .map_err(vm_error(id))?;
+            sc.mark_program(&mut machine.machine, &metadata, data_piece_id, offset)
+                .map_err(snapshot_error(id))?;
+            transferred_byte_cycles(bytes)
+        };
+        machine
+            .machine
+            .add_cycles_no_checking(load_cycles)
+            .map_err(vm_error(id))?;
+        Ok((context, machine, load_cycles))
+    }
+
     // Create a new VM instance with syscalls attached
-    fn create_dummy_vm(&self, id: &VmId) -> Result<(MachineContext<DL>, AsmMachine), Error> {
-        // The code here looks slightly weird, since I don't want to copy over all syscall
-        // impls here again. Ideally, this scheduler package should be merged with ckb-script,
-        // or simply replace ckb-script. That way, the quirks here will be eliminated.
-        let version = self
-            .verifier
-            .select_version(&self.tx_data.script_group.script)
-            .map_err(|e| Error::Unexpected(format!("Select version error: {:?}", e)))?;
+    fn create_dummy_vm(
+        &self,
+        id: &VmId,
+    ) -> Result<(MachineContext<DL>, AsmMachine), SchedulerError> {
+        let version = self.version;
         log::debug!("Creating VM {} using version {:?}", id, version);
         let core_machine = AsmCoreMachine::new(
             version.vm_isa(),
@@ -690,29 +1640,20 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
             // We will update max_cycles for each machine when it gets a chance to run
             u64::max_value(),
         );
-        let machine_context =
-            MachineContext::new(*id, self.message_box.clone(), self.tx_data.clone());
-        let machine_builder = DefaultMachineBuilder::new(core_machine)
-            .instruction_cycle_func(Box::new(estimate_cycles))
-            // ckb-vm iterates syscalls in insertion order, by putting
-            // MachineContext at the first place, we can override other
-            // syscalls with implementations from MachineContext. For example,
-            // we can override load_cell_data syscall with a new implementation.
-            .syscall(Box::new(machine_context.clone()));
-        let syscalls = self.verifier.generate_syscalls(
-            // Skip current spawn implementation
-            if version == ScriptVersion::V2 {
-                ScriptVersion::V1
-            } else {
-                version
-            },
-            &self.tx_data.script_group,
-            Default::default(),
+        let machine_context = MachineContext::new(
+            *id,
+            // Root VM is considered to be its own parent
+            self.spawn_tree.parent(*id).unwrap_or(*id),
+            version,
+            self.message_box.clone(),
+            self.debug_output.clone(),
+            self.tx_data.clone(),
+            self.cost_schedule.clone(),
         );
-        let machine_builder = syscalls
-            .into_iter()
-            .fold(machine_builder, |builder, syscall| builder.syscall(syscall));
-        let default_machine = machine_builder.build();
+        let default_machine = DefaultMachineBuilder::new(core_machine)
+            .instruction_cycle_func(Box::new(estimate_cycles))
+            .syscall(Box::new(machine_context.clone()))
+            .build();
         Ok((machine_context, AsmMachine::new(default_machine)))
     }
 }
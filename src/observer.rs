@@ -0,0 +1,177 @@
+// Hooks for observing scheduler activities
+
+use crate::types::{DataPieceId, Message, PipeId, VmId, VmState};
+use ckb_types::core::Cycle;
+use std::sync::{Arc, Mutex};
+
+/// A SchedulerObserver gets notified of activities happening inside a
+/// scheduler, it can be used to build tracing, metrics and visualization
+/// tools. All methods do nothing by default, an observer only implements
+/// the events it is interested in.
+///
+/// In all methods, `cycles` contains the total cycles consumed by the script
+/// when the event happens. Since cycles charged to a VM are accumulated into
+/// total cycles only when the VM runs, events happening between 2 runs share
+/// the same `cycles` value.
+///
+/// Observers must not affect script execution in any way, they are not part
+/// of the suspended state, and must be set again after resuming.
+pub trait SchedulerObserver: Send {
+    /// A new VM is booted from the specified data piece
+    fn on_vm_boot(&mut self, _cycles: Cycle, _vm_id: VmId, _data_piece_id: &DataPieceId) {}
+
+    /// A VM runs for `consumed_cycles` cycles, `cycles` contains the total
+    /// cycles before the VM starts running.
+    fn on_vm_run(&mut self, _cycles: Cycle, _vm_id: VmId, _consumed_cycles: Cycle) {}
+
+    /// A VM enters a new state
+    fn on_vm_state_change(&mut self, _cycles: Cycle, _vm_id: VmId, _state: &VmState) {}
+
+    /// A message posted by a VM via syscalls is about to be processed
+    fn on_message(&mut self, _cycles: Cycle, _message: &Message) {}
+
+    /// `parent` spawns `child`, passing `pipes` to it
+    fn on_spawn(&mut self, _cycles: Cycle, _parent: VmId, _child: VmId, _pipes: &[PipeId]) {}
+
+    /// A VM blocks waiting on any of `targets` to terminate
+    fn on_join_wait(&mut self, _cycles: Cycle, _vm_id: VmId, _targets: &[VmId]) {}
+
+    /// A join / join_any syscall finishes with `target`'s exit code
+    fn on_join_wakeup(&mut self, _cycles: Cycle, _vm_id: VmId, _target: VmId, _exit_code: i8) {}
+
+    /// A VM creates a pair of pipes
+    fn on_pipe_create(
+        &mut self,
+        _cycles: Cycle,
+        _vm_id: VmId,
+        _read_pipe: PipeId,
+        _write_pipe: PipeId,
+    ) {
+    }
+
+    /// A pipe owned by a VM is closed, either via close syscall, or when
+    /// the VM terminates. All pipes still open are closed when the root VM
+    /// terminates, including those owned by other VMs.
+    fn on_pipe_close(&mut self, _cycles: Cycle, _vm_id: VmId, _pipe: PipeId) {}
+
+    /// `bytes` of data are transferred from `write_pipe` owned by `write_vm`,
+    /// to the other end of the pipe owned by `read_vm`.
+    fn on_pipe_transfer(
+        &mut self,
+        _cycles: Cycle,
+        _write_vm: VmId,
+        _write_pipe: PipeId,
+        _read_vm: VmId,
+        _bytes: u64,
+    ) {
+    }
+
+    /// A VM is suspended into a snapshot, `bytes` is the swapped size
+    fn on_vm_suspend(&mut self, _cycles: Cycle, _vm_id: VmId, _bytes: u64) {}
+
+    /// A VM is resumed from a snapshot, `bytes` is the swapped size
+    fn on_vm_resume(&mut self, _cycles: Cycle, _vm_id: VmId, _bytes: u64) {}
+
+    /// A VM terminates, either by exiting, or by getting killed. `vm_cycles`
+    /// contains cycles consumed by the VM itself. VMs joining the terminated
+    /// VM are woken up after this event.
+    ///
+    /// VMs still alive when the root VM terminates are purged without
+    /// exiting, no event is emitted for them, the root VM's event is always
+    /// the last one.
+    fn on_vm_terminate(&mut self, _cycles: Cycle, _vm_id: VmId, _exit_code: i8, _vm_cycles: Cycle) {
+    }
+}
+
+/// Allows the caller to keep a handle to the observer, so as to inspect it
+/// after the scheduler finishes running.
+impl<O: SchedulerObserver + ?Sized> SchedulerObserver for Arc<Mutex<O>> {
+    fn on_vm_boot(&mut self, cycles: Cycle, vm_id: VmId, data_piece_id: &DataPieceId) {
+        self.lock()
+            .expect("lock")
+            .on_vm_boot(cycles, vm_id, data_piece_id)
+    }
+
+    fn on_vm_run(&mut self, cycles: Cycle, vm_id: VmId, consumed_cycles: Cycle) {
+        self.lock()
+            .expect("lock")
+            .on_vm_run(cycles, vm_id, consumed_cycles)
+    }
+
+    fn on_vm_state_change(&mut self, cycles: Cycle, vm_id: VmId, state: &VmState) {
+        self.lock()
+            .expect("lock")
+            .on_vm_state_change(cycles, vm_id, state)
+    }
+
+    fn on_message(&mut self, cycles: Cycle, message: &Message) {
+        self.lock().expect("lock").on_message(cycles, message)
+    }
+
+    fn on_spawn(&mut self, cycles: Cycle, parent: VmId, child: VmId, pipes: &[PipeId]) {
+        self.lock()
+            .expect("lock")
+            .on_spawn(cycles, parent, child, pipes)
+    }
+
+    fn on_join_wait(&mut self, cycles: Cycle, vm_id: VmId, targets: &[VmId]) {
+        self.lock()
+            .expect("lock")
+            .on_join_wait(cycles, vm_id, targets)
+    }
+
+    fn on_join_wakeup(&mut self, cycles: Cycle, vm_id: VmId, target: VmId, exit_code: i8) {
+        self.lock()
+            .expect("lock")
+            .on_join_wakeup(cycles, vm_id, target, exit_code)
+    }
+
+    fn on_pipe_create(
+        &mut self,
+        cycles: Cycle,
+        vm_id: VmId,
+        read_pipe: PipeId,
+        write_pipe: PipeId,
+    ) {
+        self.lock()
+            .expect("lock")
+            .on_pipe_create(cycles, vm_id, read_pipe, write_pipe)
+    }
+
+    fn on_pipe_close(&mut self, cycles: Cycle, vm_id: VmId, pipe: PipeId) {
+        self.lock()
+            .expect("lock")
+            .on_pipe_close(cycles, vm_id, pipe)
+    }
+
+    fn on_pipe_transfer(
+        &mut self,
+        cycles: Cycle,
+        write_vm: VmId,
+        write_pipe: PipeId,
+        read_vm: VmId,
+        bytes: u64,
+    ) {
+        self.lock()
+            .expect("lock")
+            .on_pipe_transfer(cycles, write_vm, write_pipe, read_vm, bytes)
+    }
+
+    fn on_vm_suspend(&mut self, cycles: Cycle, vm_id: VmId, bytes: u64) {
+        self.lock()
+            .expect("lock")
+            .on_vm_suspend(cycles, vm_id, bytes)
+    }
+
+    fn on_vm_resume(&mut self, cycles: Cycle, vm_id: VmId, bytes: u64) {
+        self.lock()
+            .expect("lock")
+            .on_vm_resume(cycles, vm_id, bytes)
+    }
+
+    fn on_vm_terminate(&mut self, cycles: Cycle, vm_id: VmId, exit_code: i8, vm_cycles: Cycle) {
+        self.lock()
+            .expect("lock")
+            .on_vm_terminate(cycles, vm_id, exit_code, vm_cycles)
+    }
+}
@@ -0,0 +1,97 @@
+// Policies deciding which runnable VM gets to run next
+
+use crate::types::VmId;
+use ckb_types::core::Cycle;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Information a scheduling policy can use to pick the next VM. All of
+/// it is persisted in FullSuspendedState, so a policy relying only on this
+/// context behaves the same way no matter how many times a scheduler is
+/// suspended and resumed.
+pub struct SchedulingContext<'a> {
+    /// The VM that ran most recently, None if no VM has run yet
+    pub last_run_vm: Option<VmId>,
+    /// Cycles consumed by each live VM so far
+    pub vm_cycles: &'a BTreeMap<VmId, Cycle>,
+}
+
+/// A SchedulingPolicy picks the next VM to run from all runnable VMs.
+///
+/// Policies must be deterministic: given the same runnable VMs and the same
+/// context, the same VM must be picked, otherwise consensus might be broken.
+pub trait SchedulingPolicy: Send + Sync {
+    /// Unique ID of the policy, it is recorded in FullSuspendedState so
+    /// the same policy can be used when resuming.
+    fn id(&self) -> u8;
+
+    /// Pick the next VM to run. `runnable` is never empty, and is sorted
+    /// by VM ID in ascending order. The returned VM must be one of them.
+    fn select(&self, runnable: &[VmId], context: &SchedulingContext) -> VmId;
+}
+
+pub const HIGHEST_ID_FIRST_POLICY_ID: u8 = 0;
+pub const ROUND_ROBIN_POLICY_ID: u8 = 1;
+pub const CYCLE_FAIR_POLICY_ID: u8 = 2;
+
+/// Always runs the runnable VM with the largest ID, this is the default
+/// policy, it minimizes swapping when VMs spawn children.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HighestIdFirst;
+
+impl SchedulingPolicy for HighestIdFirst {
+    fn id(&self) -> u8 {
+        HIGHEST_ID_FIRST_POLICY_ID
+    }
+
+    fn select(&self, runnable: &[VmId], _context: &SchedulingContext) -> VmId {
+        *runnable.last().unwrap()
+    }
+}
+
+/// Runs the runnable VM with the smallest ID larger than the last run VM,
+/// wrapping around to the smallest ID.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RoundRobin;
+
+impl SchedulingPolicy for RoundRobin {
+    fn id(&self) -> u8 {
+        ROUND_ROBIN_POLICY_ID
+    }
+
+    fn select(&self, runnable: &[VmId], context: &SchedulingContext) -> VmId {
+        context
+            .last_run_vm
+            .and_then(|last| runnable.iter().find(|id| **id > last))
+            .copied()
+            .unwrap_or(runnable[0])
+    }
+}
+
+/// Runs the runnable VM that has consumed the least cycles, ties are
+/// broken by picking the smallest ID.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CycleFair;
+
+impl SchedulingPolicy for CycleFair {
+    fn id(&self) -> u8 {
+        CYCLE_FAIR_POLICY_ID
+    }
+
+    fn select(&self, runnable: &[VmId], context: &SchedulingContext) -> VmId {
+        *runnable
+            .iter()
+            .min_by_key(|id| (context.vm_cycles.get(*id).copied().unwrap_or(0), **id))
+            .unwrap()
+    }
+}
+
+/// Looks up a builtin policy by its ID
+pub fn builtin_scheduling_policy(id: u8) -> Option<Arc<dyn SchedulingPolicy>> {
+    match id {
+        HIGHEST_ID_FIRST_POLICY_ID => Some(Arc::new(HighestIdFirst)),
+        ROUND_ROBIN_POLICY_ID => Some(Arc::new(RoundRobin)),
+        CYCLE_FAIR_POLICY_ID => Some(Arc::new(CycleFair)),
+        _ => None,
+    }
+}
@@ -0,0 +1,838 @@
+// Binary encoding for suspended states
+//
+// All integers are encoded in little endian. An encoded state has the
+// following layout:
+//
+// | version(u32) | blake2b hash of body([u8; 32]) | body |
+//
+// Variable length lists in body are prefixed with a u32 item count, enums
+// are prefixed with a u8 tag.
+//
+// Molecule is not used here on purpose: the state is only persisted and
+// loaded by the same node, it is never hashed into chain data or exchanged
+// between nodes, so there is no need for a canonical cross-language schema.
+// Most of the encoded types, such as Snapshot2 and VmState, are Rust types
+// defined outside of any schema, a molecule schema would still require
+// converting each of them by hand. Instead, the version number rejects states
+// written by a different format, and the blake2b hash(from ckb-hash, which is
+// already a dependency) catches corrupted data, before the body is decoded.
+// Decoded states are then validated against invariants the scheduler relies
+// on, see `validate`.
+
+use crate::{
+    error::SchedulerError,
+    types::{
+        DataPieceId, DebugMessage, DebugSink, ExecArgs, FullSuspendedState, JoinAnyArgs, JoinArgs,
+        Limits, Message, PipeArgs, PipeId, PipeIoArgs, SelectArgs, SpawnArgs, VmId, VmState,
+        VmStats,
+    },
+    ROOT_VM_ID,
+};
+use ckb_hash::blake2b_256;
+use ckb_vm::{bytes::Bytes, snapshot2::Snapshot2, RISCV_GENERAL_REGISTER_NUMBER};
+use std::collections::BTreeSet;
+
+/// Current version of the encoding format, this must be bumped whenever
+/// the format changes after a release.
+pub const SUSPENDED_STATE_VERSION: u32 = 1;
+
+const HASH_LENGTH: usize = 32;
+const HEADER_LENGTH: usize = 4 + HASH_LENGTH;
+
+/// Encoded size of a single VM in a suspended state, broken down into
+/// different parts of the snapshot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VmEncodedSize {
+    pub vm_id: VmId,
+    /// General purpose registers & PC
+    pub registers: u64,
+    /// Dirty pages, which contain the actual page content
+    pub dirty_pages: u64,
+    /// Pages loaded from transaction data, only the location of the data is
+    /// kept, not the page content
+    pub source_pages: u64,
+    /// The rest: VM ID, VM state, snapshot version, cycles and max cycles
+    pub other: u64,
+}
+
+impl VmEncodedSize {
+    pub fn total(&self) -> u64 {
+        self.registers + self.dirty_pages + self.source_pages + self.other
+    }
+}
+
+impl FullSuspendedState {
+    /// Exact length in bytes of the encoded state, as returned by `encode`.
+    /// The size is measured by encoding the body, so it costs about the same
+    /// as `encode`.
+    pub fn size(&self) -> u64 {
+        HEADER_LENGTH as u64 + self.encode_body().0.len() as u64
+    }
+
+    /// Encoded size of each VM in the state, measured by encoding each part
+    /// of the VM separately.
+    pub fn vm_sizes(&self) -> Vec<VmEncodedSize> {
+        self.vms
+            .iter()
+            .map(|(vm_id, state, snapshot)| {
+                let registers = encoded_length(|w| w.registers(snapshot));
+                let dirty_pages = encoded_length(|w| w.dirty_pages(snapshot));
+                let source_pages = encoded_length(|w| w.source_pages(snapshot));
+                let total = encoded_length(|w| w.vm(*vm_id, state, snapshot));
+                VmEncodedSize {
+                    vm_id: *vm_id,
+                    registers,
+                    dirty_pages,
+                    source_pages,
+                    other: total - registers - dirty_pages - source_pages,
+                }
+            })
+            .collect()
+    }
+
+    /// Encode the state into a versioned binary format protected by a hash
+    pub fn encode(&self) -> Bytes {
+        let body = self.encode_body();
+        let mut data = Vec::with_capacity(HEADER_LENGTH + body.0.len());
+        data.extend_from_slice(&SUSPENDED_STATE_VERSION.to_le_bytes());
+        data.extend_from_slice(&blake2b_256(&body.0));
+        data.extend_from_slice(&body.0);
+        data.into()
+    }
+
+    fn encode_body(&self) -> Writer {
+        let mut body = Writer::default();
+        body.u64(self.total_cycles);
+        body.u64(self.next_vm_id);
+        body.u64(self.next_pipe_slot);
+        body.length(self.vms.len());
+        for (id, state, snapshot) in &self.vms {
+            body.vm(*id, state, snapshot);
+        }
+        body.length(self.pipes.len());
+        for (pipe, id) in &self.pipes {
+            body.u64(pipe.0);
+            body.u64(*id);
+        }
+        body.length(self.terminated_vms.len());
+        for (id, exit_code) in &self.terminated_vms {
+            body.u64(*id);
+            body.u8(*exit_code as u8);
+        }
+        body.length(self.instantiated_vms.len());
+        for id in &self.instantiated_vms {
+            body.u64(*id);
+        }
+        body.length(self.spawn_depths.len());
+        for (id, depth) in &self.spawn_depths {
+            body.u64(*id);
+            body.u64(*depth);
+        }
+        body.u64(self.limits.max_live_vms);
+        body.u64(self.limits.max_spawns);
+        body.u64(self.limits.max_pipes_per_vm);
+        body.u64(self.limits.max_pipes);
+        body.u64(self.limits.max_spawn_depth);
+        body.u8(self.script_version);
+        body.u8(self.scheduling_policy);
+        body.optional_u64(self.last_run_vm);
+        body.optional_u64(self.interrupted_vm);
+        body.length(self.vm_cycles.len());
+        for (id, cycles) in &self.vm_cycles {
+            body.u64(*id);
+            body.u64(*cycles);
+        }
+        body.u64(self.swap_count);
+        body.optional_u64(self.time_slice);
+        body.u64(self.slice_consumed_cycles);
+        body.u64(self.max_instantiated_vms);
+        body.u8(self.eviction_strategy);
+        body.u64(self.run_count);
+        body.length(self.last_run_times.len());
+        for (id, time) in &self.last_run_times {
+            body.u64(*id);
+            body.u64(*time);
+        }
+        body.length(self.parents.len());
+        for (id, parent) in &self.parents {
+            body.u64(*id);
+            body.u64(*parent);
+        }
+        body.length(self.reaped_vms.len());
+        for id in &self.reaped_vms {
+            body.u64(*id);
+        }
+        body.length(self.vm_stats.len());
+        for stats in &self.vm_stats {
+            body.vm_stats(stats);
+        }
+        body.u8(match self.debug_sink {
+            DebugSink::Log => 0,
+            DebugSink::Capture => 1,
+        });
+        body.length(self.debug_output.len());
+        for debug in &self.debug_output {
+            body.u64(debug.vm_id);
+            body.u64(debug.cycles);
+            body.bytes(debug.message.as_bytes());
+        }
+        body
+    }
+
+    /// Decode a state previously encoded via `encode`, the version and
+    /// hash are validated before decoding the body.
+    pub fn decode(data: &[u8]) -> Result<Self, SchedulerError> {
+        if data.len() < HEADER_LENGTH {
+            return Err(decode_error("data is shorter than header"));
+        }
+        let version = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        if version != SUSPENDED_STATE_VERSION {
+            return Err(decode_error(&format!(
+                "unsupported version {}, expected {}",
+                version, SUSPENDED_STATE_VERSION
+            )));
+        }
+        let body = &data[HEADER_LENGTH..];
+        if blake2b_256(body)[..] != data[4..HEADER_LENGTH] {
+            return Err(decode_error("hash mismatch"));
+        }
+
+        let mut reader = Reader(body);
+        let total_cycles = reader.u64()?;
+        let next_vm_id = reader.u64()?;
+        let next_pipe_slot = reader.u64()?;
+        let vms = reader.list(|r| Ok((r.u64()?, r.vm_state()?, r.snapshot()?)))?;
+        let pipes = reader.list(|r| Ok((PipeId(r.u64()?), r.u64()?)))?;
+        let terminated_vms = reader.list(|r| Ok((r.u64()?, r.u8()? as i8)))?;
+        let instantiated_vms = reader.list(|r| r.u64())?;
+        let spawn_depths = reader.list(|r| Ok((r.u64()?, r.u64()?)))?;
+        let limits = Limits {
+            max_live_vms: reader.u64()?,
+            max_spawns: reader.u64()?,
+            max_pipes_per_vm: reader.u64()?,
+            max_pipes: reader.u64()?,
+            max_spawn_depth: reader.u64()?,
+        };
+        let script_version = reader.u8()?;
+        let scheduling_policy = reader.u8()?;
+        let last_run_vm = reader.optional_u64()?;
+        let interrupted_vm = reader.optional_u64()?;
+        let vm_cycles = reader.list(|r| Ok((r.u64()?, r.u64()?)))?;
+        let swap_count = reader.u64()?;
+        let time_slice = reader.optional_u64()?;
+        let slice_consumed_cycles = reader.u64()?;
+        let max_instantiated_vms = reader.u64()?;
+        let eviction_strategy = reader.u8()?;
+        let run_count = reader.u64()?;
+        let last_run_times = reader.list(|r| Ok((r.u64()?, r.u64()?)))?;
+        let parents = reader.list(|r| Ok((r.u64()?, r.u64()?)))?;
+        let reaped_vms = reader.list(|r| r.u64())?;
+        let vm_stats = reader.list(|r| r.vm_stats())?;
+        let debug_sink = match reader.u8()? {
+            0 => DebugSink::Log,
+            1 => DebugSink::Capture,
+            tag => return Err(decode_error(&format!("invalid DebugSink tag {}", tag))),
+        };
+        let debug_output = reader.list(|r| {
+            Ok(DebugMessage {
+                vm_id: r.u64()?,
+                cycles: r.u64()?,
+                message: String::from_utf8(r.bytes()?)
+                    .map_err(|_| decode_error("invalid UTF-8 debug message"))?,
+            })
+        })?;
+        if !reader.0.is_empty() {
+            return Err(decode_error("trailing data"));
+        }
+
+        let state = Self {
+            total_cycles,
+            next_vm_id,
+            next_pipe_slot,
+            vms,
+            pipes,
+            terminated_vms,
+            instantiated_vms,
+            spawn_depths,
+            limits,
+            script_version,
+            scheduling_policy,
+            last_run_vm,
+            interrupted_vm,
+            vm_cycles,
+            swap_count,
+            time_slice,
+            slice_consumed_cycles,
+            max_instantiated_vms,
+            eviction_strategy,
+            run_count,
+            last_run_times,
+            parents,
+            reaped_vms,
+            vm_stats,
+            debug_sink,
+            debug_output,
+        };
+        state.validate()?;
+        Ok(state)
+    }
+
+    // Invariants held by all states created via Scheduler::suspend. A state
+    // violating them is rejected here, instead of making the scheduler panic
+    // after resuming.
+    fn validate(&self) -> Result<(), SchedulerError> {
+        let vm_ids: BTreeSet<VmId> = self.vms.iter().map(|(id, _, _)| *id).collect();
+        if vm_ids.len() != self.vms.len() {
+            return Err(decode_error("duplicate VMs"));
+        }
+        if let Some(id) = vm_ids.iter().find(|id| **id >= self.next_vm_id) {
+            return Err(decode_error(&format!("VM {} is not spawned yet", id)));
+        }
+        if let Some((pipe, id)) = self.pipes.iter().find(|(_, id)| !vm_ids.contains(id)) {
+            return Err(decode_error(&format!(
+                "pipe {} is owned by VM {} which is not alive",
+                pipe.0, id
+            )));
+        }
+        if let Some(id) = self.instantiated_vms.iter().find(|id| !vm_ids.contains(id)) {
+            return Err(decode_error(&format!(
+                "instantiated VM {} is not alive",
+                id
+            )));
+        }
+        if let Some(id) = self.interrupted_vm.filter(|id| !vm_ids.contains(id)) {
+            return Err(decode_error(&format!("interrupted VM {} is not alive", id)));
+        }
+        if !vm_ids.contains(&ROOT_VM_ID) {
+            return Err(decode_error("root VM is not alive"));
+        }
+        // Statistics are kept for every VM ever booted
+        let stats_ids: BTreeSet<VmId> = self.vm_stats.iter().map(|stats| stats.vm_id).collect();
+        if let Some(id) = vm_ids
+            .iter()
+            .chain(self.terminated_vms.iter().map(|(id, _)| id))
+            .find(|id| !stats_ids.contains(id))
+        {
+            return Err(decode_error(&format!("VM {} has no statistics", id)));
+        }
+        Ok(())
+    }
+}
+
+// Length of the data written by `f`
+fn encoded_length<F: FnOnce(&mut Writer)>(f: F) -> u64 {
+    let mut writer = Writer::default();
+    f(&mut writer);
+    writer.0.len() as u64
+}
+
+pub(crate) fn decode_error(message: &str) -> SchedulerError {
+    SchedulerError::Decode(message.to_string())
+}
+
+// Writer & Reader are shared with other binary formats in this crate, such
+// as execution traces.
+#[derive(Default)]
+pub(crate) struct Writer(pub(crate) Vec<u8>);
+
+impl Writer {
+    pub(crate) fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    pub(crate) fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn optional_u64(&mut self, v: Option<u64>) {
+        match v {
+            Some(v) => {
+                self.u8(1);
+                self.u64(v);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    pub(crate) fn length(&mut self, l: usize) {
+        self.u32(l as u32);
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.length(b.len());
+        self.0.extend_from_slice(b);
+    }
+
+    fn vm_state(&mut self, state: &VmState) {
+        match state {
+            VmState::Runnable => self.u8(0),
+            VmState::Terminated => self.u8(1),
+            VmState::Join {
+                target_vm_id,
+                exit_code_addr,
+            } => {
+                self.u8(2);
+                self.u64(*target_vm_id);
+                self.u64(*exit_code_addr);
+            }
+            VmState::WaitForWrite {
+                pipe,
+                consumed,
+                length,
+                buffer_addr,
+                length_addr,
+            } => {
+                self.u8(3);
+                self.u64(pipe.0);
+                self.u64(*consumed);
+                self.u64(*length);
+                self.u64(*buffer_addr);
+                self.u64(*length_addr);
+            }
+            VmState::WaitForRead {
+                pipe,
+                length,
+                buffer_addr,
+                length_addr,
+            } => {
+                self.u8(4);
+                self.u64(pipe.0);
+                self.u64(*length);
+                self.u64(*buffer_addr);
+                self.u64(*length_addr);
+            }
+            VmState::WaitForAny {
+                pipes,
+                ready_pipe_addr,
+            } => {
+                self.u8(5);
+                self.length(pipes.len());
+                for pipe in pipes {
+                    self.u64(pipe.0);
+                }
+                self.u64(*ready_pipe_addr);
+            }
+            VmState::JoinAny {
+                target_vm_ids,
+                instance_id_addr,
+                exit_code_addr,
+            } => {
+                self.u8(6);
+                self.length(target_vm_ids.len());
+                for id in target_vm_ids {
+                    self.u64(*id);
+                }
+                self.u64(*instance_id_addr);
+                self.u64(*exit_code_addr);
+            }
+        }
+    }
+
+    fn data_piece_id(&mut self, id: &DataPieceId) {
+        match id {
+            DataPieceId::Program => self.u8(0),
+            DataPieceId::Input(i) => {
+                self.u8(1);
+                self.u32(*i);
+            }
+            DataPieceId::Output(i) => {
+                self.u8(2);
+                self.u32(*i);
+            }
+            DataPieceId::CellDep(i) => {
+                self.u8(3);
+                self.u32(*i);
+            }
+            DataPieceId::GroupInput(i) => {
+                self.u8(4);
+                self.u32(*i);
+            }
+            DataPieceId::GroupOutput(i) => {
+                self.u8(5);
+                self.u32(*i);
+            }
+            DataPieceId::Witness(i) => {
+                self.u8(6);
+                self.u32(*i);
+            }
+            DataPieceId::GroupInputWitness(i) => {
+                self.u8(7);
+                self.u32(*i);
+            }
+            DataPieceId::GroupOutputWitness(i) => {
+                self.u8(8);
+                self.u32(*i);
+            }
+            DataPieceId::Script => self.u8(9),
+        }
+    }
+
+    fn vm_stats(&mut self, stats: &VmStats) {
+        self.u64(stats.vm_id);
+        self.data_piece_id(&stats.program);
+        self.u64(stats.execution_cycles);
+        self.u64(stats.syscall_cycles);
+        self.u64(stats.swap_cycles);
+        self.u64(stats.bytes_read);
+        self.u64(stats.bytes_written);
+        self.u64(stats.unpaid_cycles);
+        match stats.exit_code {
+            Some(exit_code) => {
+                self.u8(1);
+                self.u8(exit_code as u8);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    pub(crate) fn message(&mut self, message: &Message) {
+        match message {
+            Message::Spawn(vm_id, args) => {
+                self.u8(0);
+                self.u64(*vm_id);
+                self.data_piece_id(&args.data_piece_id);
+                self.u64(args.offset);
+                self.u64(args.length);
+                self.length(args.argv.len());
+                for arg in &args.argv {
+                    self.bytes(arg);
+                }
+                self.length(args.pipes.len());
+                for pipe in &args.pipes {
+                    self.u64(pipe.0);
+                }
+                self.u64(args.instance_id_addr);
+            }
+            Message::Join(vm_id, args) => {
+                self.u8(1);
+                self.u64(*vm_id);
+                self.u64(args.target_id);
+                self.u64(args.exit_code_addr);
+            }
+            Message::Pipe(vm_id, args) => {
+                self.u8(2);
+                self.u64(*vm_id);
+                self.u64(args.pipe1_addr);
+                self.u64(args.pipe2_addr);
+            }
+            Message::PipeRead(vm_id, args) => {
+                self.u8(3);
+                self.u64(*vm_id);
+                self.pipe_io_args(args);
+            }
+            Message::PipeWrite(vm_id, args) => {
+                self.u8(4);
+                self.u64(*vm_id);
+                self.pipe_io_args(args);
+            }
+            Message::Close(vm_id, pipe) => {
+                self.u8(5);
+                self.u64(*vm_id);
+                self.u64(pipe.0);
+            }
+            Message::Select(vm_id, args) => {
+                self.u8(6);
+                self.u64(*vm_id);
+                self.length(args.pipes.len());
+                for pipe in &args.pipes {
+                    self.u64(pipe.0);
+                }
+                self.u64(args.ready_pipe_addr);
+            }
+            Message::JoinAny(vm_id, args) => {
+                self.u8(7);
+                self.u64(*vm_id);
+                self.length(args.target_ids.len());
+                for id in &args.target_ids {
+                    self.u64(*id);
+                }
+                self.u64(args.instance_id_addr);
+                self.u64(args.exit_code_addr);
+            }
+            Message::Kill(vm_id, target_id) => {
+                self.u8(8);
+                self.u64(*vm_id);
+                self.u64(*target_id);
+            }
+            Message::Exec(vm_id, args) => {
+                self.u8(9);
+                self.u64(*vm_id);
+                self.data_piece_id(&args.data_piece_id);
+                self.u64(args.offset);
+                self.u64(args.length);
+                self.length(args.argv.len());
+                for arg in &args.argv {
+                    self.bytes(arg);
+                }
+            }
+        }
+    }
+
+    fn pipe_io_args(&mut self, args: &PipeIoArgs) {
+        self.u64(args.pipe.0);
+        self.u64(args.length);
+        self.u64(args.buffer_addr);
+        self.u64(args.length_addr);
+        self.u8(args.nonblocking as u8);
+    }
+
+    fn vm(&mut self, id: VmId, state: &VmState, snapshot: &Snapshot2<DataPieceId>) {
+        self.u64(id);
+        self.vm_state(state);
+        self.snapshot(snapshot);
+    }
+
+    fn snapshot(&mut self, snapshot: &Snapshot2<DataPieceId>) {
+        self.source_pages(snapshot);
+        self.dirty_pages(snapshot);
+        self.u32(snapshot.version);
+        self.registers(snapshot);
+        self.u64(snapshot.cycles);
+        self.u64(snapshot.max_cycles);
+    }
+
+    fn source_pages(&mut self, snapshot: &Snapshot2<DataPieceId>) {
+        self.length(snapshot.pages_from_source.len());
+        for (addr, flag, id, offset, length) in &snapshot.pages_from_source {
+            self.u64(*addr);
+            self.u8(*flag);
+            self.data_piece_id(id);
+            self.u64(*offset);
+            self.u64(*length);
+        }
+    }
+
+    fn dirty_pages(&mut self, snapshot: &Snapshot2<DataPieceId>) {
+        self.length(snapshot.dirty_pages.len());
+        for (addr, flag, content) in &snapshot.dirty_pages {
+            self.u64(*addr);
+            self.u8(*flag);
+            self.bytes(content);
+        }
+    }
+
+    // General purpose registers & PC
+    fn registers(&mut self, snapshot: &Snapshot2<DataPieceId>) {
+        for register in &snapshot.registers {
+            self.u64(*register);
+        }
+        self.u64(snapshot.pc);
+    }
+}
+
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SchedulerError> {
+        if self.0.len() < n {
+            return Err(decode_error("unexpected end of data"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SchedulerError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SchedulerError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SchedulerError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn optional_u64(&mut self) -> Result<Option<u64>, SchedulerError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.u64()?)),
+            tag => Err(decode_error(&format!("invalid Option tag {}", tag))),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, SchedulerError> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    pub(crate) fn list<T, F>(&mut self, mut f: F) -> Result<Vec<T>, SchedulerError>
+    where
+        F: FnMut(&mut Self) -> Result<T, SchedulerError>,
+    {
+        let length = self.u32()? as usize;
+        // Length comes from untrusted data, the capacity is capped to
+        // avoid allocating huge memory upfront.
+        let mut items = Vec::with_capacity(std::cmp::min(length, self.0.len()));
+        for _ in 0..length {
+            items.push(f(self)?);
+        }
+        Ok(items)
+    }
+
+    fn vm_state(&mut self) -> Result<VmState, SchedulerError> {
+        Ok(match self.u8()? {
+            0 => VmState::Runnable,
+            1 => VmState::Terminated,
+            2 => VmState::Join {
+                target_vm_id: self.u64()?,
+                exit_code_addr: self.u64()?,
+            },
+            3 => VmState::WaitForWrite {
+                pipe: PipeId(self.u64()?),
+                consumed: self.u64()?,
+                length: self.u64()?,
+                buffer_addr: self.u64()?,
+                length_addr: self.u64()?,
+            },
+            4 => VmState::WaitForRead {
+                pipe: PipeId(self.u64()?),
+                length: self.u64()?,
+                buffer_addr: self.u64()?,
+                length_addr: self.u64()?,
+            },
+            5 => VmState::WaitForAny {
+                pipes: self.list(|r| Ok(PipeId(r.u64()?)))?,
+                ready_pipe_addr: self.u64()?,
+            },
+            6 => VmState::JoinAny {
+                target_vm_ids: self.list(|r| r.u64())?,
+                instance_id_addr: self.u64()?,
+                exit_code_addr: self.u64()?,
+            },
+            tag => return Err(decode_error(&format!("invalid VmState tag {}", tag))),
+        })
+    }
+
+    fn data_piece_id(&mut self) -> Result<DataPieceId, SchedulerError> {
+        Ok(match self.u8()? {
+            0 => DataPieceId::Program,
+            1 => DataPieceId::Input(self.u32()?),
+            2 => DataPieceId::Output(self.u32()?),
+            3 => DataPieceId::CellDep(self.u32()?),
+            4 => DataPieceId::GroupInput(self.u32()?),
+            5 => DataPieceId::GroupOutput(self.u32()?),
+            6 => DataPieceId::Witness(self.u32()?),
+            7 => DataPieceId::GroupInputWitness(self.u32()?),
+            8 => DataPieceId::GroupOutputWitness(self.u32()?),
+            9 => DataPieceId::Script,
+            tag => return Err(decode_error(&format!("invalid DataPieceId tag {}", tag))),
+        })
+    }
+
+    fn vm_stats(&mut self) -> Result<VmStats, SchedulerError> {
+        Ok(VmStats {
+            vm_id: self.u64()?,
+            program: self.data_piece_id()?,
+            execution_cycles: self.u64()?,
+            syscall_cycles: self.u64()?,
+            swap_cycles: self.u64()?,
+            bytes_read: self.u64()?,
+            bytes_written: self.u64()?,
+            unpaid_cycles: self.u64()?,
+            exit_code: match self.u8()? {
+                0 => None,
+                1 => Some(self.u8()? as i8),
+                tag => return Err(decode_error(&format!("invalid Option tag {}", tag))),
+            },
+        })
+    }
+
+    pub(crate) fn message(&mut self) -> Result<Message, SchedulerError> {
+        Ok(match self.u8()? {
+            0 => Message::Spawn(
+                self.u64()?,
+                SpawnArgs {
+                    data_piece_id: self.data_piece_id()?,
+                    offset: self.u64()?,
+                    length: self.u64()?,
+                    argv: self.list(|r| Ok(r.bytes()?.into()))?,
+                    pipes: self.list(|r| Ok(PipeId(r.u64()?)))?,
+                    instance_id_addr: self.u64()?,
+                },
+            ),
+            1 => Message::Join(
+                self.u64()?,
+                JoinArgs {
+                    target_id: self.u64()?,
+                    exit_code_addr: self.u64()?,
+                },
+            ),
+            2 => Message::Pipe(
+                self.u64()?,
+                PipeArgs {
+                    pipe1_addr: self.u64()?,
+                    pipe2_addr: self.u64()?,
+                },
+            ),
+            3 => Message::PipeRead(self.u64()?, self.pipe_io_args()?),
+            4 => Message::PipeWrite(self.u64()?, self.pipe_io_args()?),
+            5 => Message::Close(self.u64()?, PipeId(self.u64()?)),
+            6 => Message::Select(
+                self.u64()?,
+                SelectArgs {
+                    pipes: self.list(|r| Ok(PipeId(r.u64()?)))?,
+                    ready_pipe_addr: self.u64()?,
+                },
+            ),
+            7 => Message::JoinAny(
+                self.u64()?,
+                JoinAnyArgs {
+                    target_ids: self.list(|r| r.u64())?,
+                    instance_id_addr: self.u64()?,
+                    exit_code_addr: self.u64()?,
+                },
+            ),
+            8 => Message::Kill(self.u64()?, self.u64()?),
+            9 => Message::Exec(
+                self.u64()?,
+                ExecArgs {
+                    data_piece_id: self.data_piece_id()?,
+                    offset: self.u64()?,
+                    length: self.u64()?,
+                    argv: self.list(|r| Ok(r.bytes()?.into()))?,
+                },
+            ),
+            tag => return Err(decode_error(&format!("invalid Message tag {}", tag))),
+        })
+    }
+
+    fn pipe_io_args(&mut self) -> Result<PipeIoArgs, SchedulerError> {
+        Ok(PipeIoArgs {
+            pipe: PipeId(self.u64()?),
+            length: self.u64()?,
+            buffer_addr: self.u64()?,
+            length_addr: self.u64()?,
+            nonblocking: match self.u8()? {
+                0 => false,
+                1 => true,
+                tag => return Err(decode_error(&format!("invalid bool tag {}", tag))),
+            },
+        })
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot2<DataPieceId>, SchedulerError> {
+        let pages_from_source =
+            self.list(|r| Ok((r.u64()?, r.u8()?, r.data_piece_id()?, r.u64()?, r.u64()?)))?;
+        let dirty_pages = self.list(|r| Ok((r.u64()?, r.u8()?, r.bytes()?)))?;
+        let version = self.u32()?;
+        let mut registers = [0u64; RISCV_GENERAL_REGISTER_NUMBER];
+        for register in registers.iter_mut() {
+            *register = self.u64()?;
+        }
+        Ok(Snapshot2 {
+            pages_from_source,
+            dirty_pages,
+            version,
+            registers,
+            pc: self.u64()?,
+            cycles: self.u64()?,
+            max_cycles: self.u64()?,
+        })
+    }
+}
@@ -1,19 +1,34 @@
 // Syscall implementation
 
 use crate::{
-    types::{JoinArgs, Message, PipeArgs, PipeId, PipeIoArgs, SpawnArgs, VmId},
+    cost_schedule::CostSchedule,
+    error::{is_index_out_of_bound, yield_error},
+    types::{
+        DebugMessage, ExecArgs, JoinAnyArgs, JoinArgs, Message, PipeArgs, PipeId, PipeIoArgs,
+        SelectArgs, SpawnArgs, VmId,
+    },
     DataPieceId, TxData,
 };
+use ckb_script::ScriptVersion;
 use ckb_traits::{CellDataProvider, ExtensionProvider, HeaderProvider};
+use ckb_types::{
+    core::{
+        cell::{CellMeta, CellMetaBuilder},
+        Capacity, HeaderView,
+    },
+    packed::{Byte32, CellInput, OutPoint},
+    prelude::*,
+};
 use ckb_vm::{
     bytes::Bytes,
     machine::SupportMachine,
     memory::{Memory, FLAG_EXECUTABLE, FLAG_FREEZED},
     registers::{A0, A1, A2, A3, A4, A5, A7},
-    snapshot2::{DataSource, Snapshot2Context},
+    snapshot2::{DataSource, Snapshot2, Snapshot2Context},
     syscalls::Syscalls,
-    Error, Register,
+    Error, Register, RISCV_GENERAL_REGISTER_NUMBER,
 };
+use std::mem::size_of;
 use std::sync::{Arc, Mutex};
 
 #[derive(Clone)]
@@ -21,20 +36,41 @@ pub struct MachineContext<
     DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + Clone + 'static,
 > {
     id: VmId,
+    parent_id: VmId,
+    version: ScriptVersion,
     base_cycles: Arc<Mutex<u64>>,
+    // Cycles charged by syscalls since the last time they are taken
+    syscall_cycles: Arc<Mutex<u64>>,
     message_box: Arc<Mutex<Vec<Message>>>,
+    debug_output: Arc<Mutex<Vec<DebugMessage>>>,
+    tx_data: TxData<DL>,
     snapshot2_context: Arc<Mutex<Snapshot2Context<DataPieceId, TxData<DL>>>>,
+    cost_schedule: Arc<dyn CostSchedule>,
 }
 
 impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + Clone + 'static>
     MachineContext<DL>
 {
-    pub fn new(id: VmId, message_box: Arc<Mutex<Vec<Message>>>, tx_data: TxData<DL>) -> Self {
+    pub fn new(
+        id: VmId,
+        parent_id: VmId,
+        version: ScriptVersion,
+        message_box: Arc<Mutex<Vec<Message>>>,
+        debug_output: Arc<Mutex<Vec<DebugMessage>>>,
+        tx_data: TxData<DL>,
+        cost_schedule: Arc<dyn CostSchedule>,
+    ) -> Self {
         Self {
             id,
+            parent_id,
+            version,
             base_cycles: Arc::new(Mutex::new(0)),
+            syscall_cycles: Arc::new(Mutex::new(0)),
             message_box,
+            debug_output,
+            tx_data: tx_data.clone(),
             snapshot2_context: Arc::new(Mutex::new(Snapshot2Context::new(tx_data))),
+            cost_schedule,
         }
     }
 
@@ -50,6 +86,23 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         *self.base_cycles.lock().expect("lock") = base_cycles;
     }
 
+    /// Returns cycles charged by syscalls since the last call, so the
+    /// scheduler can tell them apart from execution cycles.
+    pub fn take_syscall_cycles(&self) -> u64 {
+        std::mem::take(&mut *self.syscall_cycles.lock().expect("lock"))
+    }
+
+    fn charge_cycles<Mac: SupportMachine>(
+        &self,
+        machine: &mut Mac,
+        cycles: u64,
+    ) -> Result<(), Error> {
+        machine.add_cycles_no_checking(cycles)?;
+        let mut syscall_cycles = self.syscall_cycles.lock().expect("lock");
+        *syscall_cycles = syscall_cycles.saturating_add(cycles);
+        Ok(())
+    }
+
     // The different architecture here requires a re-implementation on current
     // cycles syscall.
     fn current_cycles<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
@@ -66,8 +119,15 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
     fn load_cell_data<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
         let index = machine.registers()[A3].to_u64();
         let source = machine.registers()[A4].to_u64();
+        self.load_data_piece(machine, DataPieceId::try_from((source, index)))
+    }
 
-        let data_piece_id = match DataPieceId::try_from((source, index)) {
+    fn load_data_piece<Mac: SupportMachine>(
+        &mut self,
+        machine: &mut Mac,
+        data_piece_id: Result<DataPieceId, String>,
+    ) -> Result<(), Error> {
+        let data_piece_id = match data_piece_id {
             Ok(id) => id,
             Err(e) => {
                 // Current implementation would throw an error immediately
@@ -90,7 +150,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         let (wrote_size, full_size) =
             match sc.store_bytes(machine, addr, &data_piece_id, offset, size) {
                 Ok(val) => val,
-                Err(Error::External(m)) if m == "INDEX_OUT_OF_BOUND" => {
+                Err(e) if is_index_out_of_bound(&e) => {
                     // This comes from TxData results in an out of bound error, to
                     // mimic current behavior, we would return INDEX_OUT_OF_BOUND error.
                     machine.set_register(A0, Mac::REG::from_u8(INDEX_OUT_OF_BOUND));
@@ -102,7 +162,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         machine
             .memory_mut()
             .store64(&size_addr, &Mac::REG::from_u64(full_size))?;
-        machine.add_cycles_no_checking(transferred_byte_cycles(wrote_size))?;
+        self.charge_cycles(machine, transferred_byte_cycles(wrote_size))?;
         machine.set_register(A0, Mac::REG::from_u8(SUCCESS));
         Ok(())
     }
@@ -148,7 +208,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
             .load_data(&data_piece_id, 0, u64::max_value())
         {
             Ok(val) => val,
-            Err(Error::External(m)) if m == "INDEX_OUT_OF_BOUND" => {
+            Err(e) if is_index_out_of_bound(&e) => {
                 // This comes from TxData results in an out of bound error, to
                 // mimic current behavior, we would return INDEX_OUT_OF_BOUND error.
                 machine.set_register(A0, Mac::REG::from_u8(INDEX_OUT_OF_BOUND));
@@ -177,12 +237,14 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         )?;
         sc.track_pages(machine, addr, memory_size, &data_piece_id, content_offset)?;
 
-        machine.add_cycles_no_checking(transferred_byte_cycles(memory_size))?;
+        self.charge_cycles(machine, transferred_byte_cycles(memory_size))?;
         machine.set_register(A0, Mac::REG::from_u8(SUCCESS));
         Ok(())
     }
 
-    // Reimplementing debug syscall for printing debug messages
+    // Reimplementing debug syscall for printing debug messages, messages
+    // are collected together with the cycles at which they are printed, the
+    // scheduler then decides where they go.
     fn debug<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
         let mut addr = machine.registers()[A0].to_u64();
         let mut buffer = Vec::new();
@@ -199,10 +261,18 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
             addr += 1;
         }
 
-        machine.add_cycles_no_checking(transferred_byte_cycles(buffer.len() as u64))?;
+        self.charge_cycles(machine, transferred_byte_cycles(buffer.len() as u64))?;
         let s = String::from_utf8(buffer)
             .map_err(|e| Error::External(format!("String from buffer {e:?}")))?;
-        log::debug!("Debug print from VM {}: {}", self.id, s);
+        let cycles = self
+            .base_cycles()
+            .checked_add(machine.cycles())
+            .ok_or(Error::CyclesOverflow)?;
+        self.debug_output.lock().expect("lock").push(DebugMessage {
+            vm_id: self.id,
+            cycles,
+            message: s,
+        });
 
         Ok(())
     }
@@ -212,7 +282,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         let index = machine.registers()[A0].to_u64();
         let source = machine.registers()[A1].to_u64();
 
-        let data_piece_id = match DataPieceId::try_from((source, index)) {
+        let data_piece_id = match DataPieceId::program(source, index) {
             Ok(id) => id,
             Err(e) => {
                 // Current implementation would throw an error immediately
@@ -232,18 +302,8 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
 
         let argv = {
             let argc = machine.registers()[A3].to_u64();
-            let mut argv_addr = machine.registers()[A4].to_u64();
-            let mut argv_vec = Vec::with_capacity(argc as usize);
-            for _ in 0..argc {
-                let target_addr = machine
-                    .memory_mut()
-                    .load64(&Mac::REG::from_u64(argv_addr))?
-                    .to_u64();
-                let cstr = load_c_string(machine, target_addr)?;
-                argv_vec.push(cstr);
-                argv_addr += 8;
-            }
-            argv_vec
+            let argv_addr = machine.registers()[A4].to_u64();
+            load_argv(machine, argc, argv_addr)?
         };
 
         let (instance_id_addr, pipes) = {
@@ -277,32 +337,20 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         };
 
         // We are fetching the actual cell here for some in-place validation
-        {
-            let sc = self.snapshot2_context().lock().expect("lock");
-            let (_, full_length) = match sc.data_source().load_data(&data_piece_id, 0, 0) {
-                Ok(val) => val,
-                Err(Error::External(m)) if m == "INDEX_OUT_OF_BOUND" => {
-                    // This comes from TxData results in an out of bound error, to
-                    // mimic current behavior, we would return INDEX_OUT_OF_BOUND error.
-                    machine.set_register(A0, Mac::REG::from_u8(INDEX_OUT_OF_BOUND));
-                    return Ok(());
-                }
-                Err(e) => return Err(e),
-            };
-            if offset >= full_length {
-                machine.set_register(A0, Mac::REG::from_u8(SLICE_OUT_OF_BOUND));
-                return Ok(());
-            }
-            if length > 0 {
-                let end = offset.checked_add(length).ok_or(Error::MemOutOfBound)?;
-                if end > full_length {
-                    machine.set_register(A0, Mac::REG::from_u8(SLICE_OUT_OF_BOUND));
-                    return Ok(());
-                }
-            }
+        let code = self.check_bounds(&data_piece_id, offset, length)?;
+        if code != SUCCESS {
+            machine.set_register(A0, Mac::REG::from_u8(code));
+            return Ok(());
         }
 
-        // TODO: charge spawn base cycles
+        let argv_bytes = argv.iter().fold(0, |acc, arg| acc + arg.len() as u64 + 1);
+        let cycles = self
+            .cost_schedule
+            .spawn_base_cycles()
+            .checked_add(self.cost_schedule.spawn_argv_cycles(argv_bytes))
+            .and_then(|c| c.checked_add(self.cost_schedule.spawn_pipe_cycles(pipes.len() as u64)))
+            .ok_or(Error::CyclesOverflow)?;
+        self.charge_cycles(machine, cycles)?;
         self.message_box.lock().expect("lock").push(Message::Spawn(
             self.id,
             SpawnArgs {
@@ -320,7 +368,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         // its control back to scheduler, so a runnable VM with a higher ID can
         // start its execution first. That's why we actually return a yield error
         // here.
-        Err(Error::External("YIELD".to_string()))
+        Err(yield_error())
     }
 
     // Join syscall blocks till the specified VM finishes execution, then
@@ -329,7 +377,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         let target_id = machine.registers()[A0].to_u64();
         let exit_code_addr = machine.registers()[A1].to_u64();
 
-        // TODO: charge cycles
+        self.charge_cycles(machine, self.cost_schedule.join_cycles())?;
         self.message_box.lock().expect("lock").push(Message::Join(
             self.id,
             JoinArgs {
@@ -339,22 +387,29 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
         ));
 
         // Like spawn, join yields control upon success
-        Err(Error::External("YIELD".to_string()))
+        Err(yield_error())
     }
 
     // Fetch current instance ID
     fn instance_id<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
-        // TODO: charge cycles
+        self.charge_cycles(machine, self.cost_schedule.instance_id_cycles())?;
         machine.set_register(A0, Mac::REG::from_u64(self.id));
         Ok(())
     }
 
+    // Fetch the ID of the VM spawning current VM, root VM gets its own ID
+    fn parent_id<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        self.charge_cycles(machine, self.cost_schedule.parent_id_cycles())?;
+        machine.set_register(A0, Mac::REG::from_u64(self.parent_id));
+        Ok(())
+    }
+
     // Create a pair of pipes
     fn pipe<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
         let pipe1_addr = machine.registers()[A0].to_u64();
         let pipe2_addr = pipe1_addr.wrapping_add(8);
 
-        // TODO: charge cycles
+        self.charge_cycles(machine, self.cost_schedule.pipe_cycles())?;
         self.message_box.lock().expect("lock").push(Message::Pipe(
             self.id,
             PipeArgs {
@@ -363,7 +418,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
             },
         ));
 
-        Err(Error::External("YIELD".to_string()))
+        Err(yield_error())
     }
 
     // Write to pipe
@@ -375,6 +430,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
             .load64(&Mac::REG::from_u64(length_addr))?
             .to_u64();
         let pipe = PipeId(machine.registers()[A2].to_u64());
+        let nonblocking = machine.registers()[A3].to_u64() & PIPE_NONBLOCK != 0;
 
         // We can only do basic checks here, when the message is actually processed,
         // more complete checks will be performed.
@@ -384,7 +440,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
             return Ok(());
         }
 
-        // TODO: charge cycles
+        self.charge_cycles(machine, self.cost_schedule.pipe_write_cycles())?;
         self.message_box
             .lock()
             .expect("lock")
@@ -395,11 +451,12 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                     length,
                     buffer_addr,
                     length_addr,
+                    nonblocking,
                 },
             ));
 
         // A0 will be updated once the write operation is fulfilled
-        Err(Error::External("YIELD".to_string()))
+        Err(yield_error())
     }
 
     // Read from pipe
@@ -411,6 +468,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
             .load64(&Mac::REG::from_u64(length_addr))?
             .to_u64();
         let pipe = PipeId(machine.registers()[A2].to_u64());
+        let nonblocking = machine.registers()[A3].to_u64() & PIPE_NONBLOCK != 0;
 
         // We can only do basic checks here, when the message is actually processed,
         // more complete checks will be performed.
@@ -420,7 +478,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
             return Ok(());
         }
 
-        // TODO: charge cycles
+        self.charge_cycles(machine, self.cost_schedule.pipe_read_cycles())?;
         self.message_box
             .lock()
             .expect("lock")
@@ -431,11 +489,468 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                     length,
                     buffer_addr,
                     length_addr,
+                    nonblocking,
                 },
             ));
 
         // A0 will be updated once the read operation is fulfilled
-        Err(Error::External("YIELD".to_string()))
+        Err(yield_error())
+    }
+
+    // Close one end of a pipe
+    fn close<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let pipe = PipeId(machine.registers()[A0].to_u64());
+
+        self.charge_cycles(machine, self.cost_schedule.close_cycles())?;
+        self.message_box
+            .lock()
+            .expect("lock")
+            .push(Message::Close(self.id, pipe));
+
+        // A0 will be updated once the pipe ownership is verified
+        Err(yield_error())
+    }
+
+    // Wait till any of the pipes is ready for reading or writing
+    fn select<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let mut pipes_addr = machine.registers()[A0].to_u64();
+        let count = machine.registers()[A1].to_u64();
+        let ready_pipe_addr = machine.registers()[A2].to_u64();
+
+        if count == 0 || count > MAX_SELECT_PIPES {
+            machine.set_register(A0, Mac::REG::from_u8(INVALID_PIPE));
+            return Ok(());
+        }
+        let mut pipes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let pipe = machine
+                .memory_mut()
+                .load64(&Mac::REG::from_u64(pipes_addr))?
+                .to_u64();
+            pipes.push(PipeId(pipe));
+            pipes_addr = pipes_addr.wrapping_add(8);
+        }
+
+        self.charge_cycles(machine, self.cost_schedule.select_cycles(count))?;
+        self.message_box.lock().expect("lock").push(Message::Select(
+            self.id,
+            SelectArgs {
+                pipes,
+                ready_pipe_addr,
+            },
+        ));
+
+        // A0 will be updated once any of the pipes is ready
+        Err(yield_error())
+    }
+
+    // Join any of the specified VMs, or any VM spawned by current VM when
+    // no VM is specified. Blocks till one of them finishes execution, then
+    // returns its ID and exit code.
+    fn join_any<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let mut ids_addr = machine.registers()[A0].to_u64();
+        let count = machine.registers()[A1].to_u64();
+        let instance_id_addr = machine.registers()[A2].to_u64();
+        let exit_code_addr = machine.registers()[A3].to_u64();
+
+        if count > MAX_JOIN_ANY_VMS {
+            machine.set_register(A0, Mac::REG::from_u8(JOIN_FAILURE));
+            return Ok(());
+        }
+        let mut target_ids = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let id = machine
+                .memory_mut()
+                .load64(&Mac::REG::from_u64(ids_addr))?
+                .to_u64();
+            target_ids.push(id);
+            ids_addr = ids_addr.wrapping_add(8);
+        }
+
+        self.charge_cycles(machine, self.cost_schedule.join_any_cycles(count))?;
+        self.message_box
+            .lock()
+            .expect("lock")
+            .push(Message::JoinAny(
+                self.id,
+                JoinAnyArgs {
+                    target_ids,
+                    instance_id_addr,
+                    exit_code_addr,
+                },
+            ));
+
+        // A0 will be updated once any of the VMs finishes execution
+        Err(yield_error())
+    }
+
+    // Check that the specified slice of a data piece exists, returns the
+    // error code to set when it does not.
+    fn check_bounds(
+        &self,
+        data_piece_id: &DataPieceId,
+        offset: u64,
+        length: u64,
+    ) -> Result<u8, Error> {
+        let sc = self.snapshot2_context().lock().expect("lock");
+        let (_, full_length) = match sc.data_source().load_data(data_piece_id, 0, 0) {
+            Ok(val) => val,
+            Err(e) if is_index_out_of_bound(&e) => {
+                // This comes from TxData results in an out of bound error, to
+                // mimic current behavior, we would return INDEX_OUT_OF_BOUND error.
+                return Ok(INDEX_OUT_OF_BOUND);
+            }
+            Err(e) => return Err(e),
+        };
+        if offset >= full_length {
+            return Ok(SLICE_OUT_OF_BOUND);
+        }
+        if length > 0 {
+            let end = offset.checked_add(length).ok_or(Error::MemOutOfBound)?;
+            if end > full_length {
+                return Ok(SLICE_OUT_OF_BOUND);
+            }
+        }
+        Ok(SUCCESS)
+    }
+
+    // Replace the program running in current VM with a new one, the actual
+    // loading is done by the scheduler, which rebuilds the VM from scratch,
+    // the same way as a newly spawned VM.
+    fn exec<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let index = machine.registers()[A0].to_u64();
+        let source = machine.registers()[A1].to_u64();
+        let place = machine.registers()[A2].to_u64();
+
+        let data_piece_id = match place {
+            0 => DataPieceId::try_from((source, index)),
+            1 => DataPieceId::witness(source, index),
+            _ => Err(format!("Invalid place value: {}", place)),
+        };
+        let data_piece_id = match data_piece_id {
+            Ok(id) => id,
+            Err(e) => {
+                // Similar to spawn, INDEX_OUT_OF_BOUND error is returned for
+                // all invalid values for simplicity.
+                log::error!("DataPieceId parsing error: {:?}", e);
+                machine.set_register(A0, Mac::REG::from_u8(INDEX_OUT_OF_BOUND));
+                return Ok(());
+            }
+        };
+
+        let bounds = machine.registers()[A3].to_u64();
+        let offset = bounds >> 32;
+        let length = bounds as u32 as u64;
+
+        let argv = {
+            let argc = machine.registers()[A4].to_u64();
+            let argv_addr = machine.registers()[A5].to_u64();
+            load_argv(machine, argc, argv_addr)?
+        };
+
+        let code = self.check_bounds(&data_piece_id, offset, length)?;
+        if code != SUCCESS {
+            machine.set_register(A0, Mac::REG::from_u8(code));
+            return Ok(());
+        }
+
+        self.message_box.lock().expect("lock").push(Message::Exec(
+            self.id,
+            ExecArgs {
+                data_piece_id,
+                offset,
+                length,
+                argv,
+            },
+        ));
+
+        // Exec never returns on success, the VM starts from the entrypoint
+        // of the new program next time it runs.
+        Err(yield_error())
+    }
+
+    fn vm_version<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        machine.set_register(A0, Mac::REG::from_u32(self.version.vm_version()));
+        Ok(())
+    }
+
+    fn load_tx_hash<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let hash = self.tx_data.rtx.transaction.hash();
+        self.load_bytes(machine, Ok(hash.as_bytes()))
+    }
+
+    fn load_transaction<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let tx = self.tx_data.rtx.transaction.data();
+        self.load_bytes(machine, Ok(tx.as_bytes()))
+    }
+
+    fn load_script_hash<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let hash = self.tx_data.script_group.script.calc_script_hash();
+        self.load_bytes(machine, Ok(hash.as_bytes()))
+    }
+
+    // Script is loaded as a data piece, so pages copied from it are kept out
+    // of snapshots.
+    fn load_script<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        self.load_data_piece(machine, Ok(DataPieceId::Script))
+    }
+
+    fn load_cell<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let cell = self.cell(
+            machine.registers()[A4].to_u64(),
+            machine.registers()[A3].to_u64(),
+        );
+        self.load_bytes(machine, cell.map(|cell| cell.cell_output.as_bytes()))
+    }
+
+    // Witnesses are also loaded as data pieces, so pages copied from
+    // witnesses are kept out of snapshots as well.
+    fn load_witness<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let index = machine.registers()[A3].to_u64();
+        let source = machine.registers()[A4].to_u64();
+        self.load_data_piece(machine, DataPieceId::witness(source, index))
+    }
+
+    fn load_cell_by_field<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let cell = self.cell(
+            machine.registers()[A4].to_u64(),
+            machine.registers()[A3].to_u64(),
+        );
+        let field = machine.registers()[A5].to_u64();
+        let data = match cell {
+            Ok(cell) => match field {
+                CELL_FIELD_CAPACITY => Ok(cell.cell_output.capacity().as_bytes()),
+                CELL_FIELD_DATA_HASH => self
+                    .tx_data
+                    .data_loader
+                    .load_cell_data_hash(&cell)
+                    .map(|hash| hash.as_bytes())
+                    .ok_or(ITEM_MISSING),
+                CELL_FIELD_LOCK => Ok(cell.cell_output.lock().as_bytes()),
+                CELL_FIELD_LOCK_HASH => Ok(cell.cell_output.lock().calc_script_hash().as_bytes()),
+                CELL_FIELD_TYPE => cell
+                    .cell_output
+                    .type_()
+                    .to_opt()
+                    .map(|script| script.as_bytes())
+                    .ok_or(ITEM_MISSING),
+                CELL_FIELD_TYPE_HASH => cell
+                    .cell_output
+                    .type_()
+                    .to_opt()
+                    .map(|script| script.calc_script_hash().as_bytes())
+                    .ok_or(ITEM_MISSING),
+                CELL_FIELD_OCCUPIED_CAPACITY => {
+                    let capacity = Capacity::bytes(cell.data_bytes as usize)
+                        .and_then(|data_capacity| cell.cell_output.occupied_capacity(data_capacity))
+                        .map_err(|e| Error::Unexpected(format!("Capacity error: {:?}", e)))?;
+                    Ok(Bytes::from(capacity.as_u64().to_le_bytes().to_vec()))
+                }
+                _ => return Err(Error::Unexpected(format!("Invalid cell field: {}", field))),
+            },
+            Err(code) => Err(code),
+        };
+        self.load_bytes(machine, data)
+    }
+
+    fn load_header<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let header = self.header(
+            machine.registers()[A4].to_u64(),
+            machine.registers()[A3].to_u64(),
+        );
+        self.load_bytes(machine, header.map(|header| header.data().as_bytes()))
+    }
+
+    fn load_header_by_field<Mac: SupportMachine>(
+        &mut self,
+        machine: &mut Mac,
+    ) -> Result<(), Error> {
+        let header = self.header(
+            machine.registers()[A4].to_u64(),
+            machine.registers()[A3].to_u64(),
+        );
+        let field = machine.registers()[A5].to_u64();
+        let value = match header {
+            Ok(header) => {
+                let epoch = header.epoch();
+                match field {
+                    HEADER_FIELD_EPOCH_NUMBER => Ok(epoch.number()),
+                    HEADER_FIELD_EPOCH_START_BLOCK_NUMBER => {
+                        Ok(header.number().checked_sub(epoch.index()).ok_or_else(|| {
+                            Error::Unexpected("Invalid epoch index in header".to_string())
+                        })?)
+                    }
+                    HEADER_FIELD_EPOCH_LENGTH => Ok(epoch.length()),
+                    _ => {
+                        return Err(Error::Unexpected(format!(
+                            "Invalid header field: {}",
+                            field
+                        )))
+                    }
+                }
+            }
+            Err(code) => Err(code),
+        };
+        let data = value.map(|value| Bytes::from(value.to_le_bytes().to_vec()));
+        self.load_bytes(machine, data)
+    }
+
+    fn load_input<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let input = self.input(
+            machine.registers()[A4].to_u64(),
+            machine.registers()[A3].to_u64(),
+        );
+        self.load_bytes(machine, input.map(|input| input.as_bytes()))
+    }
+
+    fn load_input_by_field<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let input = self.input(
+            machine.registers()[A4].to_u64(),
+            machine.registers()[A3].to_u64(),
+        );
+        let field = machine.registers()[A5].to_u64();
+        let data = match input {
+            Ok(input) => match field {
+                INPUT_FIELD_OUT_POINT => Ok(input.previous_output().as_bytes()),
+                INPUT_FIELD_SINCE => Ok(input.since().as_bytes()),
+                _ => return Err(Error::Unexpected(format!("Invalid input field: {}", field))),
+            },
+            Err(code) => Err(code),
+        };
+        self.load_bytes(machine, data)
+    }
+
+    fn load_block_extension<Mac: SupportMachine>(
+        &mut self,
+        machine: &mut Mac,
+    ) -> Result<(), Error> {
+        let extension = self
+            .block_hash(
+                machine.registers()[A4].to_u64(),
+                machine.registers()[A3].to_u64(),
+            )
+            .and_then(|block_hash| {
+                self.tx_data
+                    .data_loader
+                    .get_block_extension(&block_hash)
+                    .ok_or(ITEM_MISSING)
+            });
+        self.load_bytes(machine, extension.map(|extension| extension.raw_data()))
+    }
+
+    // Store loaded data into VM memory, or set the error code when the data
+    // cannot be loaded.
+    fn load_bytes<Mac: SupportMachine>(
+        &mut self,
+        machine: &mut Mac,
+        data: Result<Bytes, u8>,
+    ) -> Result<(), Error> {
+        match data {
+            Ok(data) => {
+                let wrote_size = store_data(machine, &data)?;
+                self.charge_cycles(machine, transferred_byte_cycles(wrote_size))?;
+                machine.set_register(A0, Mac::REG::from_u8(SUCCESS));
+            }
+            Err(code) => machine.set_register(A0, Mac::REG::from_u8(code)),
+        }
+        Ok(())
+    }
+
+    // Look up a cell for load_cell & load_cell_by_field syscalls
+    fn cell(&self, source: u64, index: u64) -> Result<CellMeta, u8> {
+        let rtx = &self.tx_data.rtx;
+        let group = &self.tx_data.script_group;
+        let index = index as usize;
+        match parse_source(source)? {
+            (false, SOURCE_INPUT) => rtx.resolved_inputs.get(index).cloned(),
+            (false, SOURCE_OUTPUT) => self.output_cell(index),
+            (false, SOURCE_CELL_DEP) => rtx.resolved_cell_deps.get(index).cloned(),
+            (true, SOURCE_INPUT) => group
+                .input_indices
+                .get(index)
+                .and_then(|i| rtx.resolved_inputs.get(*i).cloned()),
+            (true, SOURCE_OUTPUT) => group
+                .output_indices
+                .get(index)
+                .and_then(|i| self.output_cell(*i)),
+            _ => None,
+        }
+        .ok_or(INDEX_OUT_OF_BOUND)
+    }
+
+    fn output_cell(&self, index: usize) -> Option<CellMeta> {
+        let tx = &self.tx_data.rtx.transaction;
+        let output = tx.outputs().get(index)?;
+        let data = tx.outputs_data().get(index)?.raw_data();
+        Some(
+            CellMetaBuilder::from_cell_output(output, data)
+                .out_point(OutPoint::new(tx.hash(), index as u32))
+                .build(),
+        )
+    }
+
+    // Look up the hash of a block for load_header & load_block_extension
+    // syscalls. Cells are located via the blocks they are committed in, which
+    // must be included in header deps.
+    fn block_hash(&self, source: u64, index: u64) -> Result<Byte32, u8> {
+        let tx = &self.tx_data.rtx.transaction;
+        let block_hash = match parse_source(source)? {
+            (false, SOURCE_HEADER_DEP) => {
+                return tx
+                    .header_deps()
+                    .get(index as usize)
+                    .ok_or(INDEX_OUT_OF_BOUND)
+            }
+            (_, SOURCE_INPUT) | (false, SOURCE_CELL_DEP) => self
+                .cell(source, index)?
+                .transaction_info
+                .map(|info| info.block_hash)
+                .ok_or(ITEM_MISSING)?,
+            _ => return Err(INDEX_OUT_OF_BOUND),
+        };
+        if tx.header_deps_iter().any(|hash| hash == block_hash) {
+            Ok(block_hash)
+        } else {
+            Err(ITEM_MISSING)
+        }
+    }
+
+    fn header(&self, source: u64, index: u64) -> Result<HeaderView, u8> {
+        let block_hash = self.block_hash(source, index)?;
+        self.tx_data
+            .data_loader
+            .get_header(&block_hash)
+            .ok_or(ITEM_MISSING)
+    }
+
+    fn input(&self, source: u64, index: u64) -> Result<CellInput, u8> {
+        let inputs = self.tx_data.rtx.transaction.inputs();
+        let index = index as usize;
+        match parse_source(source)? {
+            (false, SOURCE_INPUT) => inputs.get(index),
+            (true, SOURCE_INPUT) => self
+                .tx_data
+                .script_group
+                .input_indices
+                .get(index)
+                .and_then(|i| inputs.get(*i)),
+            _ => None,
+        }
+        .ok_or(INDEX_OUT_OF_BOUND)
+    }
+
+    // Terminate a VM spawned by current VM, directly or indirectly
+    fn kill<Mac: SupportMachine>(&mut self, machine: &mut Mac) -> Result<(), Error> {
+        let target_id = machine.registers()[A0].to_u64();
+
+        self.charge_cycles(machine, self.cost_schedule.kill_cycles())?;
+        self.message_box
+            .lock()
+            .expect("lock")
+            .push(Message::Kill(self.id, target_id));
+
+        // A0 will be updated once the permission is verified
+        Err(yield_error())
     }
 }
 
@@ -451,9 +966,23 @@ impl<
     fn ecall(&mut self, machine: &mut Mac) -> Result<bool, Error> {
         let code = machine.registers()[A7].to_u64();
         match code {
+            2041 if self.version >= ScriptVersion::V1 => self.vm_version(machine),
             2042 => self.current_cycles(machine),
+            2043 if self.version >= ScriptVersion::V1 => self.exec(machine),
+            2051 => self.load_transaction(machine),
+            2052 => self.load_script(machine),
+            2061 => self.load_tx_hash(machine),
+            2062 => self.load_script_hash(machine),
+            2071 => self.load_cell(machine),
+            2072 => self.load_header(machine),
+            2073 => self.load_input(machine),
+            2074 => self.load_witness(machine),
+            2081 => self.load_cell_by_field(machine),
+            2082 => self.load_header_by_field(machine),
+            2083 => self.load_input_by_field(machine),
             2091 => self.load_cell_data_as_code(machine),
             2092 => self.load_cell_data(machine),
+            2104 if self.version >= ScriptVersion::V2 => self.load_block_extension(machine),
             2177 => self.debug(machine),
             // The syscall numbers here are picked intentionally to be different
             // than currently assigned syscall numbers for spawn calls
@@ -463,6 +992,11 @@ impl<
             2604 => self.pipe(machine),
             2605 => self.pipe_write(machine),
             2606 => self.pipe_read(machine),
+            2607 => self.close(machine),
+            2608 => self.select(machine),
+            2609 => self.join_any(machine),
+            2610 => self.kill(machine),
+            2611 => self.parent_id(machine),
             _ => return Ok(false),
         }?;
         Ok(true)
@@ -482,12 +1016,118 @@ pub(crate) fn transferred_byte_cycles(bytes: u64) -> u64 {
     (bytes + BYTES_PER_CYCLE - 1) / BYTES_PER_CYCLE
 }
 
+/// Copies data into VM memory following the partial loading convention
+/// shared by all data loading syscalls: A0 holds the buffer address, A1 holds
+/// the address of buffer size, which is updated to the full size of data
+/// after A2(offset). Returns the number of bytes actually written.
+fn store_data<Mac: SupportMachine>(machine: &mut Mac, data: &[u8]) -> Result<u64, Error> {
+    let addr = machine.registers()[A0].to_u64();
+    let size_addr = machine.registers()[A1].clone();
+    let data_len = data.len() as u64;
+    let offset = std::cmp::min(data_len, machine.registers()[A2].to_u64());
+
+    let size = machine.memory_mut().load64(&size_addr)?.to_u64();
+    let full_size = data_len - offset;
+    let real_size = std::cmp::min(size, full_size);
+    machine
+        .memory_mut()
+        .store64(&size_addr, &Mac::REG::from_u64(full_size))?;
+    machine
+        .memory_mut()
+        .store_bytes(addr, &data[offset as usize..(offset + real_size) as usize])?;
+    Ok(real_size)
+}
+
+/// Calculates how many bytes are copied when suspending a VM to, or resuming
+/// a VM from the specified snapshot. Only dirty pages and registers are counted
+/// here, pages tracked from transaction data are not copied into snapshots.
+pub(crate) fn swap_bytes(snapshot: &Snapshot2<DataPieceId>) -> u64 {
+    snapshot
+        .dirty_pages
+        .iter()
+        .fold(0, |acc, (_, _, content)| acc + content.len() as u64)
+        + (RISCV_GENERAL_REGISTER_NUMBER * size_of::<u64>()) as u64
+}
+
 pub(crate) const SUCCESS: u8 = 0;
 const INDEX_OUT_OF_BOUND: u8 = 1;
+const ITEM_MISSING: u8 = 2;
 const SLICE_OUT_OF_BOUND: u8 = 3;
 pub(crate) const JOIN_FAILURE: u8 = 5;
 pub(crate) const INVALID_PIPE: u8 = 6;
 pub(crate) const OTHER_END_CLOSED: u8 = 7;
+pub(crate) const MAX_VMS_SPAWNED: u8 = 8;
+pub(crate) const MAX_PIPES_CREATED: u8 = 9;
+pub(crate) const MAX_SPAWN_DEPTH: u8 = 10;
+pub(crate) const WOULD_BLOCK: u8 = 11;
+pub(crate) const KILL_FAILURE: u8 = 12;
+pub(crate) const NOT_PERMITTED: u8 = 13;
+
+// Source values accepted by data loading syscalls, a source is a cell
+// source optionally combined with the group flag.
+const SOURCE_INPUT: u64 = 1;
+const SOURCE_OUTPUT: u64 = 2;
+const SOURCE_CELL_DEP: u64 = 3;
+const SOURCE_HEADER_DEP: u64 = 4;
+const SOURCE_GROUP_FLAG: u64 = 0x0100000000000000;
+
+const CELL_FIELD_CAPACITY: u64 = 0;
+const CELL_FIELD_DATA_HASH: u64 = 1;
+const CELL_FIELD_LOCK: u64 = 2;
+const CELL_FIELD_LOCK_HASH: u64 = 3;
+const CELL_FIELD_TYPE: u64 = 4;
+const CELL_FIELD_TYPE_HASH: u64 = 5;
+const CELL_FIELD_OCCUPIED_CAPACITY: u64 = 6;
+
+const HEADER_FIELD_EPOCH_NUMBER: u64 = 0;
+const HEADER_FIELD_EPOCH_START_BLOCK_NUMBER: u64 = 1;
+const HEADER_FIELD_EPOCH_LENGTH: u64 = 2;
+
+const INPUT_FIELD_OUT_POINT: u64 = 0;
+const INPUT_FIELD_SINCE: u64 = 1;
+
+// Split a source value into the group flag and the cell source
+fn parse_source(source: u64) -> Result<(bool, u64), u8> {
+    match source & !SOURCE_GROUP_FLAG {
+        SOURCE_INPUT | SOURCE_OUTPUT | SOURCE_CELL_DEP | SOURCE_HEADER_DEP => {
+            Ok((source & SOURCE_GROUP_FLAG != 0, source & !SOURCE_GROUP_FLAG))
+        }
+        // Similar to DataPieceId, INDEX_OUT_OF_BOUND error is returned for
+        // invalid source values for simplicity.
+        _ => Err(INDEX_OUT_OF_BOUND),
+    }
+}
+
+/// Exit code recorded for a VM terminated via kill syscall
+pub const KILLED_EXIT_CODE: i8 = -9;
+
+/// Flag for pipe_read / pipe_write syscalls: instead of blocking, return
+/// WOULD_BLOCK immediately when the other end of the pipe is not ready.
+pub const PIPE_NONBLOCK: u64 = 1;
+
+/// Max number of pipes a single select syscall can wait on
+pub const MAX_SELECT_PIPES: u64 = 64;
+
+/// Max number of VMs a single join_any syscall can wait on
+pub const MAX_JOIN_ANY_VMS: u64 = 64;
+
+fn load_argv<Mac: SupportMachine>(
+    machine: &mut Mac,
+    argc: u64,
+    argv_addr: u64,
+) -> Result<Vec<Bytes>, Error> {
+    let mut argv_addr = argv_addr;
+    let mut argv = Vec::with_capacity(argc as usize);
+    for _ in 0..argc {
+        let target_addr = machine
+            .memory_mut()
+            .load64(&Mac::REG::from_u64(argv_addr))?
+            .to_u64();
+        argv.push(load_c_string(machine, target_addr)?);
+        argv_addr += 8;
+    }
+    Ok(argv)
+}
 
 fn load_c_string<Mac: SupportMachine>(machine: &mut Mac, addr: u64) -> Result<Bytes, Error> {
     let mut buffer = Vec::new();
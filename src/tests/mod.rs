@@ -1,18 +1,178 @@
-use crate::dev_utils::{build_mock_tx, generate_data_graph, verify_tx};
-use ckb_types::core::Cycle;
+use crate::cost_schedule::{CostSchedule, DefaultCostSchedule};
+use crate::deadlock::DeadlockReport;
+use crate::dev_utils::{
+    build_mock_tx, chrome_trace::ChromeTraceObserver, dag, generate_data_graph, script_groups,
+    verify_tx, verify_tx_with_ckb_script, verify_tx_with_options, VerifyOptions, VerifyResult,
+};
+use crate::error::{is_index_out_of_bound, SchedulerError};
+use crate::eviction::{
+    EvictionCandidate, EvictionStrategy, LeastRecentlyRun, LowestIdFirst, SmallestSnapshotFirst,
+};
+use crate::observer::SchedulerObserver;
+use crate::scheduling_policy::{
+    builtin_scheduling_policy, CycleFair, HighestIdFirst, RoundRobin, SchedulingContext,
+    SchedulingPolicy,
+};
+use crate::trace::{Trace, TraceEvent, TraceRecorder, TraceReplayer};
+use crate::types::{
+    DataPieceId, DebugMessage, DebugSink, ExecArgs, FullSuspendedState, Limits, Message, PipeId,
+    RunMode, RunResult, SpawnTree, VmId, VmState, VmStats,
+};
+use crate::Scheduler;
+use ckb_mock_tx_types::{MockCellDep, MockTransaction};
+use ckb_script::ScriptVersion;
+use ckb_types::{
+    core::{Cycle, EpochNumberWithFraction, HeaderView},
+    packed,
+    prelude::*,
+};
+use ckb_vm::{
+    bytes::Bytes,
+    snapshot2::{DataSource, Snapshot2},
+};
 use proptest::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
 
 const MAX_CYCLES: Cycle = 300_000_000;
 const CYCLES_PER_ITERATE: Cycle = 10_000_000;
 const CYCLES_PER_SUSPEND: Cycle = 50_000_000;
 
-#[test]
-fn test_program_exists() {
+fn test_program() -> ckb_types::bytes::Bytes {
     let program_path = match std::env::var("TEST_BIN") {
         Ok(path) => path,
         Err(_) => "./test_bin".to_string(),
     };
-    let _ = std::fs::read(program_path).expect("read");
+    std::fs::read(program_path).expect("read").into()
+}
+
+fn scenarios_program() -> ckb_types::bytes::Bytes {
+    let program_path = match std::env::var("TEST_SCENARIOS_BIN") {
+        Ok(path) => path,
+        Err(_) => "./scenarios_bin".to_string(),
+    };
+    std::fs::read(program_path).expect("read").into()
+}
+
+// Build a transaction running one scenario from test-contracts/scenarios.c,
+// the scenario is picked by script args.
+fn scenario_tx(scenario: &str) -> MockTransaction {
+    let mut mock_tx = build_mock_tx(0, scenarios_program(), dag::Data::default());
+    let input = &mut mock_tx.mock_info.inputs[0];
+    let lock = input
+        .output
+        .lock()
+        .as_builder()
+        .args(scenario.as_bytes().pack())
+        .build();
+    input.output = input.output.clone().as_builder().lock(lock).build();
+    mock_tx
+}
+
+// Run a scenario, the small budgets make sure VMs are suspended & resumed
+// in the middle of it. Debug output is printed when the scenario fails.
+fn run_scenario(scenario: &str, options: VerifyOptions) -> VerifyResult {
+    let debug_output = Arc::new(Mutex::new(Vec::new()));
+    let result = verify_tx_with_options(
+        &scenario_tx(scenario),
+        MAX_CYCLES,
+        100_000,
+        300_000,
+        VerifyOptions {
+            debug_output: Some(debug_output.clone()),
+            ..options
+        },
+    );
+    match result {
+        Ok(result) => result,
+        Err(e) => {
+            for debug in debug_output.lock().unwrap().iter() {
+                println!("VM {}: {}", debug.vm_id, debug.message);
+            }
+            panic!("Scenario {} fails: {:?}", scenario, e);
+        }
+    }
+}
+
+// DefaultCostSchedule with extra cycles charged for each pipe transfer, so
+// transfer charges can be located by comparing against DefaultCostSchedule.
+struct ExtraCostSchedule {
+    transfer_read: Cycle,
+    transfer_write: Cycle,
+}
+
+impl CostSchedule for ExtraCostSchedule {
+    fn pipe_transfer_read_cycles(&self, bytes: u64) -> Cycle {
+        DefaultCostSchedule.pipe_transfer_read_cycles(bytes) + self.transfer_read
+    }
+
+    fn pipe_transfer_write_cycles(&self, bytes: u64) -> Cycle {
+        DefaultCostSchedule.pipe_transfer_write_cycles(bytes) + self.transfer_write
+    }
+}
+
+// Scheduler events recorded by EventRecorder, cycles are left out
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Event {
+    Boot(VmId),
+    Suspend(VmId, u64),
+    Resume(VmId, u64),
+    Spawn(VmId, VmId),
+    PipeCreate(VmId, PipeId, PipeId),
+    PipeClose(VmId, PipeId),
+    JoinWakeup(VmId, VmId),
+    Terminate(VmId),
+}
+
+#[derive(Default)]
+struct EventRecorder {
+    events: Vec<Event>,
+}
+
+impl SchedulerObserver for EventRecorder {
+    fn on_vm_boot(&mut self, _cycles: Cycle, vm_id: VmId, _data_piece_id: &DataPieceId) {
+        self.events.push(Event::Boot(vm_id));
+    }
+
+    fn on_vm_suspend(&mut self, _cycles: Cycle, vm_id: VmId, bytes: u64) {
+        self.events.push(Event::Suspend(vm_id, bytes));
+    }
+
+    fn on_vm_resume(&mut self, _cycles: Cycle, vm_id: VmId, bytes: u64) {
+        self.events.push(Event::Resume(vm_id, bytes));
+    }
+
+    fn on_spawn(&mut self, _cycles: Cycle, parent: VmId, child: VmId, _pipes: &[PipeId]) {
+        self.events.push(Event::Spawn(parent, child));
+    }
+
+    fn on_pipe_create(
+        &mut self,
+        _cycles: Cycle,
+        vm_id: VmId,
+        read_pipe: PipeId,
+        write_pipe: PipeId,
+    ) {
+        self.events
+            .push(Event::PipeCreate(vm_id, read_pipe, write_pipe));
+    }
+
+    fn on_pipe_close(&mut self, _cycles: Cycle, vm_id: VmId, pipe: PipeId) {
+        self.events.push(Event::PipeClose(vm_id, pipe));
+    }
+
+    fn on_join_wakeup(&mut self, _cycles: Cycle, vm_id: VmId, target: VmId, _exit_code: i8) {
+        self.events.push(Event::JoinWakeup(vm_id, target));
+    }
+
+    fn on_vm_terminate(&mut self, _cycles: Cycle, vm_id: VmId, _exit_code: i8, _vm_cycles: Cycle) {
+        self.events.push(Event::Terminate(vm_id));
+    }
+}
+
+#[test]
+fn test_program_exists() {
+    let _ = test_program();
 }
 
 #[test]
@@ -22,11 +182,7 @@ fn test_single_dag() {
     let writes = 168;
 
     let data = generate_data_graph(seed, spawns, writes, 3).expect("generate dag");
-    let program_path = match std::env::var("TEST_BIN") {
-        Ok(path) => path,
-        Err(_) => "./test_bin".to_string(),
-    };
-    let program = std::fs::read(program_path).expect("read").into();
+    let program = test_program();
 
     let mock_tx = build_mock_tx(seed.wrapping_add(10), program, data);
 
@@ -34,6 +190,1403 @@ fn test_single_dag() {
     assert!(result.unwrap() <= MAX_CYCLES);
 }
 
+#[test]
+fn test_trace_replay() {
+    let data = generate_data_graph(1, 20, 40, 3).expect("generate dag");
+    let program = test_program();
+    let mock_tx = build_mock_tx(11, program, data);
+
+    let recorder = Arc::new(Mutex::new(TraceRecorder::default()));
+    verify_tx_with_options(
+        &mock_tx,
+        MAX_CYCLES,
+        CYCLES_PER_ITERATE,
+        CYCLES_PER_SUSPEND,
+        VerifyOptions {
+            observer: Some(recorder.clone()),
+            ..Default::default()
+        },
+    )
+    .expect("verify");
+    let trace = recorder.lock().unwrap().trace().clone();
+    assert!(matches!(trace.events[0], TraceEvent::Run { vm_id: 0, .. }));
+    let decoded = Trace::decode(&trace.encode()).expect("decode");
+    assert_eq!(decoded, trace);
+
+    // Splitting execution differently must not change the trace
+    let replayer = Arc::new(Mutex::new(TraceReplayer::new(decoded)));
+    verify_tx_with_options(
+        &mock_tx,
+        MAX_CYCLES,
+        CYCLES_PER_ITERATE / 7,
+        CYCLES_PER_SUSPEND / 3,
+        VerifyOptions {
+            observer: Some(replayer.clone()),
+            ..Default::default()
+        },
+    )
+    .expect("verify");
+    replayer.lock().unwrap().finish().expect("replay");
+
+    let mut tampered = trace.clone();
+    if let TraceEvent::Run {
+        consumed_cycles, ..
+    } = &mut tampered.events[0]
+    {
+        *consumed_cycles += 1;
+    }
+    let divergence = tampered.first_divergence(&trace).expect("diverge");
+    assert_eq!(divergence.index, 0);
+    assert_eq!(divergence.actual, Some(trace.events[0].clone()));
+
+    let exec = Trace {
+        events: vec![TraceEvent::Message(Message::Exec(
+            1,
+            ExecArgs {
+                data_piece_id: DataPieceId::Witness(2),
+                offset: 4,
+                length: 100,
+                argv: vec![Bytes::from_static(b"arg0"), Bytes::new()],
+            },
+        ))],
+    };
+    assert_eq!(Trace::decode(&exec.encode()).expect("decode"), exec);
+}
+
+#[test]
+fn test_debug_output() {
+    let data = generate_data_graph(2, 10, 20, 3).expect("generate dag");
+    let program = test_program();
+    let mock_tx = build_mock_tx(12, program, data);
+
+    let capture = |max_cycles, cycles_per_iterate, cycles_per_suspend| {
+        let debug_output = Arc::new(Mutex::new(Vec::new()));
+        let result = verify_tx_with_options(
+            &mock_tx,
+            max_cycles,
+            cycles_per_iterate,
+            cycles_per_suspend,
+            VerifyOptions {
+                debug_output: Some(debug_output.clone()),
+                ..Default::default()
+            },
+        );
+        let output = debug_output.lock().unwrap().clone();
+        (result.is_ok(), output)
+    };
+    let (verified, output) = capture(MAX_CYCLES, CYCLES_PER_ITERATE, CYCLES_PER_SUSPEND);
+    assert!(verified);
+    assert!(output.iter().any(|debug| debug.vm_id > 0));
+    // Captured output survives suspending & resuming
+    assert_eq!(
+        capture(MAX_CYCLES, CYCLES_PER_ITERATE / 7, CYCLES_PER_SUSPEND / 3),
+        (true, output.clone())
+    );
+    // Output printed before the script fails is kept as well
+    let (verified, partial_output) = capture(1_000_000, 100_000, CYCLES_PER_SUSPEND);
+    assert!(!verified);
+    assert!(!partial_output.is_empty());
+    assert!(output.starts_with(&partial_output));
+}
+
+#[test]
+fn test_vm_stats_add_up() {
+    let data = generate_data_graph(3, 30, 60, 3).expect("generate dag");
+    let program = test_program();
+    let mock_tx = build_mock_tx(13, program, data);
+
+    let result = verify_tx_with_options(
+        &mock_tx,
+        MAX_CYCLES,
+        CYCLES_PER_ITERATE / 7,
+        CYCLES_PER_SUSPEND / 3,
+        VerifyOptions {
+            max_instantiated_vms: Some(2),
+            ..Default::default()
+        },
+    )
+    .expect("verify");
+    assert_eq!(result.groups.len(), 1);
+    let group = &result.groups[0];
+    assert_eq!(group.total_cycles, result.consumed_cycles);
+    assert!(group.swap_count > 0);
+    assert_eq!(group.vm_stats.len(), 31);
+    assert_eq!(
+        group
+            .vm_stats
+            .iter()
+            .map(|stats| stats.total_cycles())
+            .sum::<Cycle>(),
+        group.total_cycles
+    );
+}
+
+#[test]
+fn test_pipe_transfer_charges() {
+    // Root VM spawns a single VM, one of them writes to the other once
+    let data = generate_data_graph(4, 1, 1, 3).expect("generate dag");
+    let mock_tx = build_mock_tx(14, test_program(), data);
+
+    let run = |cost_schedule: Arc<dyn CostSchedule>| {
+        let recorder = Arc::new(Mutex::new(TraceRecorder::default()));
+        let result = verify_tx_with_options(
+            &mock_tx,
+            MAX_CYCLES,
+            CYCLES_PER_ITERATE,
+            CYCLES_PER_SUSPEND,
+            VerifyOptions {
+                cost_schedule,
+                observer: Some(recorder.clone()),
+                ..Default::default()
+            },
+        )
+        .expect("verify");
+        let transfers: Vec<_> = recorder
+            .lock()
+            .unwrap()
+            .trace()
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::Transfer {
+                    write_vm,
+                    read_vm,
+                    bytes,
+                    ..
+                } => Some((*write_vm, *read_vm, *bytes)),
+                _ => None,
+            })
+            .collect();
+        (
+            result.groups[0].vm_stats.clone(),
+            result.consumed_cycles,
+            transfers,
+        )
+    };
+    let (stats, cycles, transfers) = run(Arc::new(DefaultCostSchedule));
+    assert_eq!(transfers.len(), 1);
+    let (write_vm, read_vm, bytes) = transfers[0];
+    assert_ne!(write_vm, read_vm);
+    assert!(bytes > 0);
+    assert_eq!(stats[read_vm as usize].bytes_read, bytes);
+    assert_eq!(stats[read_vm as usize].bytes_written, 0);
+    assert_eq!(stats[write_vm as usize].bytes_written, bytes);
+    assert_eq!(stats[write_vm as usize].bytes_read, 0);
+
+    // Extra charges go to the reading VM & the writing VM respectively, and
+    // show up in total cycles.
+    let (extra_stats, extra_cycles, extra_transfers) = run(Arc::new(ExtraCostSchedule {
+        transfer_read: 1000,
+        transfer_write: 5000,
+    }));
+    assert_eq!(extra_transfers, transfers);
+    assert_eq!(
+        extra_stats[read_vm as usize].syscall_cycles,
+        stats[read_vm as usize].syscall_cycles + 1000
+    );
+    assert_eq!(
+        extra_stats[write_vm as usize].syscall_cycles,
+        stats[write_vm as usize].syscall_cycles + 5000
+    );
+    assert_eq!(extra_cycles, cycles + 6000);
+}
+
+#[test]
+fn test_observer_event_order() {
+    let data = generate_data_graph(6, 4, 8, 3).expect("generate dag");
+    let mock_tx = build_mock_tx(8, test_program(), data);
+    let recorder = Arc::new(Mutex::new(EventRecorder::default()));
+    let result = verify_tx_with_options(
+        &mock_tx,
+        MAX_CYCLES,
+        CYCLES_PER_ITERATE,
+        CYCLES_PER_SUSPEND,
+        VerifyOptions {
+            observer: Some(recorder.clone()),
+            ..Default::default()
+        },
+    )
+    .expect("verify");
+    let events = recorder.lock().unwrap().events.clone();
+    let position = |expected: &Event| events.iter().position(|event| event == expected);
+
+    // Root VM boots first and terminates last
+    assert_eq!(events.first(), Some(&Event::Boot(0)));
+    assert_eq!(events.last(), Some(&Event::Terminate(0)));
+    let vm_stats = &result.groups[0].vm_stats;
+    assert!(vm_stats.len() > 1);
+    for stats in vm_stats {
+        let vm_id = stats.vm_id;
+        let boot = position(&Event::Boot(vm_id)).expect("boot");
+        if vm_id != 0 {
+            let spawn = events
+                .iter()
+                .position(|event| matches!(event, Event::Spawn(_, child) if *child == vm_id))
+                .expect("spawn");
+            assert!(boot < spawn);
+        }
+        // Only VMs exiting before the root VM get terminate events
+        let terminates = events
+            .iter()
+            .filter(|event| **event == Event::Terminate(vm_id))
+            .count();
+        assert_eq!(terminates, stats.exit_code.map_or(0, |_| 1));
+        if let Some(terminate) = position(&Event::Terminate(vm_id)) {
+            // Nothing happens to a terminated VM, apart from VMs joining it
+            // getting woken up.
+            for (i, event) in events.iter().enumerate() {
+                let actor = match event {
+                    Event::Boot(id)
+                    | Event::Suspend(id, _)
+                    | Event::Resume(id, _)
+                    | Event::Spawn(id, _)
+                    | Event::PipeCreate(id, _, _)
+                    | Event::PipeClose(id, _)
+                    | Event::JoinWakeup(id, _)
+                    | Event::Terminate(id) => *id,
+                };
+                if actor == vm_id {
+                    assert!(i <= terminate, "{:?} after VM {} terminates", event, vm_id);
+                }
+                if *event == Event::JoinWakeup(actor, vm_id) {
+                    assert!(i > terminate);
+                }
+            }
+        }
+    }
+
+    // Each pipe is closed exactly once, by the VM owning it when it closes
+    let mut created: Vec<PipeId> = Vec::new();
+    let mut closed: Vec<PipeId> = Vec::new();
+    for event in &events {
+        match event {
+            Event::PipeCreate(_, read_pipe, write_pipe) => {
+                created.push(*read_pipe);
+                created.push(*write_pipe);
+            }
+            Event::PipeClose(_, pipe) => closed.push(*pipe),
+            _ => (),
+        }
+    }
+    assert!(!created.is_empty());
+    created.sort_by_key(|pipe| pipe.0);
+    closed.sort_by_key(|pipe| pipe.0);
+    assert_eq!(created, closed);
+}
+
+#[test]
+fn test_chrome_trace() {
+    let data = generate_data_graph(6, 4, 8, 3).expect("generate dag");
+    let mock_tx = build_mock_tx(8, test_program(), data);
+    let observer = Arc::new(Mutex::new(ChromeTraceObserver::default()));
+    let result = verify_tx_with_options(
+        &mock_tx,
+        MAX_CYCLES,
+        CYCLES_PER_ITERATE,
+        CYCLES_PER_SUSPEND,
+        VerifyOptions {
+            observer: Some(observer.clone()),
+            ..Default::default()
+        },
+    )
+    .expect("verify");
+    let json = observer.lock().unwrap().to_json();
+    let trace: serde_json::Value = serde_json::from_str(&json).expect("parse");
+    let events = trace["traceEvents"].as_array().expect("events");
+
+    // One named track per VM, all events go to the tracks of the only
+    // script group.
+    let vm_ids: Vec<u64> = result.groups[0]
+        .vm_stats
+        .iter()
+        .map(|stats| stats.vm_id)
+        .collect();
+    let mut tracks: Vec<u64> = events
+        .iter()
+        .filter(|event| event["ph"] == "M" && event["name"] == "thread_name")
+        .map(|event| event["tid"].as_u64().expect("tid"))
+        .collect();
+    tracks.sort_unstable();
+    assert_eq!(tracks, vm_ids);
+    for event in events {
+        assert_eq!(event["pid"], 0);
+        assert!(vm_ids.contains(&event["tid"].as_u64().expect("tid")));
+    }
+    let runs = events.iter().filter(|event| event["name"] == "Run").count();
+    assert!(runs >= vm_ids.len());
+}
+
+#[test]
+fn test_swap_charges() {
+    let data = generate_data_graph(5, 8, 16, 3).expect("generate dag");
+    let mock_tx = build_mock_tx(15, test_program(), data);
+
+    // Full suspends are disabled, so all swaps are made to fit VMs into
+    // the instantiation cap.
+    let recorder = Arc::new(Mutex::new(EventRecorder::default()));
+    let result = verify_tx_with_options(
+        &mock_tx,
+        MAX_CYCLES,
+        CYCLES_PER_ITERATE,
+        Cycle::MAX,
+        VerifyOptions {
+            max_instantiated_vms: Some(2),
+            observer: Some(recorder.clone()),
+            ..Default::default()
+        },
+    )
+    .expect("verify");
+
+    // A VM being instantiated pays for its own resuming, as well as for
+    // suspending the VMs it replaces, the replaced VMs pay nothing.
+    let mut expected: BTreeMap<VmId, Cycle> = BTreeMap::new();
+    let mut suspend_cycles = 0;
+    for event in &recorder.lock().unwrap().events {
+        match event {
+            Event::Suspend(_, bytes) => suspend_cycles += DefaultCostSchedule.swap_cycles(*bytes),
+            Event::Resume(vm_id, bytes) => {
+                *expected.entry(*vm_id).or_default() +=
+                    std::mem::take(&mut suspend_cycles) + DefaultCostSchedule.swap_cycles(*bytes);
+            }
+            Event::Boot(vm_id) => {
+                *expected.entry(*vm_id).or_default() += std::mem::take(&mut suspend_cycles);
+            }
+            _ => (),
+        }
+    }
+    assert_eq!(suspend_cycles, 0);
+    let group = &result.groups[0];
+    assert!(group.swap_count > 0);
+    for stats in &group.vm_stats {
+        assert_eq!(
+            stats.swap_cycles,
+            expected.get(&stats.vm_id).copied().unwrap_or(0),
+            "VM {}",
+            stats.vm_id
+        );
+    }
+}
+
+#[test]
+fn test_run_budget() {
+    let data = generate_data_graph(6, 10, 20, 3).expect("generate dag");
+    let mock_tx = build_mock_tx(16, test_program(), data);
+    let group = script_groups(&mock_tx).remove(0);
+    let new_scheduler = || {
+        Scheduler::new(
+            group.tx_data.clone(),
+            group.version,
+            Arc::new(DefaultCostSchedule),
+        )
+    };
+
+    let expected_cycles = match new_scheduler()
+        .run(RunMode::LimitCycles(Cycle::MAX))
+        .expect("run")
+    {
+        RunResult::Terminated {
+            exit_code: 0,
+            consumed_cycles,
+            total_cycles,
+            ..
+        } => {
+            assert_eq!(consumed_cycles, total_cycles);
+            total_cycles
+        }
+        result => panic!("Unexpected result: {:?}", result),
+    };
+
+    // Even a single cycle budget makes progress
+    match new_scheduler().run(RunMode::LimitCycles(1)).expect("run") {
+        RunResult::Exhausted {
+            consumed_cycles,
+            total_cycles,
+        } => {
+            assert!(consumed_cycles >= 1);
+            assert_eq!(consumed_cycles, total_cycles);
+        }
+        result => panic!("Unexpected result: {:?}", result),
+    }
+
+    for budget in [1000, 77_777] {
+        let mut scheduler = new_scheduler();
+        let mut last_total_cycles = 0;
+        let mut exhausted = 0;
+        loop {
+            match scheduler.run(RunMode::LimitCycles(budget)).expect("run") {
+                RunResult::Exhausted {
+                    consumed_cycles,
+                    total_cycles,
+                } => {
+                    // The budget is used up, a VM can only be interrupted at
+                    // certain points, so it might be slightly exceeded.
+                    assert!(consumed_cycles >= budget);
+                    assert_eq!(consumed_cycles, total_cycles - last_total_cycles);
+                    assert_eq!(total_cycles, scheduler.consumed_cycles());
+                    last_total_cycles = total_cycles;
+                    exhausted += 1;
+                }
+                RunResult::Terminated {
+                    exit_code,
+                    consumed_cycles,
+                    total_cycles,
+                    ..
+                } => {
+                    assert_eq!(exit_code, 0);
+                    assert_eq!(consumed_cycles, total_cycles - last_total_cycles);
+                    assert_eq!(total_cycles, expected_cycles);
+                    break;
+                }
+            }
+        }
+        assert!(exhausted > 0);
+    }
+}
+
+#[test]
+fn test_close() {
+    let result = run_scenario("close", VerifyOptions::default());
+    // Root VM, a reader & a writer, all exit normally
+    let exit_codes: Vec<_> = result.groups[0]
+        .vm_stats
+        .iter()
+        .map(|stats| stats.exit_code)
+        .collect();
+    assert_eq!(exit_codes, vec![Some(0); 3]);
+}
+
+#[test]
+fn test_nonblock() {
+    let result = run_scenario("nonblock", VerifyOptions::default());
+    // Root VM, a writer & a reader
+    let stats = &result.groups[0].vm_stats;
+    assert_eq!(stats.len(), 3);
+    assert!(stats.iter().all(|stats| stats.exit_code == Some(0)));
+    // Data is only moved by transfers finishing non-blocking operations
+    assert_eq!(stats[1].bytes_written, 5);
+    assert_eq!(stats[2].bytes_read, 3);
+}
+
+#[test]
+fn test_select() {
+    // Without waking up selects on both ends of a pipe together, the
+    // scenario deadlocks.
+    let result = run_scenario("select", VerifyOptions::default());
+    let exit_codes: Vec<_> = result.groups[0]
+        .vm_stats
+        .iter()
+        .map(|stats| stats.exit_code)
+        .collect();
+    assert_eq!(exit_codes, vec![Some(0); 4]);
+}
+
+#[test]
+fn test_join_any() {
+    let result = run_scenario("join_any", VerifyOptions::default());
+    let exit_codes: Vec<_> = result.groups[0]
+        .vm_stats
+        .iter()
+        .map(|stats| stats.exit_code)
+        .collect();
+    assert_eq!(
+        exit_codes,
+        vec![Some(0), Some(11), Some(12), Some(13), Some(0)]
+    );
+}
+
+#[test]
+fn test_kill() {
+    run_scenario("kill", VerifyOptions::default());
+
+    // Full suspends are disabled, so no swap cycles are charged to the
+    // killed VM, and both runs differ only in the transfer charge.
+    let run = |cost_schedule| {
+        let result = verify_tx_with_options(
+            &scenario_tx("kill"),
+            MAX_CYCLES,
+            CYCLES_PER_ITERATE,
+            Cycle::MAX,
+            VerifyOptions {
+                cost_schedule,
+                ..Default::default()
+            },
+        )
+        .expect("verify");
+        (result.groups[0].vm_stats.clone(), result.consumed_cycles)
+    };
+    let (stats, cycles) = run(Arc::new(DefaultCostSchedule));
+    let exit_codes: Vec<_> = stats.iter().map(|stats| stats.exit_code).collect();
+    assert_eq!(exit_codes, vec![Some(0), Some(0), Some(-9)]);
+    // The write transfer charged to the killed VM is paid by the root VM
+    let pending = DefaultCostSchedule.pipe_transfer_write_cycles(1);
+    assert_eq!(stats[2].unpaid_cycles, pending);
+    assert_eq!(stats[2].bytes_written, 1);
+
+    let (extra_stats, extra_cycles) = run(Arc::new(ExtraCostSchedule {
+        transfer_read: 0,
+        transfer_write: 5000,
+    }));
+    assert_eq!(extra_stats[2].unpaid_cycles, pending + 5000);
+    assert_eq!(
+        extra_stats[0].syscall_cycles,
+        stats[0].syscall_cycles + 5000
+    );
+    assert_eq!(extra_stats[1].syscall_cycles, stats[1].syscall_cycles);
+    assert_eq!(extra_cycles, cycles + 5000);
+}
+
+#[test]
+fn test_root_exit_pending_cycles() {
+    run_scenario("root_exit", VerifyOptions::default());
+
+    let run = |cost_schedule| {
+        verify_tx_with_options(
+            &scenario_tx("root_exit"),
+            MAX_CYCLES,
+            CYCLES_PER_ITERATE,
+            Cycle::MAX,
+            VerifyOptions {
+                cost_schedule,
+                ..Default::default()
+            },
+        )
+        .expect("verify")
+    };
+    let result = run(Arc::new(DefaultCostSchedule));
+    let stats = &result.groups[0].vm_stats;
+    let exit_codes: Vec<_> = stats.iter().map(|stats| stats.exit_code).collect();
+    assert_eq!(exit_codes, vec![Some(0), None]);
+    // The write transfer charged to the blocked VM is paid by the root VM
+    let pending = DefaultCostSchedule.pipe_transfer_write_cycles(1);
+    assert_eq!(stats[1].unpaid_cycles, pending);
+    assert_eq!(
+        stats
+            .iter()
+            .map(|stats| stats.total_cycles())
+            .sum::<Cycle>(),
+        result.consumed_cycles
+    );
+
+    // Pending cycles show up in total cycles
+    let extra_result = run(Arc::new(ExtraCostSchedule {
+        transfer_read: 0,
+        transfer_write: 5000,
+    }));
+    let extra_stats = &extra_result.groups[0].vm_stats;
+    assert_eq!(extra_stats[1].unpaid_cycles, pending + 5000);
+    assert_eq!(
+        extra_stats[0].syscall_cycles,
+        stats[0].syscall_cycles + 5000
+    );
+    assert_eq!(extra_result.consumed_cycles, result.consumed_cycles + 5000);
+}
+
+#[test]
+fn test_exec() {
+    let result = run_scenario("exec", VerifyOptions::default());
+    let stats = &result.groups[0].vm_stats;
+    let exit_codes: Vec<_> = stats.iter().map(|stats| stats.exit_code).collect();
+    assert_eq!(exit_codes, vec![Some(0), Some(0)]);
+    // Cycles burnt before exec are carried over, and the pipe passed to the
+    // VM is still usable after exec.
+    assert!(stats[1].execution_cycles >= 1_000_000);
+    assert_eq!(stats[1].program, DataPieceId::CellDep(0));
+    assert_eq!(stats[1].bytes_written, 1);
+}
+
+// Scenario "syscalls" in a transaction exercising all loading paths: the
+// input's header is a header dep with an extension, while cell dep 0 is
+// committed in a block missing from header deps.
+fn syscalls_tx() -> MockTransaction {
+    let mut mock_tx = scenario_tx("syscalls");
+    let header_a = HeaderView::new_advanced_builder()
+        .number(100u64.pack())
+        .epoch(EpochNumberWithFraction::new(2, 10, 1800).pack())
+        .build();
+    let header_b = HeaderView::new_advanced_builder()
+        .number(200u64.pack())
+        .build();
+    mock_tx.mock_info.inputs[0].header = Some(header_a.hash());
+    mock_tx.mock_info.cell_deps[0].header = Some(header_b.hash());
+    mock_tx.mock_info.header_deps = vec![header_a.clone()];
+    mock_tx.mock_info.extensions = vec![(header_a.hash(), Bytes::from_static(b"extension").pack())];
+
+    let data_dep = MockCellDep {
+        cell_dep: packed::CellDep::new_builder()
+            .out_point(
+                packed::OutPoint::new_builder()
+                    .tx_hash([7u8; 32].pack())
+                    .build(),
+            )
+            .build(),
+        output: packed::CellOutput::new_builder()
+            .capacity(1000u64.pack())
+            .build(),
+        data: Bytes::from_static(b"cell dep data"),
+        header: None,
+    };
+    mock_tx.tx = mock_tx
+        .tx
+        .into_view()
+        .as_advanced_builder()
+        .cell_dep(data_dep.cell_dep.clone())
+        .header_dep(header_a.hash())
+        .set_outputs_data(vec![Bytes::from_static(b"output data").pack()])
+        .witness(Bytes::from_static(b"extra witness").pack())
+        .build()
+        .data();
+    mock_tx.mock_info.cell_deps.push(data_dep);
+    mock_tx
+}
+
+#[test]
+fn test_syscalls_against_ckb_script() {
+    let mock_tx = syscalls_tx();
+    let debug_output = Arc::new(Mutex::new(Vec::new()));
+    verify_tx_with_options(
+        &mock_tx,
+        MAX_CYCLES,
+        100_000,
+        300_000,
+        VerifyOptions {
+            debug_output: Some(debug_output.clone()),
+            ..Default::default()
+        },
+    )
+    .expect("verify");
+    let messages: Vec<String> = debug_output
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|debug| debug.message.clone())
+        .collect();
+
+    let (result, expected) = verify_tx_with_ckb_script(&mock_tx, MAX_CYCLES);
+    result.expect("verify with ckb-script");
+    assert_eq!(messages, expected);
+
+    // Make sure the transaction covers both success & error paths
+    for prefix in [
+        "load_header source 1 index 0 field 0: 0,",
+        "load_block_extension source 4 index 0 field 0: 0,",
+        "load_cell_data source 3 index 1 field 0: 0,",
+        "load_witness source 2 index 1 field 0: 0,",
+        // INDEX_OUT_OF_BOUND
+        "load_cell source 1 index 1 field 0: 1,",
+        // Header of cell dep 0 is not in header deps: ITEM_MISSING
+        "load_header source 3 index 0 field 0: 2,",
+        "exec index: 1",
+        // SLICE_OUT_OF_BOUND
+        "exec bounds: 3",
+        "exec witness: 1",
+        "exec argv: a bc",
+    ] {
+        assert!(
+            messages.iter().any(|message| message.starts_with(prefix)),
+            "missing {}",
+            prefix
+        );
+    }
+}
+
+#[test]
+fn test_limits() {
+    // The scenario burns cycles between checks, so all limits are hit
+    // after the scheduler has been suspended & resumed.
+    let result = run_scenario(
+        "limits",
+        VerifyOptions {
+            limits: Limits {
+                max_spawns: 4,
+                max_pipes_per_vm: 6,
+                max_spawn_depth: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    let stats = &result.groups[0].vm_stats;
+    assert_eq!(stats.len(), 5);
+    assert!(stats.iter().all(|stats| stats.exit_code == Some(0)));
+}
+
+#[test]
+fn test_scheduling_policy_select() {
+    let vm_cycles: BTreeMap<VmId, Cycle> = [(1, 500), (2, 100), (3, 100), (4, 300)]
+        .into_iter()
+        .collect();
+    let context = |last_run_vm| SchedulingContext {
+        last_run_vm,
+        vm_cycles: &vm_cycles,
+    };
+    let runnable = [1, 2, 3, 4];
+
+    assert_eq!(HighestIdFirst.select(&runnable, &context(None)), 4);
+    assert_eq!(HighestIdFirst.select(&runnable, &context(Some(4))), 4);
+    assert_eq!(HighestIdFirst.select(&[1, 3], &context(Some(4))), 3);
+
+    assert_eq!(RoundRobin.select(&runnable, &context(None)), 1);
+    assert_eq!(RoundRobin.select(&runnable, &context(Some(2))), 3);
+    // The last run VM does not need to be runnable
+    assert_eq!(RoundRobin.select(&[1, 4], &context(Some(2))), 4);
+    // Wrapping around
+    assert_eq!(RoundRobin.select(&runnable, &context(Some(4))), 1);
+    assert_eq!(RoundRobin.select(&[2, 3], &context(Some(5))), 2);
+
+    // 2 & 3 consume the same cycles, the smaller ID wins
+    assert_eq!(CycleFair.select(&runnable, &context(None)), 2);
+    assert_eq!(CycleFair.select(&[1, 3, 4], &context(Some(3))), 3);
+    // VMs that have not run yet have consumed 0 cycles
+    assert_eq!(CycleFair.select(&[1, 2, 6], &context(None)), 6);
+
+    for policy in [
+        Arc::new(HighestIdFirst) as Arc<dyn SchedulingPolicy>,
+        Arc::new(RoundRobin),
+        Arc::new(CycleFair),
+    ] {
+        assert_eq!(
+            builtin_scheduling_policy(policy.id()).map(|p| p.id()),
+            Some(policy.id())
+        );
+    }
+    assert!(builtin_scheduling_policy(255).is_none());
+}
+
+#[test]
+fn test_scheduling_policy_dag() {
+    let data = generate_data_graph(8, 20, 40, 3).expect("generate dag");
+    let mock_tx = build_mock_tx(18, test_program(), data);
+    for policy in [
+        Arc::new(HighestIdFirst) as Arc<dyn SchedulingPolicy>,
+        Arc::new(RoundRobin),
+        Arc::new(CycleFair),
+    ] {
+        let run = |cycles_per_iterate, cycles_per_suspend| {
+            verify_tx_with_options(
+                &mock_tx,
+                MAX_CYCLES,
+                cycles_per_iterate,
+                cycles_per_suspend,
+                VerifyOptions {
+                    scheduling_policy: policy.clone(),
+                    time_slice: Some(50_000),
+                    ..Default::default()
+                },
+            )
+            .expect("verify")
+        };
+        // The policy is restored when resuming, splitting execution
+        // differently does not change the result.
+        assert_eq!(
+            run(CYCLES_PER_ITERATE, CYCLES_PER_SUSPEND),
+            run(CYCLES_PER_ITERATE / 7, CYCLES_PER_SUSPEND / 3),
+            "policy {}",
+            policy.id()
+        );
+    }
+}
+
+#[test]
+fn test_scheduling_policy_mismatch() {
+    let data = generate_data_graph(9, 5, 10, 3).expect("generate dag");
+    let mock_tx = build_mock_tx(19, test_program(), data);
+    let group = script_groups(&mock_tx).remove(0);
+    let mut scheduler = Scheduler::new(
+        group.tx_data.clone(),
+        group.version,
+        Arc::new(DefaultCostSchedule),
+    )
+    .with_scheduling_policy(Arc::new(RoundRobin));
+    assert!(matches!(
+        scheduler.run(RunMode::LimitCycles(100_000)),
+        Ok(RunResult::Exhausted { .. })
+    ));
+
+    // The policy is fixed once the root VM boots
+    scheduler = scheduler.with_scheduling_policy(Arc::new(CycleFair));
+    assert!(matches!(
+        scheduler.run(RunMode::LimitCycles(100_000)),
+        Err(SchedulerError::SchedulingPolicyMismatch {
+            expected: 1,
+            actual: 2
+        })
+    ));
+
+    // Builtin policies are restored when resuming, setting a different
+    // one after resuming is rejected as well.
+    scheduler = scheduler.with_scheduling_policy(Arc::new(RoundRobin));
+    assert!(scheduler.run(RunMode::LimitCycles(100_000)).is_ok());
+    let state = scheduler.suspend().expect("suspend");
+    let resume = |state| {
+        Scheduler::resume(
+            group.tx_data.clone(),
+            group.version,
+            Arc::new(DefaultCostSchedule),
+            state,
+        )
+        .expect("resume")
+    };
+    assert!(resume(state.clone())
+        .run(RunMode::LimitCycles(100_000))
+        .is_ok());
+    assert!(matches!(
+        resume(state)
+            .with_scheduling_policy(Arc::new(HighestIdFirst))
+            .run(RunMode::LimitCycles(100_000)),
+        Err(SchedulerError::SchedulingPolicyMismatch {
+            expected: 1,
+            actual: 0
+        })
+    ));
+}
+
+#[test]
+fn test_time_slice() {
+    // 3 VMs spin at the same time while only 2 can be instantiated, so each
+    // turn starts with swapping, which costs more than the time slice.
+    let time_slice = 2_000;
+    let recorder = Arc::new(Mutex::new(TraceRecorder::default()));
+    let result = run_scenario(
+        "time_slice",
+        VerifyOptions {
+            scheduling_policy: Arc::new(RoundRobin),
+            time_slice: Some(time_slice),
+            max_instantiated_vms: Some(2),
+            observer: Some(recorder.clone()),
+            ..Default::default()
+        },
+    );
+    let group = &result.groups[0];
+    assert!(group.swap_count > 0);
+    let mut runs: BTreeMap<VmId, Cycle> = BTreeMap::new();
+    for event in &recorder.lock().unwrap().trace().events {
+        if let TraceEvent::Run { vm_id, .. } = event {
+            *runs.entry(*vm_id).or_default() += 1;
+        }
+    }
+    for stats in &group.vm_stats[1..] {
+        assert!(stats.swap_cycles > 0);
+        // Spinning VMs are preempted in turn
+        assert!(runs[&stats.vm_id] > 20, "VM {}", stats.vm_id);
+        // Swapping cycles are not counted against the time slice, all
+        // turns but the first & the last execute a full time slice.
+        assert!(
+            runs[&stats.vm_id] <= stats.execution_cycles / time_slice + 2,
+            "VM {}",
+            stats.vm_id
+        );
+    }
+}
+
+#[test]
+fn test_eviction_strategy_select() {
+    let candidate = |vm_id, last_run, dirty_bytes| EvictionCandidate {
+        vm_id,
+        last_run,
+        dirty_bytes,
+    };
+    let candidates = [
+        candidate(1, 5, 8192),
+        candidate(2, 1, 12288),
+        candidate(3, 9, 4096),
+        candidate(4, 1, 4096),
+    ];
+    assert_eq!(LowestIdFirst.select(&candidates, 1), vec![1]);
+    assert_eq!(LowestIdFirst.select(&candidates, 2), vec![1, 2]);
+    // Ties are broken by picking the smallest ID
+    assert_eq!(LeastRecentlyRun.select(&candidates, 1), vec![2]);
+    assert_eq!(LeastRecentlyRun.select(&candidates, 3), vec![2, 4, 1]);
+    assert_eq!(SmallestSnapshotFirst.select(&candidates, 1), vec![3]);
+    assert_eq!(SmallestSnapshotFirst.select(&candidates, 3), vec![3, 4, 1]);
+
+    // Only SmallestSnapshotFirst requires scanning memory pages
+    assert!(!LowestIdFirst.needs_dirty_bytes());
+    assert!(!LeastRecentlyRun.needs_dirty_bytes());
+    assert!(SmallestSnapshotFirst.needs_dirty_bytes());
+}
+
+#[test]
+fn test_eviction_strategies() {
+    // In the eviction scenario, each strategy picks a different VM when the
+    // 4th VM boots. Full suspends are disabled so only evictions show up as
+    // suspend events.
+    for (strategy, expected) in [
+        (Arc::new(LowestIdFirst) as Arc<dyn EvictionStrategy>, 0),
+        (Arc::new(LeastRecentlyRun), 1),
+        (Arc::new(SmallestSnapshotFirst), 2),
+    ] {
+        let recorder = Arc::new(Mutex::new(EventRecorder::default()));
+        verify_tx_with_options(
+            &scenario_tx("eviction"),
+            MAX_CYCLES,
+            CYCLES_PER_ITERATE,
+            Cycle::MAX,
+            VerifyOptions {
+                max_instantiated_vms: Some(3),
+                eviction_strategy: strategy.clone(),
+                observer: Some(recorder.clone()),
+                ..Default::default()
+            },
+        )
+        .expect("verify");
+        let evicted = recorder
+            .lock()
+            .unwrap()
+            .events
+            .iter()
+            .find_map(|event| match event {
+                Event::Suspend(vm_id, _) => Some(*vm_id),
+                _ => None,
+            });
+        assert_eq!(evicted, Some(expected), "strategy {}", strategy.id());
+    }
+}
+
+#[test]
+fn test_resume_invalid_max_instantiated_vms() {
+    let data = generate_data_graph(10, 5, 10, 3).expect("generate dag");
+    let mock_tx = build_mock_tx(20, test_program(), data);
+    let group = script_groups(&mock_tx).remove(0);
+    let mut scheduler = Scheduler::new(
+        group.tx_data.clone(),
+        group.version,
+        Arc::new(DefaultCostSchedule),
+    )
+    .with_max_instantiated_vms(2)
+    .expect("max instantiated VMs");
+    assert!(scheduler.run(RunMode::LimitCycles(300_000)).is_ok());
+    let state = scheduler.suspend().expect("suspend");
+    assert!(!state.instantiated_vms.is_empty());
+    let resume = |state| {
+        Scheduler::resume(
+            group.tx_data.clone(),
+            group.version,
+            Arc::new(DefaultCostSchedule),
+            state,
+        )
+    };
+    for max_instantiated_vms in [0, 1] {
+        let mut invalid = state.clone();
+        invalid.max_instantiated_vms = max_instantiated_vms;
+        invalid
+            .instantiated_vms
+            .truncate(max_instantiated_vms as usize);
+        assert!(matches!(
+            resume(invalid),
+            Err(SchedulerError::InvalidState(_))
+        ));
+    }
+    // More VMs are instantiated than allowed
+    let mut invalid = state.clone();
+    invalid.max_instantiated_vms = 2;
+    invalid.instantiated_vms = vec![state.instantiated_vms[0]; 3];
+    assert!(matches!(
+        resume(invalid),
+        Err(SchedulerError::InvalidState(_))
+    ));
+    assert!(resume(state).is_ok());
+}
+
+#[test]
+fn test_invalid_max_instantiated_vms() {
+    let data = generate_data_graph(10, 5, 10, 3).expect("generate dag");
+    let mock_tx = build_mock_tx(20, test_program(), data);
+    let group = script_groups(&mock_tx).remove(0);
+    for max_instantiated_vms in [0, 1] {
+        let scheduler = Scheduler::new(
+            group.tx_data.clone(),
+            group.version,
+            Arc::new(DefaultCostSchedule),
+        );
+        assert!(matches!(
+            scheduler.with_max_instantiated_vms(max_instantiated_vms),
+            Err(SchedulerError::InvalidState(_))
+        ));
+        let result = verify_tx_with_options(
+            &mock_tx,
+            MAX_CYCLES,
+            CYCLES_PER_ITERATE,
+            CYCLES_PER_SUSPEND,
+            VerifyOptions {
+                max_instantiated_vms: Some(max_instantiated_vms),
+                ..Default::default()
+            },
+        );
+        assert!(result.is_err());
+    }
+}
+
+#[test]
+fn test_script_version_mismatch() {
+    let data = generate_data_graph(11, 5, 10, 3).expect("generate dag");
+    let mock_tx = build_mock_tx(21, test_program(), data);
+    let group = script_groups(&mock_tx).remove(0);
+    let mut scheduler = Scheduler::new(
+        group.tx_data.clone(),
+        group.version,
+        Arc::new(DefaultCostSchedule),
+    );
+    assert!(scheduler.run(RunMode::LimitCycles(100_000)).is_ok());
+    let state = scheduler.suspend().expect("suspend");
+    assert_eq!(state.script_version, group.version as u8);
+    let other = if group.version == ScriptVersion::V1 {
+        ScriptVersion::V2
+    } else {
+        ScriptVersion::V1
+    };
+    let resume = |version| {
+        Scheduler::resume(
+            group.tx_data.clone(),
+            version,
+            Arc::new(DefaultCostSchedule),
+            state.clone(),
+        )
+    };
+    match resume(other) {
+        Err(SchedulerError::ScriptVersionMismatch { expected, actual }) => {
+            assert_eq!(expected, group.version as u8);
+            assert_eq!(actual, other as u8);
+        }
+        _ => panic!("script version mismatch is not detected"),
+    }
+    assert!(resume(group.version).is_ok());
+}
+
+#[test]
+fn test_default_cost_schedule() {
+    // Pipe transfers and swaps cost the same as they did before cost
+    // schedules became pluggable.
+    let schedule = DefaultCostSchedule;
+    for (bytes, cycles) in [(0, 800), (1, 801), (4, 801), (5, 802), (4096, 1824)] {
+        assert_eq!(schedule.pipe_transfer_read_cycles(bytes), cycles);
+        assert_eq!(schedule.pipe_transfer_write_cycles(bytes), 800);
+        assert_eq!(schedule.swap_cycles(bytes), cycles);
+    }
+    assert_eq!(schedule.spawn_base_cycles(), 100_000);
+    assert_eq!(schedule.spawn_argv_cycles(10), 3);
+    assert_eq!(schedule.spawn_pipe_cycles(2), 4);
+    assert_eq!(schedule.select_cycles(3), 806);
+    assert_eq!(schedule.join_any_cycles(0), 800);
+    assert_eq!(schedule.instance_id_cycles(), 0);
+    assert_eq!(schedule.parent_id_cycles(), 0);
+}
+
+#[test]
+fn test_deadlock_report() {
+    // VM 0 joins VM 1, VM 1 & VM 2 are reading from each other
+    let (p1, p2, _) = PipeId::create(2);
+    let (p3, p4, _) = PipeId::create(4);
+    let mut states = BTreeMap::new();
+    states.insert(
+        0,
+        VmState::Join {
+            target_vm_id: 1,
+            exit_code_addr: 0,
+        },
+    );
+    for (vm_id, pipe) in [(1, p1), (2, p3)] {
+        states.insert(
+            vm_id,
+            VmState::WaitForRead {
+                pipe,
+                length: 1,
+                buffer_addr: 0,
+                length_addr: 0,
+            },
+        );
+    }
+    let mut pipes = HashMap::new();
+    pipes.insert(p1, 1);
+    pipes.insert(p4, 1);
+    pipes.insert(p2, 2);
+    pipes.insert(p3, 2);
+
+    let report = DeadlockReport::build(&states, &pipes);
+    let waiting_on: Vec<_> = report.vms.iter().map(|vm| vm.waiting_on.clone()).collect();
+    assert_eq!(waiting_on, vec![vec![1], vec![2], vec![1]]);
+    assert_eq!(report.cycle, vec![1, 2]);
+    assert!(report.to_dot().contains("vm2 -> vm1 [color=red];"));
+    assert!(report.to_dot().contains("vm0 -> vm1;"));
+}
+
+#[test]
+fn test_spawn_tree() {
+    let tree: SpawnTree = vec![(1, 0), (2, 0), (3, 1), (4, 3)].into_iter().collect();
+    assert_eq!(tree.parent(0), None);
+    assert_eq!(tree.parent(4), Some(3));
+    assert_eq!(tree.children(0), vec![1, 2]);
+    assert!(tree.children(4).is_empty());
+    assert!(tree.is_ancestor(0, 4));
+    assert!(tree.is_ancestor(1, 4));
+    assert!(!tree.is_ancestor(2, 4));
+    assert!(!tree.is_ancestor(4, 4));
+}
+
+#[test]
+fn test_data_piece_id_sources() {
+    assert_eq!(DataPieceId::try_from((3, 1)), Ok(DataPieceId::CellDep(1)));
+    // Only spawn & exec load programs from witnesses
+    assert!(DataPieceId::try_from((0x0200000000000001, 2)).is_err());
+    assert!(DataPieceId::try_from((0x0300000000000001, 0)).is_err());
+    assert_eq!(
+        DataPieceId::program(0x0200000000000001, 2),
+        Ok(DataPieceId::Witness(2))
+    );
+    // Same as load_witness, output source loads the witness at the index
+    assert_eq!(
+        DataPieceId::program(0x0200000000000002, 2),
+        Ok(DataPieceId::Witness(2))
+    );
+    assert_eq!(
+        DataPieceId::program(0x0300000000000001, 0),
+        Ok(DataPieceId::GroupInputWitness(0))
+    );
+    assert_eq!(
+        DataPieceId::program(0x0300000000000002, 1),
+        Ok(DataPieceId::GroupOutputWitness(1))
+    );
+    assert_eq!(DataPieceId::program(3, 1), Ok(DataPieceId::CellDep(1)));
+    assert_eq!(
+        DataPieceId::witness(0x0100000000000002, 1),
+        Ok(DataPieceId::GroupOutputWitness(1))
+    );
+    // Witness flag cannot be combined with cell deps
+    assert!(DataPieceId::program(0x0200000000000003, 0).is_err());
+    assert!(DataPieceId::witness(3, 0).is_err());
+}
+
+#[test]
+fn test_tx_data_load_witness() {
+    let mut mock_tx = build_mock_tx(0, test_program(), dag::Data::default());
+    let witnesses: Vec<Bytes> = vec![
+        Bytes::from_static(b"input witness"),
+        Bytes::from_static(b"extra witness"),
+    ];
+    mock_tx.tx = mock_tx
+        .tx
+        .as_builder()
+        .witnesses(
+            ckb_types::packed::BytesVec::new_builder()
+                .set(witnesses.iter().map(|w| w.pack()).collect())
+                .build(),
+        )
+        .build();
+    // The lock script group has the only input, and no outputs
+    let tx_data = script_groups(&mock_tx).remove(0).tx_data;
+    let load = |id: DataPieceId| tx_data.load_data(&id, 0, 0);
+
+    for (id, expected) in [
+        (DataPieceId::Witness(0), &witnesses[0]),
+        (DataPieceId::Witness(1), &witnesses[1]),
+        (DataPieceId::GroupInputWitness(0), &witnesses[0]),
+    ] {
+        let (data, length) = load(id).expect("load");
+        assert_eq!(&data, expected);
+        assert_eq!(length, expected.len() as u64);
+    }
+    let (data, length) = tx_data
+        .load_data(&DataPieceId::Witness(1), 6, 3)
+        .expect("load");
+    assert_eq!(&data[..], b"wit");
+    // Length of the witness from the offset
+    assert_eq!(length, witnesses[1].len() as u64 - 6);
+
+    for id in [
+        DataPieceId::Witness(2),
+        DataPieceId::GroupInputWitness(1),
+        DataPieceId::GroupOutputWitness(0),
+    ] {
+        assert!(is_index_out_of_bound(&load(id).unwrap_err()));
+    }
+}
+
+#[test]
+fn test_suspended_state_encoding() {
+    let (p1, p2, next_pipe_slot) = PipeId::create(2);
+    let (p3, _, next_pipe_slot) = PipeId::create(next_pipe_slot);
+    let snapshot = Snapshot2 {
+        pages_from_source: vec![
+            (0x10000, 1, DataPieceId::CellDep(2), 0, 4096),
+            (0x11000, 1, DataPieceId::Script, 0, 4096),
+        ],
+        dirty_pages: vec![(0x20000, 0, vec![7; 4096])],
+        version: 2,
+        registers: [3; 32],
+        pc: 0x10010,
+        cycles: 1000,
+        max_cycles: 2000,
+    };
+    let state = FullSuspendedState {
+        total_cycles: 5000,
+        next_vm_id: 4,
+        next_pipe_slot,
+        vms: vec![
+            (
+                0,
+                VmState::JoinAny {
+                    target_vm_ids: vec![2, 3],
+                    instance_id_addr: 0x30000,
+                    exit_code_addr: 0x30008,
+                },
+                snapshot.clone(),
+            ),
+            (
+                2,
+                VmState::WaitForRead {
+                    pipe: p1,
+                    length: 16,
+                    buffer_addr: 0x40000,
+                    length_addr: 0x40010,
+                },
+                snapshot.clone(),
+            ),
+            (
+                3,
+                VmState::WaitForAny {
+                    pipes: vec![p3, p2],
+                    ready_pipe_addr: 0x50000,
+                },
+                snapshot,
+            ),
+        ],
+        pipes: vec![(p1, 2), (p2, 0)],
+        terminated_vms: vec![(1, -1)],
+        instantiated_vms: vec![2],
+        spawn_depths: vec![(2, 1)],
+        limits: Limits {
+            max_spawns: 10,
+            max_spawn_depth: 3,
+            ..Default::default()
+        },
+        script_version: 2,
+        scheduling_policy: 1,
+        last_run_vm: Some(2),
+        interrupted_vm: None,
+        vm_cycles: vec![(0, 3000), (2, 2000)],
+        swap_count: 5,
+        time_slice: Some(100_000),
+        slice_consumed_cycles: 2000,
+        max_instantiated_vms: 4,
+        eviction_strategy: 2,
+        run_count: 10,
+        last_run_times: vec![(0, 8), (2, 9)],
+        parents: vec![(1, 0), (2, 0), (3, 2)],
+        reaped_vms: vec![1],
+        vm_stats: vec![
+            VmStats::new(0, DataPieceId::Program),
+            VmStats {
+                exit_code: Some(-1),
+                bytes_written: 64,
+                ..VmStats::new(1, DataPieceId::GroupInputWitness(3))
+            },
+            VmStats {
+                execution_cycles: 1500,
+                syscall_cycles: 300,
+                swap_cycles: 200,
+                bytes_read: 64,
+                unpaid_cycles: 100,
+                ..VmStats::new(2, DataPieceId::Program)
+            },
+            VmStats::new(3, DataPieceId::CellDep(2)),
+        ],
+        debug_sink: DebugSink::Capture,
+        debug_output: vec![DebugMessage {
+            vm_id: 2,
+            cycles: 4500,
+            message: "Read 64 bytes".to_string(),
+        }],
+    };
+
+    let encoded = state.encode();
+    assert_eq!(state.size(), encoded.len() as u64);
+    let vm_sizes = state.vm_sizes();
+    assert_eq!(vm_sizes[0].dirty_pages, 4 + 8 + 1 + 4 + 4096);
+    assert_eq!(
+        vm_sizes[0].source_pages,
+        4 + (8 + 1 + 5 + 8 + 8) + (8 + 1 + 1 + 8 + 8)
+    );
+    assert_eq!(vm_sizes[0].registers, 33 * 8);
+    let decoded = FullSuspendedState::decode(&encoded).expect("decode");
+    assert_eq!(decoded.encode(), encoded);
+    assert_eq!(decoded.vms[0].1, state.vms[0].1);
+    assert_eq!(decoded.vms[1].1, state.vms[1].1);
+    assert_eq!(decoded.vms[2].1, state.vms[2].1);
+    assert_eq!(decoded.terminated_vms, vec![(1, -1)]);
+    assert_eq!(decoded.limits, state.limits);
+    assert_eq!(decoded.parents, state.parents);
+    assert_eq!(decoded.vm_stats, state.vm_stats);
+    assert_eq!(decoded.debug_sink, state.debug_sink);
+    assert_eq!(decoded.debug_output, state.debug_output);
+
+    let mut corrupted = encoded.to_vec();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert!(FullSuspendedState::decode(&corrupted).is_err());
+
+    // States with valid encoding but broken invariants are rejected as well
+    let mut dangling_pipe = state.clone();
+    dangling_pipe.pipes.push((p3, 1));
+    assert!(FullSuspendedState::decode(&dangling_pipe.encode()).is_err());
+    let mut dangling_instantiated = state.clone();
+    dangling_instantiated.instantiated_vms.push(1);
+    assert!(FullSuspendedState::decode(&dangling_instantiated.encode()).is_err());
+    let mut duplicate_vm = state.clone();
+    duplicate_vm.vms.push(state.vms[1].clone());
+    assert!(FullSuspendedState::decode(&duplicate_vm.encode()).is_err());
+    let mut missing_root = state.clone();
+    missing_root.vms.remove(0);
+    missing_root.pipes.retain(|(_, id)| *id != 0);
+    assert!(matches!(
+        FullSuspendedState::decode(&missing_root.encode()),
+        Err(SchedulerError::Decode(_))
+    ));
+    for vm_id in [0, 1, 3] {
+        // VMs alive, terminated or instantiated must all have statistics
+        let mut missing_stats = state.clone();
+        missing_stats.instantiated_vms = vec![3];
+        missing_stats.vm_stats.retain(|stats| stats.vm_id != vm_id);
+        assert!(matches!(
+            FullSuspendedState::decode(&missing_stats.encode()),
+            Err(SchedulerError::Decode(_))
+        ));
+    }
+}
+
+// Sizes must agree with the encoded data for states taken from a real run
+#[test]
+fn test_suspended_state_sizes() {
+    let data = generate_data_graph(7, 10, 20, 3).expect("generate dag");
+    let mock_tx = build_mock_tx(17, test_program(), data);
+    let group = script_groups(&mock_tx).remove(0);
+    let mut scheduler = Scheduler::new(
+        group.tx_data.clone(),
+        group.version,
+        Arc::new(DefaultCostSchedule),
+    );
+    let mut suspends = 0;
+    while let RunResult::Exhausted { .. } =
+        scheduler.run(RunMode::LimitCycles(1_000_000)).expect("run")
+    {
+        let state = scheduler.suspend().expect("suspend");
+        assert_eq!(state.size(), state.encode().len() as u64);
+        // VM sizes add up to the size taken by VMs
+        let mut without_vms = state.clone();
+        without_vms.vms.clear();
+        assert_eq!(
+            without_vms.size() + state.vm_sizes().iter().map(|s| s.total()).sum::<u64>(),
+            state.size()
+        );
+        suspends += 1;
+        scheduler = Scheduler::resume(
+            group.tx_data.clone(),
+            group.version,
+            Arc::new(DefaultCostSchedule),
+            state,
+        )
+        .expect("resume");
+    }
+    assert!(suspends > 1);
+}
+
 proptest! {
     #[test]
     fn test_random_dag(
@@ -42,11 +1595,7 @@ proptest! {
         writes in 3u32..201u32,
     ) {
         let data = generate_data_graph(seed, spawns, writes, 3).expect("generate dag");
-        let program_path = match std::env::var("TEST_BIN") {
-            Ok(path) => path,
-            Err(_) => "./test_bin".to_string(),
-        };
-        let program = std::fs::read(program_path).expect("read").into();
+        let program = test_program();
 
         let mock_tx = build_mock_tx(seed.wrapping_add(10), program, data);
 
@@ -0,0 +1,294 @@
+// Deterministic execution traces
+//
+// A trace records all scheduling decisions & operations performed by a
+// scheduler. Re-executing the same transaction must produce the identical
+// trace, no matter which node version or ckb-vm version is used, and no
+// matter how execution is split into multiple runs via cycle budget, pause
+// signal, or suspending & resuming.
+
+use crate::{
+    error::SchedulerError,
+    observer::SchedulerObserver,
+    serialization::{decode_error, Reader, Writer},
+    types::{Message, PipeId, VmId},
+};
+use ckb_types::core::Cycle;
+use ckb_vm::bytes::Bytes;
+use std::fmt;
+
+/// Current version of the trace encoding format, this must be bumped
+/// whenever the format changes.
+pub const TRACE_VERSION: u32 = 2;
+
+/// A single scheduling decision or operation in a trace
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// A VM runs for `consumed_cycles` cycles. A VM interrupted by cycle
+    /// budget or pause signal continues to run in the next invocation,
+    /// consecutive runs of the same VM are merged into a single event.
+    Run { vm_id: VmId, consumed_cycles: Cycle },
+    /// A VM posts a message to the scheduler via syscalls
+    Message(Message),
+    /// Data transferred from `write_pipe` owned by `write_vm`, to the other
+    /// end of the pipe owned by `read_vm`.
+    Transfer {
+        write_vm: VmId,
+        write_pipe: PipeId,
+        read_vm: VmId,
+        bytes: u64,
+    },
+    /// A VM terminates, either by exiting, or by getting killed
+    Terminate { vm_id: VmId, exit_code: i8 },
+}
+
+/// A sequence of trace events, in the order they happen
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub events: Vec<TraceEvent>,
+}
+
+/// The first point where 2 traces diverge
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceDivergence {
+    /// Index of the first differing event
+    pub index: usize,
+    /// Event from the expected trace, None if the expected trace ends here
+    pub expected: Option<TraceEvent>,
+    /// Event from the actual trace, None if the actual trace ends here
+    pub actual: Option<TraceEvent>,
+}
+
+impl fmt::Display for TraceDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Traces diverge at event #{}, expected: {:?}, actual: {:?}",
+            self.index, self.expected, self.actual
+        )
+    }
+}
+
+impl Trace {
+    fn push(&mut self, event: TraceEvent) {
+        if let (
+            Some(TraceEvent::Run {
+                vm_id: last_vm_id,
+                consumed_cycles: last_cycles,
+            }),
+            TraceEvent::Run {
+                vm_id,
+                consumed_cycles,
+            },
+        ) = (self.events.last_mut(), &event)
+        {
+            if *last_vm_id == *vm_id {
+                *last_cycles = last_cycles.saturating_add(*consumed_cycles);
+                return;
+            }
+        }
+        self.events.push(event);
+    }
+
+    /// Compare with an actual trace, returns the first point where the
+    /// actual trace diverges from current trace.
+    pub fn first_divergence(&self, actual: &Trace) -> Option<TraceDivergence> {
+        let length = std::cmp::max(self.events.len(), actual.events.len());
+        (0..length).find_map(|index| {
+            let expected = self.events.get(index);
+            let actual = actual.events.get(index);
+            if expected == actual {
+                None
+            } else {
+                Some(TraceDivergence {
+                    index,
+                    expected: expected.cloned(),
+                    actual: actual.cloned(),
+                })
+            }
+        })
+    }
+
+    /// Encode the trace into a compact binary format, integers are encoded
+    /// in little endian, with the following layout:
+    ///
+    /// | version(u32) | event count(u32) | events |
+    pub fn encode(&self) -> Bytes {
+        let mut writer = Writer::default();
+        writer.u32(TRACE_VERSION);
+        writer.length(self.events.len());
+        for event in &self.events {
+            match event {
+                TraceEvent::Run {
+                    vm_id,
+                    consumed_cycles,
+                } => {
+                    writer.u8(0);
+                    writer.u64(*vm_id);
+                    writer.u64(*consumed_cycles);
+                }
+                TraceEvent::Message(message) => {
+                    writer.u8(1);
+                    writer.message(message);
+                }
+                TraceEvent::Transfer {
+                    write_vm,
+                    write_pipe,
+                    read_vm,
+                    bytes,
+                } => {
+                    writer.u8(2);
+                    writer.u64(*write_vm);
+                    writer.u64(write_pipe.0);
+                    writer.u64(*read_vm);
+                    writer.u64(*bytes);
+                }
+                TraceEvent::Terminate { vm_id, exit_code } => {
+                    writer.u8(3);
+                    writer.u64(*vm_id);
+                    writer.u8(*exit_code as u8);
+                }
+            }
+        }
+        writer.0.into()
+    }
+
+    /// Decode a trace previously encoded via `encode`
+    pub fn decode(data: &[u8]) -> Result<Self, SchedulerError> {
+        let mut reader = Reader(data);
+        let version = reader.u32()?;
+        if version != TRACE_VERSION {
+            return Err(decode_error(&format!(
+                "unsupported trace version {}, expected {}",
+                version, TRACE_VERSION
+            )));
+        }
+        let events = reader.list(|r| {
+            Ok(match r.u8()? {
+                0 => TraceEvent::Run {
+                    vm_id: r.u64()?,
+                    consumed_cycles: r.u64()?,
+                },
+                1 => TraceEvent::Message(r.message()?),
+                2 => TraceEvent::Transfer {
+                    write_vm: r.u64()?,
+                    write_pipe: PipeId(r.u64()?),
+                    read_vm: r.u64()?,
+                    bytes: r.u64()?,
+                },
+                3 => TraceEvent::Terminate {
+                    vm_id: r.u64()?,
+                    exit_code: r.u8()? as i8,
+                },
+                tag => return Err(decode_error(&format!("invalid TraceEvent tag {}", tag))),
+            })
+        })?;
+        if !reader.0.is_empty() {
+            return Err(decode_error("trailing data"));
+        }
+        Ok(Self { events })
+    }
+}
+
+/// An observer recording a trace of scheduler activities. When execution
+/// is split into multiple schedulers via suspending & resuming, the same
+/// recorder must be set on all of them.
+#[derive(Clone, Debug, Default)]
+pub struct TraceRecorder {
+    trace: Trace,
+}
+
+impl TraceRecorder {
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    pub fn into_trace(self) -> Trace {
+        self.trace
+    }
+}
+
+impl SchedulerObserver for TraceRecorder {
+    fn on_vm_run(&mut self, _cycles: Cycle, vm_id: VmId, consumed_cycles: Cycle) {
+        self.trace.push(TraceEvent::Run {
+            vm_id,
+            consumed_cycles,
+        });
+    }
+
+    fn on_message(&mut self, _cycles: Cycle, message: &Message) {
+        self.trace.push(TraceEvent::Message(message.clone()));
+    }
+
+    fn on_pipe_transfer(
+        &mut self,
+        _cycles: Cycle,
+        write_vm: VmId,
+        write_pipe: PipeId,
+        read_vm: VmId,
+        bytes: u64,
+    ) {
+        self.trace.push(TraceEvent::Transfer {
+            write_vm,
+            write_pipe,
+            read_vm,
+            bytes,
+        });
+    }
+
+    fn on_vm_terminate(&mut self, _cycles: Cycle, vm_id: VmId, exit_code: i8, _vm_cycles: Cycle) {
+        self.trace.push(TraceEvent::Terminate { vm_id, exit_code });
+    }
+}
+
+/// An observer checking that re-executing a transaction produces the
+/// identical trace as a previously recorded one.
+#[derive(Clone, Debug)]
+pub struct TraceReplayer {
+    expected: Trace,
+    recorder: TraceRecorder,
+}
+
+impl TraceReplayer {
+    pub fn new(expected: Trace) -> Self {
+        Self {
+            expected,
+            recorder: TraceRecorder::default(),
+        }
+    }
+
+    /// Check the trace recorded so far against the expected trace, this
+    /// shall be called after execution finishes.
+    pub fn finish(&self) -> Result<(), TraceDivergence> {
+        match self.expected.first_divergence(self.recorder.trace()) {
+            Some(divergence) => Err(divergence),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SchedulerObserver for TraceReplayer {
+    fn on_vm_run(&mut self, cycles: Cycle, vm_id: VmId, consumed_cycles: Cycle) {
+        self.recorder.on_vm_run(cycles, vm_id, consumed_cycles);
+    }
+
+    fn on_message(&mut self, cycles: Cycle, message: &Message) {
+        self.recorder.on_message(cycles, message);
+    }
+
+    fn on_pipe_transfer(
+        &mut self,
+        cycles: Cycle,
+        write_vm: VmId,
+        write_pipe: PipeId,
+        read_vm: VmId,
+        bytes: u64,
+    ) {
+        self.recorder
+            .on_pipe_transfer(cycles, write_vm, write_pipe, read_vm, bytes);
+    }
+
+    fn on_vm_terminate(&mut self, cycles: Cycle, vm_id: VmId, exit_code: i8, vm_cycles: Cycle) {
+        self.recorder
+            .on_vm_terminate(cycles, vm_id, exit_code, vm_cycles);
+    }
+}
@@ -1,15 +1,19 @@
 // Core data structures here
 
+use crate::error::index_out_of_bound_error;
 use ckb_script::ScriptGroup;
 use ckb_traits::{CellDataProvider, ExtensionProvider, HeaderProvider};
-use ckb_types::core::{cell::ResolvedTransaction, Cycle};
+use ckb_types::{
+    core::{cell::ResolvedTransaction, Cycle},
+    prelude::Entity,
+};
 use ckb_vm::{
     bytes::Bytes,
     machine::Pause,
     snapshot2::{DataSource, Snapshot2},
-    Error, RISCV_GENERAL_REGISTER_NUMBER,
+    Error,
 };
-use std::mem::size_of;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 pub type VmId = u64;
@@ -60,9 +64,18 @@ pub enum VmState {
         buffer_addr: u64,
         length_addr: u64,
     },
+    WaitForAny {
+        pipes: Vec<PipeId>,
+        ready_pipe_addr: u64,
+    },
+    JoinAny {
+        target_vm_ids: Vec<VmId>,
+        instance_id_addr: u64,
+        exit_code_addr: u64,
+    },
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct SpawnArgs {
     pub data_piece_id: DataPieceId,
     pub offset: u64,
@@ -72,33 +85,61 @@ pub struct SpawnArgs {
     pub instance_id_addr: u64,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExecArgs {
+    pub data_piece_id: DataPieceId,
+    pub offset: u64,
+    pub length: u64,
+    pub argv: Vec<Bytes>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct JoinArgs {
     pub target_id: VmId,
     pub exit_code_addr: u64,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JoinAnyArgs {
+    // An empty list means all VMs spawned by the caller that are not joined yet
+    pub target_ids: Vec<VmId>,
+    pub instance_id_addr: u64,
+    pub exit_code_addr: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PipeArgs {
     pub pipe1_addr: u64,
     pub pipe2_addr: u64,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PipeIoArgs {
     pub pipe: PipeId,
     pub length: u64,
     pub buffer_addr: u64,
     pub length_addr: u64,
+    pub nonblocking: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelectArgs {
+    pub pipes: Vec<PipeId>,
+    pub ready_pipe_addr: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Message {
     Spawn(VmId, SpawnArgs),
     Join(VmId, JoinArgs),
     Pipe(VmId, PipeArgs),
     PipeRead(VmId, PipeIoArgs),
     PipeWrite(VmId, PipeIoArgs),
+    Close(VmId, PipeId),
+    Select(VmId, SelectArgs),
+    JoinAny(VmId, JoinAnyArgs),
+    Kill(VmId, VmId),
+    Exec(VmId, ExecArgs),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -109,8 +150,47 @@ pub enum DataPieceId {
     CellDep(u32),
     GroupInput(u32),
     GroupOutput(u32),
+    // Witnesses are indexed the same way as load_witness syscall: Witness
+    // uses the absolute witness index, while the group variants use the
+    // index of the input / output within current script group.
+    Witness(u32),
+    GroupInputWitness(u32),
+    GroupOutputWitness(u32),
+    // Script of current script group, as returned by load_script syscall
+    Script,
 }
 
+// Combined with a cell source in spawn syscall to load a witness instead
+const SOURCE_WITNESS_FLAG: u64 = 0x0200000000000000;
+
+impl DataPieceId {
+    /// Witness loaded the same way as load_witness syscall. Both input and
+    /// output sources load the witness at the absolute `index`, while group
+    /// sources use the index of the input / output within current script
+    /// group.
+    pub fn witness(source: u64, index: u64) -> Result<Self, String> {
+        let index: u32 =
+            u32::try_from(index).map_err(|e| format!("Error casting index to u32: {}", e))?;
+        match source {
+            1 | 2 => Ok(DataPieceId::Witness(index)),
+            0x0100000000000001 => Ok(DataPieceId::GroupInputWitness(index)),
+            0x0100000000000002 => Ok(DataPieceId::GroupOutputWitness(index)),
+            _ => Err(format!("Invalid witness source value: {:#x}", source)),
+        }
+    }
+
+    /// Program to load in spawn syscall: cell data, or a witness when the
+    /// witness flag(0x0200000000000000) is combined with the source.
+    pub fn program(source: u64, index: u64) -> Result<Self, String> {
+        if source & SOURCE_WITNESS_FLAG != 0 {
+            Self::witness(source & !SOURCE_WITNESS_FLAG, index)
+        } else {
+            Self::try_from((source, index))
+        }
+    }
+}
+
+/// Cell data sources, as accepted by load_cell_data syscall
 impl TryFrom<(u64, u64)> for DataPieceId {
     type Error = String;
 
@@ -129,9 +209,94 @@ impl TryFrom<(u64, u64)> for DataPieceId {
     }
 }
 
+/// Resource limits enforced on a single script. When a limit is reached,
+/// the offending syscall returns a designated error code to the VM instead
+/// of terminating the whole script. All limits are unlimited by default.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Max number of VMs that are alive at the same time, including root VM
+    pub max_live_vms: u64,
+    /// Max number of spawn syscalls that can succeed in a script
+    pub max_spawns: u64,
+    /// Max number of pipes a single VM can own
+    pub max_pipes_per_vm: u64,
+    /// Max number of pipes that are open at the same time in a script
+    pub max_pipes: u64,
+    /// Max spawn depth, root VM has a depth of 0, a VM spawned by root VM
+    /// has a depth of 1, and so on.
+    pub max_spawn_depth: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_live_vms: u64::MAX,
+            max_spawns: u64::MAX,
+            max_pipes_per_vm: u64::MAX,
+            max_pipes: u64::MAX,
+            max_spawn_depth: u64::MAX,
+        }
+    }
+}
+
+/// Spawn lineage of all VMs in a script. Root VM is the only VM without a
+/// parent, a VM stays in the tree after it terminates, so the lineage of
+/// live VMs is always complete.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpawnTree {
+    // Child VM ID -> parent VM ID
+    parents: BTreeMap<VmId, VmId>,
+}
+
+impl SpawnTree {
+    pub(crate) fn insert(&mut self, vm_id: VmId, parent: VmId) {
+        self.parents.insert(vm_id, parent);
+    }
+
+    /// The VM spawning `vm_id`, None for root VM or unknown VMs
+    pub fn parent(&self, vm_id: VmId) -> Option<VmId> {
+        self.parents.get(&vm_id).copied()
+    }
+
+    /// VMs spawned by `vm_id`, sorted by VM ID
+    pub fn children(&self, vm_id: VmId) -> Vec<VmId> {
+        self.parents
+            .iter()
+            .filter(|(_, parent)| **parent == vm_id)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Returns true if `ancestor` spawns `vm_id`, directly or indirectly
+    pub fn is_ancestor(&self, ancestor: VmId, vm_id: VmId) -> bool {
+        let mut current = vm_id;
+        while let Some(parent) = self.parent(current) {
+            if parent == ancestor {
+                return true;
+            }
+            current = parent;
+        }
+        false
+    }
+
+    /// All (child, parent) pairs, sorted by child VM ID
+    pub fn iter(&self) -> impl Iterator<Item = (VmId, VmId)> + '_ {
+        self.parents.iter().map(|(id, parent)| (*id, *parent))
+    }
+}
+
+impl FromIterator<(VmId, VmId)> for SpawnTree {
+    fn from_iter<T: IntoIterator<Item = (VmId, VmId)>>(iter: T) -> Self {
+        Self {
+            parents: iter.into_iter().collect(),
+        }
+    }
+}
+
 /// Full state representing all VM instances from verifying a CKB script.
-/// It should be serializable to binary formats, while also be able to
-/// fully recover the running environment with the full transaction environment.
+/// It can be serialized to a binary format(see `serialization` module), while
+/// also be able to fully recover the running environment with the full
+/// transaction environment.
 #[derive(Clone, Debug)]
 pub struct FullSuspendedState {
     pub total_cycles: Cycle,
@@ -140,34 +305,33 @@ pub struct FullSuspendedState {
     pub vms: Vec<(VmId, VmState, Snapshot2<DataPieceId>)>,
     pub pipes: Vec<(PipeId, VmId)>,
     pub terminated_vms: Vec<(VmId, i8)>,
-}
-
-impl FullSuspendedState {
-    pub fn size(&self) -> u64 {
-        (size_of::<Cycle>()
-            + size_of::<VmId>()
-            + size_of::<u64>()
-            + self.vms.iter().fold(0, |mut acc, (_, _, snapshot)| {
-                acc += size_of::<VmId>() + size_of::<VmState>();
-                acc += snapshot.pages_from_source.len()
-                    * (size_of::<u64>()
-                        + size_of::<u8>()
-                        + size_of::<DataPieceId>()
-                        + size_of::<u64>()
-                        + size_of::<u64>());
-                for dirty_page in &snapshot.dirty_pages {
-                    acc += size_of::<u64>() + size_of::<u8>() + dirty_page.2.len();
-                }
-                acc += size_of::<u32>()
-                    + RISCV_GENERAL_REGISTER_NUMBER * size_of::<u64>()
-                    + size_of::<u64>()
-                    + size_of::<u64>()
-                    + size_of::<u64>();
-                acc
-            })
-            + (self.pipes.len() * (size_of::<PipeId>() + size_of::<VmId>()))) as u64
-            + (self.terminated_vms.len() * (size_of::<VmId>() + size_of::<i8>())) as u64
-    }
+    // VMs that are instantiated when the scheduler is suspended, they will be
+    // instantiated again when resuming to keep swapping deterministic.
+    pub instantiated_vms: Vec<VmId>,
+    pub spawn_depths: Vec<(VmId, u64)>,
+    pub limits: Limits,
+    // Script version the VMs run with, see ScriptVersion
+    pub script_version: u8,
+    // Scheduling related states, see SchedulingPolicy for more details
+    pub scheduling_policy: u8,
+    pub last_run_vm: Option<VmId>,
+    pub interrupted_vm: Option<VmId>,
+    pub vm_cycles: Vec<(VmId, Cycle)>,
+    pub swap_count: u64,
+    pub time_slice: Option<Cycle>,
+    pub slice_consumed_cycles: Cycle,
+    // Swapping related states, see EvictionStrategy for more details
+    pub max_instantiated_vms: u64,
+    pub eviction_strategy: u8,
+    pub run_count: u64,
+    pub last_run_times: Vec<(VmId, u64)>,
+    // Spawn lineage(see SpawnTree), a VM is reaped once its exit code is
+    // returned to its parent via join or join_any syscall.
+    pub parents: Vec<(VmId, VmId)>,
+    pub reaped_vms: Vec<VmId>,
+    pub vm_stats: Vec<VmStats>,
+    pub debug_sink: DebugSink,
+    pub debug_output: Vec<DebugMessage>,
 }
 
 /// Context data for current running transaction & script
@@ -182,6 +346,17 @@ pub struct TxData<DL> {
     pub script_group: Arc<ScriptGroup>,
 }
 
+impl<DL> TxData<DL> {
+    fn load_witness(&self, index: usize) -> Result<Bytes, Error> {
+        self.rtx
+            .transaction
+            .witnesses()
+            .get(index)
+            .map(|witness| witness.raw_data())
+            .ok_or_else(index_out_of_bound_error)
+    }
+}
+
 impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + Clone + 'static>
     DataSource<DataPieceId> for TxData<DL>
 {
@@ -198,7 +373,7 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                     .rtx
                     .resolved_inputs
                     .get(*i as usize)
-                    .ok_or_else(|| Error::External("INDEX_OUT_OF_BOUND".to_string()))?;
+                    .ok_or_else(index_out_of_bound_error)?;
                 self.data_loader.load_cell_data(cell).ok_or_else(|| {
                     Error::Unexpected(format!("Loading input cell #{}'s data failed!", i))
                 })
@@ -209,13 +384,13 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                 .outputs_data()
                 .get(*i as usize)
                 .map(|data| data.raw_data())
-                .ok_or_else(|| Error::External("INDEX_OUT_OF_BOUND".to_string())),
+                .ok_or_else(index_out_of_bound_error),
             DataPieceId::CellDep(i) => {
                 let cell = self
                     .rtx
                     .resolved_cell_deps
                     .get(*i as usize)
-                    .ok_or_else(|| Error::External("INDEX_OUT_OF_BOUND".to_string()))?;
+                    .ok_or_else(index_out_of_bound_error)?;
                 self.data_loader.load_cell_data(cell).ok_or_else(|| {
                     Error::Unexpected(format!("Loading dep cell #{}'s data failed!", i))
                 })
@@ -225,12 +400,12 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                     .script_group
                     .input_indices
                     .get(*i as usize)
-                    .ok_or_else(|| Error::External("INDEX_OUT_OF_BOUND".to_string()))?;
+                    .ok_or_else(index_out_of_bound_error)?;
                 let cell = self
                     .rtx
                     .resolved_inputs
                     .get(gi)
-                    .ok_or_else(|| Error::External("INDEX_OUT_OF_BOUND".to_string()))?;
+                    .ok_or_else(index_out_of_bound_error)?;
                 self.data_loader.load_cell_data(cell).ok_or_else(|| {
                     Error::Unexpected(format!("Loading input cell #{}'s data failed!", gi))
                 })
@@ -240,14 +415,32 @@ impl<DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + C
                     .script_group
                     .output_indices
                     .get(*i as usize)
-                    .ok_or_else(|| Error::External("INDEX_OUT_OF_BOUND".to_string()))?;
+                    .ok_or_else(index_out_of_bound_error)?;
                 self.rtx
                     .transaction
                     .outputs_data()
                     .get(gi)
                     .map(|data| data.raw_data())
-                    .ok_or_else(|| Error::External("INDEX_OUT_OF_BOUND".to_string()))
+                    .ok_or_else(index_out_of_bound_error)
             }
+            DataPieceId::Witness(i) => self.load_witness(*i as usize),
+            DataPieceId::GroupInputWitness(i) => {
+                let gi = *self
+                    .script_group
+                    .input_indices
+                    .get(*i as usize)
+                    .ok_or_else(index_out_of_bound_error)?;
+                self.load_witness(gi)
+            }
+            DataPieceId::GroupOutputWitness(i) => {
+                let gi = *self
+                    .script_group
+                    .output_indices
+                    .get(*i as usize)
+                    .ok_or_else(index_out_of_bound_error)?;
+                self.load_witness(gi)
+            }
+            DataPieceId::Script => Ok(self.script_group.script.as_bytes()),
         }
         .map(|data| {
             let offset = std::cmp::min(offset as usize, data.len());
@@ -270,3 +463,98 @@ pub enum RunMode {
     LimitCycles(Cycle),
     Pause(Pause),
 }
+
+/// Where output of the debug syscall goes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DebugSink {
+    /// Print via log::debug!
+    #[default]
+    Log,
+    /// Keep the output in the scheduler, see Scheduler::debug_output
+    Capture,
+}
+
+/// A single message printed by a VM via the debug syscall
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugMessage {
+    pub vm_id: VmId,
+    /// Total cycles consumed by the script when the message is printed
+    pub cycles: Cycle,
+    pub message: String,
+}
+
+/// Per VM statistics, breaking down cycles consumed by a script so as to
+/// locate the expensive parts. Cycles charged by the scheduler(swapping &
+/// pipe transfers) are counted when they are charged, while cycles charged
+/// when running a VM are counted after the VM runs. Summing total_cycles of
+/// all VMs gives the total cycles consumed by the script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VmStats {
+    pub vm_id: VmId,
+    /// Data piece the VM's program is loaded from
+    pub program: DataPieceId,
+    /// Cycles consumed by executing instructions. Syscalls not implemented
+    /// in this crate are also counted here.
+    pub execution_cycles: Cycle,
+    /// Cycles charged by syscalls, pipe transfers and program loading, as
+    /// well as cycles taken over from VMs killed by this VM. The root VM
+    /// also takes over cycles pending on other VMs when it terminates.
+    pub syscall_cycles: Cycle,
+    /// Cycles charged for suspending & resuming VMs
+    pub swap_cycles: Cycle,
+    /// Bytes read from / written to pipes
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Cycles counted above that are paid by another VM instead: cycles
+    /// taken over by the VM killing it, or by the root VM when they are still
+    /// pending as the root VM terminates. This is only a breakdown, such
+    /// cycles are part of the script's total either way.
+    pub unpaid_cycles: Cycle,
+    /// None if the VM has not terminated when the root VM terminates
+    pub exit_code: Option<i8>,
+}
+
+impl VmStats {
+    pub fn new(vm_id: VmId, program: DataPieceId) -> Self {
+        Self {
+            vm_id,
+            program,
+            execution_cycles: 0,
+            syscall_cycles: 0,
+            swap_cycles: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            unpaid_cycles: 0,
+            exit_code: None,
+        }
+    }
+
+    /// Cycles paid by the VM, which are part of the script's total cycles
+    pub fn total_cycles(&self) -> Cycle {
+        self.execution_cycles
+            .saturating_add(self.syscall_cycles)
+            .saturating_add(self.swap_cycles)
+            .saturating_sub(self.unpaid_cycles)
+    }
+}
+
+/// Result of a single Scheduler::run invocation. In both variants,
+/// consumed_cycles contains cycles consumed in the invocation, while
+/// total_cycles contains cycles consumed since the script starts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunResult {
+    /// Root VM has terminated, the script finishes execution.
+    Terminated {
+        exit_code: i8,
+        consumed_cycles: Cycle,
+        total_cycles: Cycle,
+        /// Statistics of all VMs booted in the script, ordered by VM ID
+        vm_stats: Vec<VmStats>,
+    },
+    /// Cycle budget is used up before root VM terminates, the script
+    /// can continue execution via another Scheduler::run invocation.
+    Exhausted {
+        consumed_cycles: Cycle,
+        total_cycles: Cycle,
+    },
+}
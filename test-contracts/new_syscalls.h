@@ -3,6 +3,35 @@
 
 #include <ckb_syscalls.h>
 
+// Error codes returned by the new syscalls
+#ifndef CKB_JOIN_FAILURE
+#define CKB_JOIN_FAILURE 5
+#endif
+#ifndef CKB_INVALID_PIPE
+#define CKB_INVALID_PIPE 6
+#endif
+#ifndef CKB_OTHER_END_CLOSED
+#define CKB_OTHER_END_CLOSED 7
+#endif
+#ifndef CKB_MAX_VMS_SPAWNED
+#define CKB_MAX_VMS_SPAWNED 8
+#endif
+#ifndef CKB_MAX_PIPES_CREATED
+#define CKB_MAX_PIPES_CREATED 9
+#endif
+#ifndef CKB_MAX_SPAWN_DEPTH
+#define CKB_MAX_SPAWN_DEPTH 10
+#endif
+#ifndef CKB_WOULD_BLOCK
+#define CKB_WOULD_BLOCK 11
+#endif
+#ifndef CKB_KILL_FAILURE
+#define CKB_KILL_FAILURE 12
+#endif
+#ifndef CKB_NOT_PERMITTED
+#define CKB_NOT_PERMITTED 13
+#endif
+
 typedef struct spawn2_args_t {
   /* Spawned VM instance ID */
   uint64_t *instance_id;
@@ -26,6 +55,8 @@ uint64_t ckb_instance_id() {
   return syscall(2603, 0, 0, 0, 0, 0, 0);
 }
 
+uint64_t ckb_parent_id() { return syscall(2611, 0, 0, 0, 0, 0, 0); }
+
 int ckb_pipe(uint64_t fildes[2]) {
   return syscall(2604, fildes, 0, 0, 0, 0, 0);
 }
@@ -44,4 +75,35 @@ int ckb_pipe_write(const uint8_t *buffer, size_t *length, uint64_t filde) {
   return ret;
 }
 
+#define CKB_PIPE_NONBLOCK 1
+
+int ckb_pipe_try_read(uint8_t *buffer, size_t *length, uint64_t filde) {
+  volatile size_t l = *length;
+  int ret = syscall(2606, buffer, &l, filde, CKB_PIPE_NONBLOCK, 0, 0);
+  *length = l;
+  return ret;
+}
+
+int ckb_pipe_try_write(const uint8_t *buffer, size_t *length, uint64_t filde) {
+  volatile size_t l = *length;
+  int ret = syscall(2605, buffer, &l, filde, CKB_PIPE_NONBLOCK, 0, 0);
+  *length = l;
+  return ret;
+}
+
+int ckb_select(const uint64_t *fildes, size_t count, uint64_t *ready) {
+  return syscall(2608, fildes, count, ready, 0, 0, 0);
+}
+
+int ckb_join_any(const uint64_t *ids, size_t count, uint64_t *id,
+                 int8_t *exit_code) {
+  return syscall(2609, ids, count, id, exit_code, 0, 0);
+}
+
+int ckb_kill(uint64_t id) { return syscall(2610, id, 0, 0, 0, 0, 0); }
+
+int ckb_close(uint64_t filde) {
+  return syscall(2607, filde, 0, 0, 0, 0, 0);
+}
+
 #endif /* NEW_SYSCALLS_H_ */
@@ -0,0 +1,929 @@
+// Scenarios testing scheduler syscall behaviors. The root VM picks a
+// scenario by script args, VMs spawned by a scenario run the same program
+// from cell dep 0, picking their roles by argv[0]. The remaining argv items
+// are decimal numbers, typically pipe IDs passed from the parent.
+//
+// A scenario returns 0 when all checks pass, a failing check prints the line
+// via debug syscall, so it shows up in the debug output.
+#define CKB_C_STDLIB_PRINTF 1
+#include <blockchain.h>
+#include <ckb_syscalls.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "new_syscalls.h"
+
+#define SCRIPT_SIZE 32768
+#define MAX_NAME_LENGTH 64
+#define MAX_ROLE_ARGS 8
+
+#define _BASE_ERROR_CODE 80
+#define ERROR_CHECK (_BASE_ERROR_CODE + 1)
+#define ERROR_ENCODING (_BASE_ERROR_CODE + 2)
+#define ERROR_ARGS (_BASE_ERROR_CODE + 3)
+#define ERROR_UNKNOWN_SCENARIO (_BASE_ERROR_CODE + 4)
+
+#define CHECK(cond)                                          \
+  do {                                                       \
+    if (!(cond)) {                                           \
+      ckb_printf("Line %d: check %s fails", __LINE__, #cond); \
+      return ERROR_CHECK;                                    \
+    }                                                        \
+  } while (0)
+
+#define CHECK_EQ(actual, expected)                                   \
+  do {                                                               \
+    int64_t actual_ = (int64_t)(actual);                             \
+    int64_t expected_ = (int64_t)(expected);                         \
+    if (actual_ != expected_) {                                      \
+      ckb_printf("Line %d: %s is %ld, expected %ld", __LINE__,       \
+                 #actual, actual_, expected_);                       \
+      return ERROR_CHECK;                                            \
+    }                                                                \
+  } while (0)
+
+#define CHECK_OK(expr) CHECK_EQ(expr, CKB_SUCCESS)
+
+int str_eq(const char *a, const char *b) {
+  while (*a != '\0' && *a == *b) {
+    a++;
+    b++;
+  }
+  return *a == *b;
+}
+
+int parse_u64(const char *s, uint64_t *value) {
+  if (*s == '\0') {
+    return ERROR_ARGS;
+  }
+  uint64_t v = 0;
+  for (; *s != '\0'; s++) {
+    if (*s < '0' || *s > '9') {
+      return ERROR_ARGS;
+    }
+    v = v * 10 + (uint64_t)(*s - '0');
+  }
+  *value = v;
+  return CKB_SUCCESS;
+}
+
+void format_u64(char *buffer, uint64_t value) {
+  char digits[21];
+  int n = 0;
+  do {
+    digits[n++] = '0' + (value % 10);
+    value /= 10;
+  } while (value > 0);
+  for (int i = 0; i < n; i++) {
+    buffer[i] = digits[n - 1 - i];
+  }
+  buffer[n] = '\0';
+}
+
+// Spawn this program from cell dep 0 running `role`, `pipes` is a 0
+// terminated list of pipes to pass, `args` are passed as decimal numbers.
+int spawn_role(const char *role, const uint64_t *pipes, size_t argc,
+               const uint64_t *args, uint64_t *id) {
+  if (argc > MAX_ROLE_ARGS) {
+    return ERROR_ARGS;
+  }
+  char buffers[MAX_ROLE_ARGS][21];
+  char *argv[MAX_ROLE_ARGS + 1];
+  argv[0] = (char *)role;
+  for (size_t i = 0; i < argc; i++) {
+    format_u64(buffers[i], args[i]);
+    argv[i + 1] = buffers[i];
+  }
+  uint64_t no_pipes[1] = {0};
+  spawn2_args_t spgs;
+  spgs.instance_id = id;
+  spgs.pipes = (pipes != NULL) ? pipes : no_pipes;
+  return ckb_spawn2(0, CKB_SOURCE_CELL_DEP, 0, argc + 1, argv, &spgs);
+}
+
+// Join a VM, checking it exits with 0
+int join_ok(uint64_t id) {
+  int8_t exit_code = -1;
+  CHECK_OK(ckb_join(id, &exit_code));
+  CHECK_EQ(exit_code, 0);
+  return CKB_SUCCESS;
+}
+
+// Run until at least `cycles` cycles are consumed, the test harness
+// suspends & resumes the scheduler in the middle.
+void burn_cycles(uint64_t cycles) {
+  uint64_t start = ckb_current_cycles();
+  while (ckb_current_cycles() - start < cycles) {
+  }
+}
+
+#define BURN_CYCLES 1000000
+
+// close: closing pipes, and how VMs on the other end observe it. With the
+// default HighestIdFirst policy, a spawned VM runs until it blocks before
+// its parent continues.
+int close_root(void) {
+  // Pipes that do not exist or are not owned
+  CHECK_EQ(ckb_close(0x123456), CKB_INVALID_PIPE);
+  uint64_t fds[2];
+  CHECK_OK(ckb_pipe(fds));
+  CHECK_OK(ckb_close(fds[0]));
+  CHECK_EQ(ckb_close(fds[0]), CKB_INVALID_PIPE);
+  // The other end was closed before the operation starts
+  uint8_t buffer[8] = {0};
+  size_t length = sizeof(buffer);
+  CHECK_EQ(ckb_pipe_write(buffer, &length, fds[1]), CKB_OTHER_END_CLOSED);
+  CHECK_OK(ckb_close(fds[1]));
+
+  // A blocked reader wakes up when the write end is closed
+  uint64_t read_fds[2];
+  CHECK_OK(ckb_pipe(read_fds));
+  uint64_t passed[2] = {read_fds[0], 0};
+  uint64_t reader_id = 0;
+  CHECK_OK(spawn_role("close_reader", passed, 1, &read_fds[0], &reader_id));
+  // The read end now belongs to the spawned VM
+  CHECK_EQ(ckb_close(read_fds[0]), CKB_INVALID_PIPE);
+  CHECK_OK(ckb_close(read_fds[1]));
+  CHECK_OK(join_ok(reader_id));
+
+  // A blocked writer wakes up when the read end is closed
+  uint64_t write_fds[2];
+  CHECK_OK(ckb_pipe(write_fds));
+  passed[0] = write_fds[1];
+  uint64_t writer_id = 0;
+  CHECK_OK(spawn_role("close_writer", passed, 1, &write_fds[1], &writer_id));
+  CHECK_EQ(ckb_close(write_fds[1]), CKB_INVALID_PIPE);
+  CHECK_OK(ckb_close(write_fds[0]));
+  CHECK_OK(join_ok(writer_id));
+  return CKB_SUCCESS;
+}
+
+int close_reader(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  uint8_t buffer[8];
+  size_t length = sizeof(buffer);
+  // Closed while reading: SUCCESS with nothing read
+  CHECK_OK(ckb_pipe_read(buffer, &length, fd));
+  CHECK_EQ(length, 0);
+  // Closed before reading
+  length = sizeof(buffer);
+  CHECK_EQ(ckb_pipe_read(buffer, &length, fd), CKB_OTHER_END_CLOSED);
+  CHECK_OK(ckb_close(fd));
+  return CKB_SUCCESS;
+}
+
+int close_writer(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  uint8_t buffer[8] = {1, 2, 3, 4, 5, 6, 7, 8};
+  size_t length = sizeof(buffer);
+  // Closed while writing: SUCCESS with nothing written
+  CHECK_OK(ckb_pipe_write(buffer, &length, fd));
+  CHECK_EQ(length, 0);
+  // Closed before writing
+  length = sizeof(buffer);
+  CHECK_EQ(ckb_pipe_write(buffer, &length, fd), CKB_OTHER_END_CLOSED);
+  return CKB_SUCCESS;
+}
+
+// limits: run with max_spawns = 4, max_pipes_per_vm = 6 and
+// max_spawn_depth = 2, each limit is hit after the scheduler is resumed.
+int limits_root(void) {
+  burn_cycles(BURN_CYCLES);
+  uint64_t fds[4][2];
+  for (int i = 0; i < 3; i++) {
+    CHECK_OK(ckb_pipe(fds[i]));
+  }
+  burn_cycles(BURN_CYCLES);
+  CHECK_EQ(ckb_pipe(fds[3]), CKB_MAX_PIPES_CREATED);
+  // Closed pipes no longer count
+  CHECK_OK(ckb_close(fds[0][0]));
+  CHECK_OK(ckb_close(fds[0][1]));
+  CHECK_OK(ckb_pipe(fds[3]));
+
+  // Spawns 2 VMs: one at depth 1, one at depth 2
+  uint64_t depth = 1;
+  uint64_t id = 0;
+  CHECK_OK(spawn_role("limits_chain", NULL, 1, &depth, &id));
+  CHECK_OK(join_ok(id));
+  burn_cycles(BURN_CYCLES);
+  // Terminated VMs still count against max_spawns
+  CHECK_OK(spawn_role("limits_noop", NULL, 0, NULL, &id));
+  CHECK_OK(join_ok(id));
+  CHECK_OK(spawn_role("limits_noop", NULL, 0, NULL, &id));
+  CHECK_OK(join_ok(id));
+  burn_cycles(BURN_CYCLES);
+  CHECK_EQ(spawn_role("limits_noop", NULL, 0, NULL, &id), CKB_MAX_VMS_SPAWNED);
+  return CKB_SUCCESS;
+}
+
+int limits_chain(int argc, char *argv[]) {
+  uint64_t depth = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &depth));
+  burn_cycles(BURN_CYCLES);
+  uint64_t child_depth = depth + 1;
+  uint64_t id = 0;
+  if (depth < 2) {
+    CHECK_OK(spawn_role("limits_chain", NULL, 1, &child_depth, &id));
+    CHECK_OK(join_ok(id));
+  } else {
+    CHECK_EQ(spawn_role("limits_chain", NULL, 1, &child_depth, &id),
+             CKB_MAX_SPAWN_DEPTH);
+  }
+  return CKB_SUCCESS;
+}
+
+int limits_noop(int argc, char *argv[]) {
+  (void)argv;
+  CHECK_EQ(argc, 0);
+  return CKB_SUCCESS;
+}
+
+// time_slice: VMs spinning at the same time, so they can only finish when
+// they are preempted in turn.
+#define SPIN_VMS 3
+
+int time_slice_root(void) {
+  uint64_t ids[SPIN_VMS];
+  uint64_t iterations = 50000;
+  for (int i = 0; i < SPIN_VMS; i++) {
+    CHECK_OK(spawn_role("spin", NULL, 1, &iterations, &ids[i]));
+  }
+  for (int i = 0; i < SPIN_VMS; i++) {
+    CHECK_OK(join_ok(ids[i]));
+  }
+  return CKB_SUCCESS;
+}
+
+// Spin without any syscalls, the VM only gives up running when preempted
+int spin(int argc, char *argv[]) {
+  uint64_t iterations = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &iterations));
+  volatile uint64_t counter = 0;
+  while (counter < iterations) {
+    counter++;
+  }
+  return CKB_SUCCESS;
+}
+
+// Write to `kilobytes` KB of stack memory, so the pages become dirty
+__attribute__((noinline)) void dirty_stack(uint64_t kilobytes) {
+  uint8_t buffer[kilobytes * 1024 + 1];
+  volatile uint8_t *p = buffer;
+  for (uint64_t i = 0; i < kilobytes * 1024; i += 256) {
+    p[i] = 1;
+  }
+}
+
+// eviction: run with 3 VMs instantiated at most. When the 4th VM boots, a
+// VM is suspended among the root VM(smallest ID), VM 1(least recently
+// run) and VM 2(least dirty pages).
+int eviction_root(void) {
+  dirty_stack(16);
+  uint64_t fds[2][2];
+  uint64_t ids[3];
+  // VM 1 & VM 2 run until they block reading from the pipes
+  uint64_t args[2] = {64, 0};
+  for (int i = 0; i < 2; i++) {
+    CHECK_OK(ckb_pipe(fds[i]));
+    uint64_t passed[2] = {fds[i][0], 0};
+    args[1] = fds[i][0];
+    CHECK_OK(spawn_role("evict_wait", passed, 2, args, &ids[i]));
+    args[0] = 0;
+  }
+  CHECK_OK(spawn_role("limits_noop", NULL, 0, NULL, &ids[2]));
+  for (int i = 0; i < 2; i++) {
+    uint8_t byte = 1;
+    size_t length = 1;
+    CHECK_OK(ckb_pipe_write(&byte, &length, fds[i][1]));
+    CHECK_EQ(length, 1);
+  }
+  for (int i = 0; i < 3; i++) {
+    CHECK_OK(join_ok(ids[i]));
+  }
+  return CKB_SUCCESS;
+}
+
+int evict_wait(int argc, char *argv[]) {
+  uint64_t kilobytes = 0;
+  uint64_t fd = 0;
+  CHECK_EQ(argc, 2);
+  CHECK_OK(parse_u64(argv[0], &kilobytes));
+  CHECK_OK(parse_u64(argv[1], &fd));
+  dirty_stack(kilobytes);
+  uint8_t byte = 0;
+  size_t length = 1;
+  CHECK_OK(ckb_pipe_read(&byte, &length, fd));
+  CHECK_EQ(length, 1);
+  return CKB_SUCCESS;
+}
+
+// nonblock: non-blocking reads & writes only succeed when a VM is blocked
+// on the other end, finishing with a single transfer.
+int nonblock_root(void) {
+  uint8_t buffer[8];
+  size_t length = sizeof(buffer);
+  uint64_t fds[2];
+  CHECK_OK(ckb_pipe(fds));
+  // Nobody is blocked on either end
+  CHECK_EQ(ckb_pipe_try_read(buffer, &length, fds[0]), CKB_WOULD_BLOCK);
+  CHECK_EQ(ckb_pipe_try_write(buffer, &length, fds[1]), CKB_WOULD_BLOCK);
+
+  // The writer blocks writing "hello"
+  uint64_t passed[2] = {fds[1], 0};
+  uint64_t args[1] = {fds[1]};
+  uint64_t writer_id = 0;
+  CHECK_OK(spawn_role("nb_writer", passed, 1, args, &writer_id));
+  length = 3;
+  CHECK_OK(ckb_pipe_try_read(buffer, &length, fds[0]));
+  CHECK_EQ(length, 3);
+  CHECK(memcmp(buffer, "hel", 3) == 0);
+  length = sizeof(buffer);
+  CHECK_OK(ckb_pipe_try_read(buffer, &length, fds[0]));
+  CHECK_EQ(length, 2);
+  CHECK(memcmp(buffer, "lo", 2) == 0);
+  // The writer has exited after failing a non-blocking write
+  length = sizeof(buffer);
+  CHECK_EQ(ckb_pipe_try_read(buffer, &length, fds[0]), CKB_OTHER_END_CLOSED);
+  CHECK_OK(join_ok(writer_id));
+
+  // The reader blocks reading 8 bytes, a non-blocking write finishes
+  // with the 3 bytes provided.
+  CHECK_OK(ckb_pipe(fds));
+  passed[0] = fds[0];
+  args[0] = fds[0];
+  uint64_t reader_id = 0;
+  CHECK_OK(spawn_role("nb_reader", passed, 1, args, &reader_id));
+  length = 3;
+  CHECK_OK(ckb_pipe_try_write((const uint8_t *)"abc", &length, fds[1]));
+  CHECK_EQ(length, 3);
+  CHECK_OK(join_ok(reader_id));
+  return CKB_SUCCESS;
+}
+
+int nb_writer(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  size_t length = 5;
+  CHECK_OK(ckb_pipe_write((const uint8_t *)"hello", &length, fd));
+  CHECK_EQ(length, 5);
+  // The parent is not reading
+  length = 1;
+  CHECK_EQ(ckb_pipe_try_write((const uint8_t *)"!", &length, fd),
+           CKB_WOULD_BLOCK);
+  return CKB_SUCCESS;
+}
+
+int nb_reader(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  uint8_t buffer[8];
+  size_t length = sizeof(buffer);
+  CHECK_OK(ckb_pipe_read(buffer, &length, fd));
+  CHECK_EQ(length, 3);
+  CHECK(memcmp(buffer, "abc", 3) == 0);
+  return CKB_SUCCESS;
+}
+
+// select: the first ready pipe in the order passed is returned, and two
+// VMs selecting on the two ends of a pipe wake each other up.
+int select_root(void) {
+  uint64_t fds[3][2];
+  for (int i = 0; i < 3; i++) {
+    CHECK_OK(ckb_pipe(fds[i]));
+  }
+  // Writers block writing a byte to pipe 1 & 2, nobody writes to pipe 0.
+  uint64_t ids[2];
+  for (int i = 1; i < 3; i++) {
+    uint64_t passed[2] = {fds[i][1], 0};
+    uint64_t args[1] = {fds[i][1]};
+    CHECK_OK(spawn_role("select_writer", passed, 1, args, &ids[i - 1]));
+  }
+  uint64_t ready = 0;
+  uint64_t order[3] = {fds[0][0], fds[2][0], fds[1][0]};
+  CHECK_OK(ckb_select(order, 3, &ready));
+  CHECK_EQ(ready, fds[2][0]);
+  uint8_t byte = 0;
+  size_t length = 1;
+  CHECK_OK(ckb_pipe_read(&byte, &length, fds[2][0]));
+  CHECK_EQ(byte, 2);
+  // The writer of pipe 2 has exited, a closed pipe is ready as well
+  order[1] = fds[1][0];
+  order[2] = fds[2][0];
+  CHECK_OK(ckb_select(order, 3, &ready));
+  CHECK_EQ(ready, fds[1][0]);
+  order[0] = fds[2][0];
+  order[1] = fds[1][0];
+  CHECK_OK(ckb_select(order, 2, &ready));
+  CHECK_EQ(ready, fds[2][0]);
+  CHECK_OK(ckb_pipe_read(&byte, &length, fds[1][0]));
+  CHECK_EQ(byte, 1);
+  // Only pipes owned by the VM can be selected
+  CHECK_EQ(ckb_select(&fds[1][1], 1, &ready), CKB_INVALID_PIPE);
+  for (int i = 0; i < 2; i++) {
+    CHECK_OK(join_ok(ids[i]));
+  }
+
+  // The child blocks selecting the read end first
+  uint64_t pair_fds[2];
+  CHECK_OK(ckb_pipe(pair_fds));
+  uint64_t passed[2] = {pair_fds[0], 0};
+  uint64_t args[1] = {pair_fds[0]};
+  uint64_t pair_id = 0;
+  CHECK_OK(spawn_role("select_pair", passed, 1, args, &pair_id));
+  CHECK_OK(ckb_select(&pair_fds[1], 1, &ready));
+  CHECK_EQ(ready, pair_fds[1]);
+  byte = 7;
+  length = 1;
+  CHECK_OK(ckb_pipe_write(&byte, &length, pair_fds[1]));
+  CHECK_EQ(length, 1);
+  CHECK_OK(join_ok(pair_id));
+  return CKB_SUCCESS;
+}
+
+int select_writer(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  uint8_t byte = (uint8_t)ckb_instance_id();
+  size_t length = 1;
+  CHECK_OK(ckb_pipe_write(&byte, &length, fd));
+  CHECK_EQ(length, 1);
+  return CKB_SUCCESS;
+}
+
+int select_pair(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  uint64_t ready = 0;
+  CHECK_OK(ckb_select(&fd, 1, &ready));
+  CHECK_EQ(ready, fd);
+  uint8_t byte = 0;
+  size_t length = 1;
+  CHECK_OK(ckb_pipe_read(&byte, &length, fd));
+  CHECK_EQ(length, 1);
+  CHECK_EQ(byte, 7);
+  return CKB_SUCCESS;
+}
+
+// Write a byte to `fd`, waking up the child blocked reading from it
+int wake_child(uint64_t fd) {
+  uint8_t byte = 1;
+  size_t length = 1;
+  CHECK_OK(ckb_pipe_write(&byte, &length, fd));
+  CHECK_EQ(length, 1);
+  return CKB_SUCCESS;
+}
+
+// join_any: terminated VMs are returned by ID order, children joined by
+// the caller are never returned again, while other VMs are.
+int join_any_root(void) {
+  uint64_t fds[3][2];
+  uint64_t ids[3];
+  for (int i = 0; i < 3; i++) {
+    CHECK_OK(ckb_pipe(fds[i]));
+    uint64_t passed[2] = {fds[i][0], 0};
+    uint64_t args[2] = {fds[i][0], 11 + i};
+    CHECK_OK(spawn_role("exit_with", passed, 2, args, &ids[i]));
+  }
+  // The joiner blocks joining VM 1
+  uint64_t joiner_args[1] = {ids[0]};
+  uint64_t joiner_id = 0;
+  CHECK_OK(spawn_role("join_any_joiner", NULL, 1, joiner_args, &joiner_id));
+
+  CHECK_OK(wake_child(fds[2][1]));
+  CHECK_OK(wake_child(fds[1][1]));
+  uint64_t id = 0;
+  int8_t exit_code = 0;
+  CHECK_OK(ckb_join_any(NULL, 0, &id, &exit_code));
+  CHECK_EQ(id, ids[1]);
+  CHECK_EQ(exit_code, 12);
+  // VM 2 has been joined
+  uint64_t targets[2] = {ids[2], ids[1]};
+  CHECK_OK(ckb_join_any(targets, 2, &id, &exit_code));
+  CHECK_EQ(id, ids[2]);
+  CHECK_EQ(exit_code, 13);
+  CHECK_EQ(ckb_join_any(targets, 2, &id, &exit_code), CKB_JOIN_FAILURE);
+
+  // Terminating VM 1 wakes up the joiner, which exits before the root VM
+  // gets to run again.
+  CHECK_OK(wake_child(fds[0][1]));
+  CHECK_OK(ckb_join_any(NULL, 0, &id, &exit_code));
+  CHECK_EQ(id, ids[0]);
+  CHECK_EQ(exit_code, 11);
+  CHECK_OK(ckb_join_any(NULL, 0, &id, &exit_code));
+  CHECK_EQ(id, joiner_id);
+  CHECK_EQ(exit_code, 0);
+  CHECK_EQ(ckb_join_any(NULL, 0, &id, &exit_code), CKB_JOIN_FAILURE);
+  return CKB_SUCCESS;
+}
+
+int exit_with(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  uint64_t code = 0;
+  CHECK_EQ(argc, 2);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  CHECK_OK(parse_u64(argv[1], &code));
+  uint8_t byte = 0;
+  size_t length = 1;
+  CHECK_OK(ckb_pipe_read(&byte, &length, fd));
+  CHECK_EQ(length, 1);
+  return (int)code;
+}
+
+int join_any_joiner(int argc, char *argv[]) {
+  uint64_t target = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &target));
+  // Blocks till the target exits
+  uint64_t id = 0;
+  int8_t exit_code = 0;
+  CHECK_OK(ckb_join_any(&target, 1, &id, &exit_code));
+  CHECK_EQ(id, target);
+  CHECK_EQ(exit_code, 11);
+  // The target is not a child, so it is returned again
+  id = 0;
+  exit_code = 0;
+  CHECK_OK(ckb_join_any(&target, 1, &id, &exit_code));
+  CHECK_EQ(id, target);
+  CHECK_EQ(exit_code, 11);
+  return CKB_SUCCESS;
+}
+
+// kill: the root VM kills its grandchild, which is blocked writing with a
+// pipe transfer charged but not yet paid, while its parent joins it.
+int kill_root(void) {
+  CHECK_EQ(ckb_kill(ckb_instance_id()), CKB_NOT_PERMITTED);
+  CHECK_EQ(ckb_kill(12345), CKB_KILL_FAILURE);
+
+  uint64_t fds[2];
+  CHECK_OK(ckb_pipe(fds));
+  uint64_t passed[2] = {fds[1], 0};
+  uint64_t args[1] = {fds[1]};
+  uint64_t parent_id = 0;
+  CHECK_OK(spawn_role("kill_parent", passed, 1, args, &parent_id));
+  // The target blocks writing 2 bytes, its parent blocks joining it
+  uint64_t target_id = parent_id + 1;
+  uint8_t buffer[2];
+  size_t length = 1;
+  CHECK_OK(ckb_pipe_read(buffer, &length, fds[0]));
+  CHECK_EQ(length, 1);
+  CHECK_OK(ckb_kill(target_id));
+  // The parent has been woken up and exited
+  length = 1;
+  CHECK_EQ(ckb_pipe_read(buffer, &length, fds[0]), CKB_OTHER_END_CLOSED);
+  CHECK_EQ(ckb_kill(target_id), CKB_KILL_FAILURE);
+  CHECK_EQ(ckb_kill(parent_id), CKB_KILL_FAILURE);
+  int8_t exit_code = 0;
+  CHECK_OK(ckb_join(target_id, &exit_code));
+  CHECK_EQ(exit_code, -9);
+  CHECK_OK(join_ok(parent_id));
+  return CKB_SUCCESS;
+}
+
+int kill_parent(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  uint64_t passed[2] = {fd, 0};
+  uint64_t target_id = 0;
+  CHECK_OK(spawn_role("kill_target", passed, 1, &fd, &target_id));
+  int8_t exit_code = 0;
+  CHECK_OK(ckb_join(target_id, &exit_code));
+  CHECK_EQ(exit_code, -9);
+  // Neither its own spawner nor itself can be killed
+  CHECK_EQ(ckb_kill(ckb_parent_id()), CKB_NOT_PERMITTED);
+  CHECK_EQ(ckb_kill(ckb_instance_id()), CKB_NOT_PERMITTED);
+  CHECK_EQ(ckb_kill(target_id), CKB_KILL_FAILURE);
+  return CKB_SUCCESS;
+}
+
+int kill_target(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  uint8_t buffer[2] = {1, 2};
+  size_t length = sizeof(buffer);
+  ckb_pipe_write(buffer, &length, fd);
+  // Killed before the write finishes
+  return ERROR_CHECK;
+}
+
+// exec: the child execs into another role, keeping its ID, parent, pipes
+// and consumed cycles.
+int exec_root(void) {
+  uint64_t fds[2];
+  CHECK_OK(ckb_pipe(fds));
+  uint64_t passed[2] = {fds[1], 0};
+  uint64_t id = 0;
+  CHECK_OK(spawn_role("exec_before", passed, 1, &fds[1], &id));
+  uint8_t buffer[1] = {0};
+  size_t length = sizeof(buffer);
+  CHECK_OK(ckb_pipe_read(buffer, &length, fds[0]));
+  CHECK_EQ(length, 1);
+  CHECK_EQ(buffer[0], 'x');
+  CHECK_OK(join_ok(id));
+  return CKB_SUCCESS;
+}
+
+int syscall_exec(size_t index, size_t source, size_t place, size_t bounds,
+                 int argc, const char *argv[]) {
+  return syscall(2043, index, source, place, bounds, argc, argv);
+}
+
+int exec_before(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  burn_cycles(BURN_CYCLES);
+  char id[21];
+  format_u64(id, ckb_instance_id());
+  const char *exec_argv[3] = {"exec_after", argv[0], id};
+  CHECK_OK(syscall_exec(0, CKB_SOURCE_CELL_DEP, 0, 0, 3, exec_argv));
+  // Exec never returns on success
+  return ERROR_CHECK;
+}
+
+int exec_after(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  uint64_t id = 0;
+  CHECK_EQ(argc, 2);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  CHECK_OK(parse_u64(argv[1], &id));
+  CHECK_EQ(ckb_instance_id(), id);
+  CHECK_EQ(ckb_parent_id(), 0);
+  uint8_t buffer[1] = {'x'};
+  size_t length = sizeof(buffer);
+  CHECK_OK(ckb_pipe_write(buffer, &length, fd));
+  CHECK_EQ(length, 1);
+  return CKB_SUCCESS;
+}
+
+// syscalls: only syscalls also provided by ckb-script are used here, each
+// outcome is printed, so the output can be compared against ckb-script
+// running the same transaction.
+#define LOAD_BUFFER_SIZE 16384
+
+uint8_t LOAD_BUFFER[LOAD_BUFFER_SIZE];
+
+uint64_t syscall_vm_version(void) { return syscall(2041, 0, 0, 0, 0, 0, 0); }
+
+int syscall_load_block_extension(void *addr, uint64_t *len, size_t offset,
+                                 size_t index, size_t source) {
+  volatile uint64_t inner_len = *len;
+  int ret = syscall(2104, addr, &inner_len, offset, index, source, 0);
+  *len = inner_len;
+  return ret;
+}
+
+// FNV-1a over loaded bytes
+uint64_t digest(uint64_t length) {
+  if (length > LOAD_BUFFER_SIZE) {
+    length = LOAD_BUFFER_SIZE;
+  }
+  uint64_t hash = 0xcbf29ce484222325;
+  for (uint64_t i = 0; i < length; i++) {
+    hash = (hash ^ LOAD_BUFFER[i]) * 0x100000001b3;
+  }
+  return hash;
+}
+
+void print_outcome(const char *name, uint64_t source, uint64_t index,
+                   uint64_t field, int ret, uint64_t length) {
+  ckb_printf("%s source %lx index %lu field %lu: %d, length %lu, digest %lx",
+             name, source, index, field, ret, length,
+             (ret == CKB_SUCCESS) ? digest(length) : 0);
+}
+
+#define SYSCALL_SOURCES 8
+#define SYSCALL_INDICES 3
+
+const uint64_t SOURCES[SYSCALL_SOURCES] = {
+    CKB_SOURCE_INPUT,         CKB_SOURCE_OUTPUT,
+    CKB_SOURCE_CELL_DEP,      CKB_SOURCE_HEADER_DEP,
+    CKB_SOURCE_GROUP_INPUT,   CKB_SOURCE_GROUP_OUTPUT,
+    0x0100000000000003,       0x0100000000000004,
+};
+
+int syscalls_root(void) {
+  uint64_t length = 0;
+  int ret = 0;
+  ckb_printf("vm version: %lu", syscall_vm_version());
+  length = LOAD_BUFFER_SIZE;
+  ret = ckb_load_tx_hash(LOAD_BUFFER, &length, 0);
+  print_outcome("load_tx_hash", 0, 0, 0, ret, length);
+  length = 10;
+  ret = ckb_load_tx_hash(LOAD_BUFFER, &length, 8);
+  print_outcome("load_tx_hash partial", 0, 0, 0, ret, length);
+  length = LOAD_BUFFER_SIZE;
+  ret = ckb_load_transaction(LOAD_BUFFER, &length, 0);
+  print_outcome("load_transaction", 0, 0, 0, ret, length);
+  length = LOAD_BUFFER_SIZE;
+  ret = ckb_load_script_hash(LOAD_BUFFER, &length, 0);
+  print_outcome("load_script_hash", 0, 0, 0, ret, length);
+  length = LOAD_BUFFER_SIZE;
+  ret = ckb_load_script(LOAD_BUFFER, &length, 0);
+  print_outcome("load_script", 0, 0, 0, ret, length);
+
+  for (int s = 0; s < SYSCALL_SOURCES; s++) {
+    uint64_t source = SOURCES[s];
+    for (uint64_t index = 0; index < SYSCALL_INDICES; index++) {
+      length = LOAD_BUFFER_SIZE;
+      ret = ckb_load_cell(LOAD_BUFFER, &length, 0, index, source);
+      print_outcome("load_cell", source, index, 0, ret, length);
+      length = LOAD_BUFFER_SIZE;
+      ret = ckb_load_header(LOAD_BUFFER, &length, 0, index, source);
+      print_outcome("load_header", source, index, 0, ret, length);
+      length = LOAD_BUFFER_SIZE;
+      ret = ckb_load_input(LOAD_BUFFER, &length, 0, index, source);
+      print_outcome("load_input", source, index, 0, ret, length);
+      length = LOAD_BUFFER_SIZE;
+      ret = ckb_load_witness(LOAD_BUFFER, &length, 0, index, source);
+      print_outcome("load_witness", source, index, 0, ret, length);
+      length = LOAD_BUFFER_SIZE;
+      ret = ckb_load_cell_data(LOAD_BUFFER, &length, 0, index, source);
+      print_outcome("load_cell_data", source, index, 0, ret, length);
+      length = LOAD_BUFFER_SIZE;
+      ret = syscall_load_block_extension(LOAD_BUFFER, &length, 0, index,
+                                         source);
+      print_outcome("load_block_extension", source, index, 0, ret, length);
+      for (uint64_t field = 0; field <= CKB_CELL_FIELD_OCCUPIED_CAPACITY;
+           field++) {
+        length = LOAD_BUFFER_SIZE;
+        ret = ckb_load_cell_by_field(LOAD_BUFFER, &length, 0, index, source,
+                                     field);
+        print_outcome("load_cell_by_field", source, index, field, ret,
+                      length);
+      }
+      for (uint64_t field = 0; field <= CKB_HEADER_FIELD_EPOCH_LENGTH;
+           field++) {
+        length = LOAD_BUFFER_SIZE;
+        ret = ckb_load_header_by_field(LOAD_BUFFER, &length, 0, index, source,
+                                       field);
+        print_outcome("load_header_by_field", source, index, field, ret,
+                      length);
+      }
+      for (uint64_t field = 0; field <= CKB_INPUT_FIELD_SINCE; field++) {
+        length = LOAD_BUFFER_SIZE;
+        ret = ckb_load_input_by_field(LOAD_BUFFER, &length, 0, index, source,
+                                      field);
+        print_outcome("load_input_by_field", source, index, field, ret,
+                      length);
+      }
+    }
+  }
+
+  // Exec failures return to the caller
+  const char *exec_argv[3] = {"syscalls_exec", "a", "bc"};
+  ckb_printf("exec index: %d",
+             syscall_exec(5, CKB_SOURCE_CELL_DEP, 0, 0, 3, exec_argv));
+  ckb_printf("exec bounds: %d",
+             syscall_exec(0, CKB_SOURCE_CELL_DEP, 0, 0xffffffff00000000, 3,
+                          exec_argv));
+  ckb_printf("exec witness: %d",
+             syscall_exec(5, CKB_SOURCE_INPUT, 1, 0, 3, exec_argv));
+  ret = syscall_exec(0, CKB_SOURCE_CELL_DEP, 0, 0, 3, exec_argv);
+  // Exec never returns on success
+  ckb_printf("exec: %d", ret);
+  return ERROR_CHECK;
+}
+
+int syscalls_exec(int argc, char *argv[]) {
+  CHECK_EQ(argc, 2);
+  ckb_printf("exec argv: %s %s", argv[0], argv[1]);
+  ckb_printf("vm version: %lu", syscall_vm_version());
+  return CKB_SUCCESS;
+}
+
+// root_exit: the root VM exits while its child is blocked writing, with a
+// pipe transfer charged but not yet paid.
+int root_exit_root(void) {
+  uint64_t fds[2];
+  CHECK_OK(ckb_pipe(fds));
+  uint64_t passed[2] = {fds[1], 0};
+  uint64_t id = 0;
+  CHECK_OK(spawn_role("blocked_writer", passed, 1, &fds[1], &id));
+  uint8_t buffer[1];
+  size_t length = sizeof(buffer);
+  CHECK_OK(ckb_pipe_read(buffer, &length, fds[0]));
+  CHECK_EQ(length, 1);
+  return CKB_SUCCESS;
+}
+
+int blocked_writer(int argc, char *argv[]) {
+  uint64_t fd = 0;
+  CHECK_EQ(argc, 1);
+  CHECK_OK(parse_u64(argv[0], &fd));
+  uint8_t buffer[2] = {1, 2};
+  size_t length = sizeof(buffer);
+  ckb_pipe_write(buffer, &length, fd);
+  // The root VM exits before the write finishes
+  return ERROR_CHECK;
+}
+
+typedef int (*root_fn)(void);
+typedef int (*role_fn)(int argc, char *argv[]);
+
+typedef struct {
+  const char *name;
+  root_fn run;
+} scenario_t;
+
+typedef struct {
+  const char *name;
+  role_fn run;
+} role_t;
+
+const scenario_t SCENARIOS[] = {
+    {"close", close_root},
+    {"limits", limits_root},
+    {"time_slice", time_slice_root},
+    {"eviction", eviction_root},
+    {"nonblock", nonblock_root},
+    {"select", select_root},
+    {"join_any", join_any_root},
+    {"kill", kill_root},
+    {"exec", exec_root},
+    {"syscalls", syscalls_root},
+    {"root_exit", root_exit_root},
+};
+
+const role_t ROLES[] = {
+    {"close_reader", close_reader},
+    {"close_writer", close_writer},
+    {"limits_chain", limits_chain},
+    {"limits_noop", limits_noop},
+    {"spin", spin},
+    {"evict_wait", evict_wait},
+    {"nb_writer", nb_writer},
+    {"nb_reader", nb_reader},
+    {"select_writer", select_writer},
+    {"select_pair", select_pair},
+    {"exit_with", exit_with},
+    {"join_any_joiner", join_any_joiner},
+    {"kill_parent", kill_parent},
+    {"kill_target", kill_target},
+    {"exec_before", exec_before},
+    {"exec_after", exec_after},
+    {"syscalls_exec", syscalls_exec},
+    {"blocked_writer", blocked_writer},
+};
+
+int load_scenario_name(char *name) {
+  uint8_t script[SCRIPT_SIZE];
+  uint64_t length = SCRIPT_SIZE;
+  int ret = ckb_load_script(script, &length, 0);
+  if (ret != CKB_SUCCESS) {
+    return ret;
+  }
+  if (length > SCRIPT_SIZE) {
+    return ERROR_ENCODING;
+  }
+  mol_seg_t script_seg;
+  script_seg.ptr = script;
+  script_seg.size = length;
+  if (MolReader_Script_verify(&script_seg, false) != MOL_OK) {
+    return ERROR_ENCODING;
+  }
+  mol_seg_t args_seg = MolReader_Script_get_args(&script_seg);
+  mol_seg_t bytes_seg = MolReader_Bytes_raw_bytes(&args_seg);
+  if (bytes_seg.size >= MAX_NAME_LENGTH) {
+    return ERROR_ARGS;
+  }
+  memcpy(name, bytes_seg.ptr, bytes_seg.size);
+  name[bytes_seg.size] = '\0';
+  return CKB_SUCCESS;
+}
+
+int main(int argc, char *argv[]) {
+  if (argc > 0) {
+    for (size_t i = 0; i < sizeof(ROLES) / sizeof(role_t); i++) {
+      if (str_eq(ROLES[i].name, argv[0])) {
+        return ROLES[i].run(argc - 1, argv + 1);
+      }
+    }
+    ckb_printf("Unknown role: %s", argv[0]);
+    return ERROR_UNKNOWN_SCENARIO;
+  }
+
+  char name[MAX_NAME_LENGTH];
+  int ret = load_scenario_name(name);
+  if (ret != CKB_SUCCESS) {
+    return ret;
+  }
+  for (size_t i = 0; i < sizeof(SCENARIOS) / sizeof(scenario_t); i++) {
+    if (str_eq(SCENARIOS[i].name, name)) {
+      return SCENARIOS[i].run();
+    }
+  }
+  ckb_printf("Unknown scenario: %s", name);
+  return ERROR_UNKNOWN_SCENARIO;
+}